@@ -44,8 +44,13 @@ if [ ! -f "$CHUNK_BINARY" ]; then
     exit 1
 fi
 
-# Start the master server
-launch_tmux_session "master" "$MASTER_BINARY"
+# The master and the chunkservers authenticate each other with a shared secret,
+# generate one for this cluster unless it is given
+export DFS_CLUSTER_SECRET="${DFS_CLUSTER_SECRET:-$(head -c 32 /dev/urandom | base64)}"
+
+# Start the master server, every chunkserver runs on this host
+# so replicas of a chunk have to be allowed on the same host
+launch_tmux_session "master" "DFS_ALLOW_SAME_HOST=1 $MASTER_BINARY"
 echo "Master server started in tmux session 'master'."
 
 # Start the specified number of chunk servers
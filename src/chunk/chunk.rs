@@ -8,6 +8,7 @@ use rocket::{
     State,
 };
 use std::{
+    collections::HashMap,
     sync::Arc,
     net::TcpListener,
     io::ErrorKind,
@@ -15,17 +16,25 @@ use std::{
 
 mod chunk_manager;
 mod heartbeat_manager;
+use lib::shared::cluster_auth::{self, ChunkReader, ChunkWriter, ClusterServer};
 use lib::shared::log_manager;
+use lib::shared::master_chunk_utils::{chunk_checksum, ChunkReport, CHUNK_SIZE_MAX};
 use uuid::Uuid;
 #[macro_use]
 extern crate rocket;
 
 const CHUNKS_DIR: &str = "chunks";
-const CHUNK_SIZE_MAX: usize = 1024 * 1024 * 256; // 256 MB
 const START_PORT: u16 = 8100;
 const MAX_PORT: u16 = 8200;
 
 type SharedChunkManager = Arc<Mutex<chunk_manager::ChunkManager>>;
+// Lock of each chunk, held by the primary while a mutation of the chunk
+// is applied and forwarded, so the secondaries receive the mutations in
+// serial order. Writes of other chunks go on meanwhile.
+type MutationLocks = Arc<Mutex<HashMap<Uuid, Arc<Mutex<()>>>>>;
+
+/// Address the master knows this server by, as "host:port".
+struct ServerAddress(String);
 
 #[rocket::main]
 async fn main() {
@@ -37,6 +46,11 @@ async fn main() {
         log::Level::Error,
     ]);
 
+    // Requests of the master and the other chunkservers are signed with the cluster secret
+    if let Err(e) = cluster_auth::cluster_auth_init() {
+        panic!("{}", e);
+    }
+
     // Initialize the chunk manager
     let chunk_manager = Arc::new(Mutex::new(chunk_manager::ChunkManager::new(
         1024,
@@ -61,21 +75,25 @@ async fn main() {
 
     // Start the heartbeat manager in the background
     // pass port number to heartbeat manager
-    tokio::spawn(heartbeat_manager::heartbeat(port.unwrap()));
+    tokio::spawn(heartbeat_manager::heartbeat(port.unwrap(), chunk_manager.clone()));
     
     info!("Starting Chunk Server on port: {}", port.unwrap());
     
     let app = rocket::build()
         .configure(config)
         .manage(chunk_manager)
-        .register("/", catchers![bad_request, not_found, payload_too_large])
+        .manage(MutationLocks::default())
+        .manage(ServerAddress(heartbeat_manager::server_address(port.unwrap())))
+        .register("/", catchers![bad_request, unauthorized, not_found, payload_too_large])
         .mount("/", routes![hello])
         .mount("/", routes![add_chunk])
         .mount("/", routes![get_chunk])
-        .mount("/", routes![append_chunk])
-        .mount("/", routes![update_chunk])
+        .mount("/", routes![write_chunk])
+        .mount("/", routes![apply_mutation])
         .mount("/", routes![delete_chunk])
-        .mount("/", routes![get_chunk_list]);
+        .mount("/", routes![get_chunk_list])
+        .mount("/", routes![chunk_info])
+        .mount("/", routes![replicate_from]);
 
     // Start the Rocket server
     app.launch().await.unwrap();
@@ -84,12 +102,7 @@ async fn main() {
 /// Attempts to find an available port between `start` and `end`.
 /// Returns the first available port, or `None` if no port is available.
 fn find_available_port(start: u16, end: u16) -> Option<u16> {
-    for port in start..=end {
-        if is_port_available(port) {
-            return Some(port);
-        }
-    }
-    None
+    (start..=end).find(|&port| is_port_available(port))
 }
 
 /// Checks if a given port is available by attempting to bind to it.
@@ -118,11 +131,18 @@ async fn hello() -> &'static str {
 ///   used to uniquely identify the chunk.
 /// - `data`: The binary data to be stored in the chunk. It is expected to be in the request body
 ///   as raw binary data.
+/// - `version`: The chunk version of the write (default 0), only for a server. A client writes
+///   at the version of the token the master handed out. It is stored next to the chunk and
+///   reported in heartbeats.
+/// - The request has to carry the write token handed out by the master for the chunk, or a
+///   server token, as `Authorization: Bearer <token>`. A chunk with a lease is only written
+///   through its primary (`write_chunk`).
 ///
 /// ## Request Examples
 ///
 /// ```bash
 /// curl -X POST "http://127.0.0.1:8000/add_chunk?id=<UUID>" \
+///      -H "Authorization: Bearer <token>" \
 ///      -H "Content-Type: application/octet-stream" \
 ///      --data-binary @example.bin
 /// ```
@@ -135,11 +155,16 @@ async fn hello() -> &'static str {
 ///
 /// ## Error Handling
 /// - If the UUID provided is invalid or improperly formatted, the server responds with a `400 BadRequest` error.
+/// - If the token is missing, invalid, for another chunk or for a chunk with a lease, the server
+///   responds with `401 Unauthorized`.
 /// - If the binary data exceeds the allowed limit, the server responds with a `413 Payload Too Large` error.
-#[post("/add_chunk?<id>", data = "<data>")]
+/// - If the stored chunk has a newer version than the write, the server responds with `409 Conflict`.
+#[post("/add_chunk?<id>&<version>", data = "<data>")]
 async fn add_chunk(
+    writer: ChunkWriter,
     state: &State<SharedChunkManager>,
     id: String, // UUID as a query parameter
+    version: Option<u64>,
     data: Data<'_>,
 ) -> Result<status::Created<&'static str>, Status> {
     let mut chunk_manager = state.lock().await;
@@ -163,32 +188,25 @@ async fn add_chunk(
         return Err(Status::PayloadTooLarge);
     }
 
+    let version = match writer.grant {
+        Some(grant) if grant.primary.is_some() => return Err(Status::Unauthorized),
+        Some(grant) => grant.version,
+        None => version.unwrap_or(0),
+    };
+
     // Add the chunk to the ChunkManager
-    chunk_manager.add_chunk(buffer, id);
+    if let Err(e) = chunk_manager.add_chunk(buffer, id, version) {
+        error!("{}", e);
+        return Err(Status::Conflict);
+    }
 
     // Log the addition and respond with success
     log::info!("Chunk added with ID: {}", id);
     Ok(status::Created::new("/").body("Chunk added\n"))
 }
 
-#[post("/append_chunk?<id>", data = "<data>")]
-async fn append_chunk(
-    state: &State<SharedChunkManager>,
-    id: String, // UUID as a query parameter
-    data: Data<'_>,
-) -> Result<status::Created<&'static str>, Status> {
-    let mut chunk_manager = state.lock().await;
-
-    // Parse the UUID from the query parameter
-    let id = match Uuid::parse_str(&id) {
-        Ok(uuid) => uuid,
-        Err(_) => {
-            error!("Invalid UUID provided");
-            return Err(Status::BadRequest);
-        }
-    };
-
-    // Read binary data from the HTTP body
+/// Reads the body of a chunk request, up to `CHUNK_SIZE_MAX` bytes.
+async fn read_body(data: Data<'_>) -> Result<Vec<u8>, Status> {
     let mut buffer = Vec::new();
     let limit = ByteUnit::Byte(CHUNK_SIZE_MAX as u64);
     let mut stream = data.open(limit);
@@ -197,48 +215,105 @@ async fn append_chunk(
         error!("Failed to read data from request: {}", e);
         return Err(Status::PayloadTooLarge);
     }
-
-    // Append the chunk to the ChunkManager
-    chunk_manager.append_chunk(buffer, id);
-
-    // Log the addition and respond with success
-    log::info!("Chunk appended with ID: {}", id);
-    Ok(status::Created::new("/").body("Chunk appended\n"))
+    Ok(buffer)
 }
 
-#[post("/update_chunk?<id>", data = "<data>")]
-async fn update_chunk(
+///
+/// Writes a chunk on its primary replica. The primary assigns the mutation the next
+/// serial number of the chunk, applies it and forwards it to every secondary replica
+/// through `apply_mutation`, so all replicas apply the writes in the same order.
+/// The version and the secondaries are those of the write token the master handed out,
+/// which has to grant the lease of the chunk to this server.
+///
+/// ## Parameters
+/// - `id`: The UUID of the chunk, the same on every replica.
+/// - `data`: The binary data of the chunk in the request body.
+///
+/// ## Example Usage
+/// ```bash
+/// curl -X POST "http://127.0.0.1:8100/write_chunk?id=<UUID>" \
+///      -H "Authorization: Bearer <token>" \
+///      --data-binary @example.bin
+/// ```
+///
+/// ## Error Handling
+/// - `400 BadRequest` if the UUID is invalid.
+/// - `401 Unauthorized` without a write token of the master granting this server the lease.
+/// - `409 Conflict` if the stored chunk is newer than the write.
+/// - `502 BadGateway` if a secondary did not apply the mutation. The client retries the write,
+///   which is forwarded again to every secondary. A secondary that still misses it stays at
+///   an older version and the master drops it as stale.
+#[post("/write_chunk?<id>", data = "<data>")]
+async fn write_chunk(
+    writer: ChunkWriter,
     state: &State<SharedChunkManager>,
-    id: String, // UUID as a query parameter
+    mutation_locks: &State<MutationLocks>,
+    address: &State<ServerAddress>,
+    id: String,
     data: Data<'_>,
 ) -> Result<status::Created<&'static str>, Status> {
-    let mut chunk_manager = state.lock().await;
-
-    // Parse the UUID from the query parameter
-    let id = match Uuid::parse_str(&id) {
-        Ok(uuid) => uuid,
-        Err(_) => {
-            error!("Invalid UUID provided");
-            return Err(Status::BadRequest);
+    let id = Uuid::parse_str(&id).map_err(|_| Status::BadRequest)?;
+    let grant = writer.grant.ok_or(Status::Unauthorized)?;
+    if grant.primary.as_deref() != Some(address.0.as_str()) {
+        error!("Lease of chunk {} is granted to {:?}, not {}", id, grant.primary, address.0);
+        return Err(Status::Unauthorized);
+    }
+    let version = grant.version;
+    let buffer = read_body(data).await?;
+
+    let chunk_lock = mutation_locks.lock().await.entry(id).or_default().clone();
+    let _ordering = chunk_lock.lock().await;
+    let serial = {
+        let mut chunk_manager = state.lock().await;
+        let serial = chunk_manager.next_serial(id);
+        if let Err(e) = chunk_manager.apply_mutation(buffer.clone(), id, version, serial) {
+            error!("{}", e);
+            return Err(Status::Conflict);
         }
+        serial
     };
 
-    // Read binary data from the HTTP body
-    let mut buffer = Vec::new();
-    let limit = ByteUnit::Byte(CHUNK_SIZE_MAX as u64);
-    let mut stream = data.open(limit);
-
-    if let Err(e) = stream.read_to_end(&mut buffer).await {
-        error!("Failed to read data from request: {}", e);
-        return Err(Status::PayloadTooLarge);
+    let client = reqwest::Client::new();
+    for server in grant.secondaries.iter() {
+        let url = format!("http://{}/apply_mutation?id={}&version={}&serial={}",
+                          server, id, version, serial);
+        let request = client.post(&url).bearer_auth(cluster_auth::server_token());
+        match request.body(buffer.clone()).send().await {
+            Ok(response) if response.status().is_success() => {}
+            _ => {
+                error!("Secondary {} failed to apply mutation {} of chunk {}", server, serial, id);
+                return Err(Status::BadGateway);
+            }
+        }
     }
 
-    // Update the chunk in the ChunkManager
-    chunk_manager.update_chunk(buffer, id);
+    log::info!("Chunk written with ID: {} (version {}, serial {})", id, version, serial);
+    Ok(status::Created::new("/").body("Chunk written\n"))
+}
+
+///
+/// Applies a mutation forwarded by the primary replica of a chunk. Mutations are
+/// applied in (version, serial) order, older or repeated ones are refused with `409 Conflict`.
+/// Only chunkservers may send mutations, requests without a server token get `401 Unauthorized`.
+#[post("/apply_mutation?<id>&<version>&<serial>", data = "<data>")]
+async fn apply_mutation(
+    _server: ClusterServer,
+    state: &State<SharedChunkManager>,
+    id: String,
+    version: u64,
+    serial: u64,
+    data: Data<'_>,
+) -> Result<status::Created<&'static str>, Status> {
+    let id = Uuid::parse_str(&id).map_err(|_| Status::BadRequest)?;
+    let buffer = read_body(data).await?;
 
-    // Log the update and respond with success
-    log::info!("Chunk updated with ID: {}", id);
-    Ok(status::Created::new("/").body("Chunk updated\n"))
+    let mut chunk_manager = state.lock().await;
+    if let Err(e) = chunk_manager.apply_mutation(buffer, id, version, serial) {
+        error!("{}", e);
+        return Err(Status::Conflict);
+    }
+    log::info!("Mutation applied to chunk {} (version {}, serial {})", id, version, serial);
+    Ok(status::Created::new("/").body("Mutation applied\n"))
 }
 
 ///
@@ -252,15 +327,22 @@ async fn update_chunk(
 /// ## Parameters
 /// - `id`: A UUID string passed as a query parameter (`id=<UUID>`), which uniquely identifies
 ///   the chunk to retrieve.
+/// - `version`: Optional, the version the stored chunk has to be at, so that a stale replica
+///   is not served.
+/// - The request has to carry the read token handed out by the master for the chunk, or a
+///   server token, as `Authorization: Bearer <token>`.
 ///
 /// ## Returns
 /// - A `Vec<u8>` representing the binary data of the chunk, if found.
 /// - HTTP status `400 BadRequest` if the UUID is invalid.
+/// - HTTP status `401 Unauthorized` without a token for the chunk or a server token.
 /// - HTTP status `404 NotFound` if the chunk with the given UUID is not found.
+/// - HTTP status `409 Conflict` if the chunk is not at the requested version.
 ///
 /// ## Example Usage
 /// ```bash
-/// curl -X GET "http://127.0.0.1:8000/get_chunk?id=<UUID>" --output chunk_output.bin
+/// curl -X GET "http://127.0.0.1:8000/get_chunk?id=<UUID>&version=2" \
+///      -H "Authorization: Bearer <token>" --output chunk_output.bin
 /// ```
 /// This command retrieves the chunk associated with the given UUID and stores it as `chunk_output.bin`
 /// on the local machine.
@@ -269,10 +351,12 @@ async fn update_chunk(
 /// - If the UUID is invalid or improperly formatted, the server responds with a `400 BadRequest` error.
 /// - If the chunk with the provided UUID is not found, the server responds with a `404 NotFound` error.
 ///
-#[get("/get_chunk?<id>")]
+#[get("/get_chunk?<id>&<version>")]
 async fn get_chunk(
+    _reader: ChunkReader,
     state: &State<SharedChunkManager>,
     id: String, // UUID as a query parameter
+    version: Option<u64>,
 ) -> Result<Vec<u8>, Status> {
     let chunk_manager = state.lock().await;
 
@@ -286,6 +370,12 @@ async fn get_chunk(
         }
     };
 
+    let stored = chunk_manager.get_chunk_version(id);
+    if version.is_some_and(|version| stored.is_some_and(|stored| stored != version)) {
+        error!("Chunk {} is at version {:?}, not {:?}", id, stored, version);
+        return Err(Status::Conflict);
+    }
+
     // Retrieve the chunk from the ChunkManager
     let chunk = match chunk_manager.get_chunk(id) {
         Ok(chunk) => chunk,
@@ -311,10 +401,11 @@ async fn get_chunk(
 /// ## Returns
 /// - HTTP status `201 Created` with a message indicating the chunk was deleted successfully.
 /// - HTTP status `400 BadRequest` if the UUID is invalid.
+/// - HTTP status `401 Unauthorized` without a server token.
 /// 
 /// ## Example Usage
 /// ```bash
-/// curl -X GET "http://127.0.0.1:8000/delete_chunk?id=<UUID>"
+/// curl -X GET "http://127.0.0.1:8000/delete_chunk?id=<UUID>" -H "Authorization: Bearer <token>"
 /// ```
 /// This command deletes the chunk associated with the given UUID.
 /// 
@@ -323,6 +414,7 @@ async fn get_chunk(
 /// 
 #[get("/delete_chunk?<id>")]
 async fn delete_chunk(
+    _server: ClusterServer,
     state: &State<SharedChunkManager>,
     id: String, // UUID as a query parameter
 ) -> Result<status::Created<&'static str>, Status> {
@@ -345,19 +437,121 @@ async fn delete_chunk(
     Ok(status::Created::new("/").body("Chunk deleted\n"))
 }
 
+///
+/// Lists the chunks stored on this server, only for the master and
+/// the other chunkservers (`401 Unauthorized` without a server token).
 #[get("/get_chunk_list")]
-async fn get_chunk_list(state: &State<SharedChunkManager>) -> Json<Vec<String>> {
+async fn get_chunk_list(_server: ClusterServer, state: &State<SharedChunkManager>) -> Json<Vec<String>> {
     let chunk_manager = state.lock().await;
     let chunk_list = chunk_manager.get_chunk_list();
     let string_list: Vec<String> = chunk_list.iter().map(|id| id.to_string()).collect();
     Json(string_list)
 }
 
+///
+/// Returns the version, size and checksum of a stored chunk.
+///
+/// ## Error Handling
+/// - `400 BadRequest` if the UUID is invalid.
+/// - `401 Unauthorized` without a read token for the chunk or a server token.
+/// - `404 NotFound` if the chunk is not stored on this server.
+#[get("/chunk_info?<id>")]
+async fn chunk_info(_reader: ChunkReader, state: &State<SharedChunkManager>, id: String)
+                    -> Result<Json<ChunkReport>, Status> {
+    let id = Uuid::parse_str(&id).map_err(|_| Status::BadRequest)?;
+    let chunk_manager = state.lock().await;
+    chunk_manager.get_chunk_report(id).map(Json).ok_or(Status::NotFound)
+}
+
+///
+/// Copies a chunk from another chunkserver, used by the master to re-replicate chunks.
+/// The chunk is pulled from the peer directly, checked against the expected version and
+/// checksum and stored under `id`. The reply reports the stored chunk to the master.
+///
+/// ## Parameters
+/// - `id`: The UUID the copy is stored under on this server.
+/// - `source`: The `host:port` of the chunkserver holding the chunk.
+/// - `source_id`: The UUID of the chunk on the source.
+/// - `version`: The version the source has to hold.
+/// - `checksum`: The hex encoded SHA-256 the data has to match.
+///
+/// ## Example Usage
+/// ```bash
+/// curl -X POST "http://127.0.0.1:8101/replicate_from?id=<UUID>&source=localhost:8100&source_id=<UUID>&version=2&checksum=<SHA-256>" \
+///      -H "Authorization: Bearer <token>"
+/// ```
+///
+/// ## Error Handling
+/// - `400 BadRequest` if a UUID is invalid.
+/// - `401 Unauthorized` without a server token.
+/// - `409 Conflict` if the source is not at `version`, or this server has a newer copy.
+/// - `422 UnprocessableEntity` if the data does not match `checksum`.
+/// - `502 BadGateway` if the source could not be reached.
+#[post("/replicate_from?<id>&<source>&<source_id>&<version>&<checksum>")]
+async fn replicate_from(
+    _server: ClusterServer,
+    state: &State<SharedChunkManager>,
+    id: String,
+    source: String,
+    source_id: String,
+    version: u64,
+    checksum: String,
+) -> Result<Json<ChunkReport>, Status> {
+    let id = Uuid::parse_str(&id).map_err(|_| Status::BadRequest)?;
+    let source_id = Uuid::parse_str(&source_id).map_err(|_| Status::BadRequest)?;
+
+    // the data of a chunk does not carry its version, ask for it first
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/chunk_info?id={}", source, source_id);
+    let request = client.get(&url).bearer_auth(cluster_auth::server_token());
+    let report: ChunkReport = match request.send().await {
+        Ok(response) if response.status().is_success() =>
+            response.json().await.map_err(|_| Status::BadGateway)?,
+        _ => {
+            error!("Failed to get chunk {} from {}", source_id, source);
+            return Err(Status::BadGateway);
+        }
+    };
+    if report.version != version {
+        error!("Chunk {} on {} is at version {}, expected {}", source_id, source, report.version, version);
+        return Err(Status::Conflict);
+    }
+
+    let url = format!("http://{}/get_chunk?id={}", source, source_id);
+    let request = client.get(&url).bearer_auth(cluster_auth::server_token());
+    let data = match request.send().await {
+        Ok(response) if response.status().is_success() =>
+            response.bytes().await.map_err(|_| Status::BadGateway)?.to_vec(),
+        _ => {
+            error!("Failed to get chunk {} from {}", source_id, source);
+            return Err(Status::BadGateway);
+        }
+    };
+    // a write between the two requests changes the checksum as well
+    if chunk_checksum(&data) != checksum {
+        error!("Chunk {} from {} does not match checksum {}", source_id, source, checksum);
+        return Err(Status::UnprocessableEntity);
+    }
+
+    let mut chunk_manager = state.lock().await;
+    if let Err(e) = chunk_manager.add_chunk(data, id, version) {
+        error!("{}", e);
+        return Err(Status::Conflict);
+    }
+    log::info!("Chunk {} copied from {} as {} (version {})", source_id, source, id, version);
+    chunk_manager.get_chunk_report(id).map(Json).ok_or(Status::InternalServerError)
+}
+
 #[catch(400)]
 fn bad_request() -> &'static str {
     "400 Bad Request\n"
 }
 
+#[catch(401)]
+fn unauthorized() -> &'static str {
+    "401 Unauthorized\n"
+}
+
 #[catch(404)]
 fn not_found() -> &'static str {
     "404 Not Found\n"
@@ -1,10 +1,42 @@
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use lib::shared::master_chunk_utils::{chunk_checksum, ChunkReport};
 use uuid::Uuid;
 
+// Each chunk file <uuid> has a sidecar <uuid>.version with its version
+const VERSION_EXTENSION: &str = "version";
+
 struct Chunk {
     data: Vec<u8>,
     _size: usize,
     id: uuid::Uuid,
+    version: u64,
+}
+
+fn version_path(chunk_path: &Path) -> PathBuf {
+    chunk_path.with_extension(VERSION_EXTENSION)
+}
+
+fn read_version(chunk_path: &Path) -> u64 {
+    std::fs::read_to_string(version_path(chunk_path))
+        .ok()
+        .and_then(|version| version.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/*
+*   Writes a file through a temp file, so that a crash
+*   never leaves a partially written chunk or version.
+*/
+fn write_atomic(path: &Path, data: &[u8]) {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    std::fs::write(&tmp_path, data).unwrap();
+    std::fs::rename(&tmp_path, path).unwrap();
+}
+
+fn write_version(chunk_path: &Path, version: u64) {
+    write_atomic(&version_path(chunk_path), version.to_string().as_bytes());
 }
 
 struct Chunks {
@@ -20,79 +52,46 @@ impl Chunks {
             chunks: Vec::new(),
             _chunk_size: chunk_size,
             chunk_count: 0,
-            chunks_dir: chunks_dir,
+            chunks_dir,
         }
     }
-    pub fn add_chunk(&mut self, data: Vec<u8>, id: Uuid) {
+    pub fn add_chunk(&mut self, data: Vec<u8>, id: Uuid, version: u64) {
         let size = data.len();
         let chunk = Chunk {
             data,
             _size: size,
-            id: id,
+            id,
+            version,
         };
 
         // Create the file path for the new chunk
         let chunk_path = Path::new(&self.chunks_dir).join(id.to_string());
 
         // Write the chunk data to the file
-        std::fs::write(&chunk_path, &chunk.data).unwrap();
-
-        self.chunks.push(chunk);
-        self.chunk_count += 1;
-    }
-
-    pub fn delete_chunk(&mut self, id: Uuid) {
-        let chunk = self.find_chunk(id);
-        match chunk {
-            Some(_chunk) => {
-                let chunk_path = Path::new(&self.chunks_dir).join(id.to_string());
-                std::fs::remove_file(&chunk_path).unwrap();
-                self.chunks.retain(|c| c.id != id);
-                self.chunk_count -= 1;
-            }
-            None => {}
-        }
-    }
+        write_atomic(&chunk_path, &chunk.data);
+        write_version(&chunk_path, version);
 
-    pub fn append_chunk(&mut self, data: Vec<u8>, id: Uuid) {
-        let dir = Path::new(&self.chunks_dir);
-        let chunk_path = dir.join(id.to_string());
-        let chunk = self.find_chunk_mut(id);
-        
-        match chunk {
-            Some(chunk) => {
-                let size = data.len();
-                chunk.data.extend(data);
-                chunk._size += size;
-    
-                // Update the file with the new data
-                std::fs::write(&chunk_path, &chunk.data).unwrap();
-            }
+        // adding an existing chunk again replaces its data
+        match self.find_chunk_mut(id) {
+            Some(existing) => *existing = chunk,
             None => {
-                self.add_chunk(data, id);
+                self.chunks.push(chunk);
+                self.chunk_count += 1;
             }
         }
     }
 
-    pub fn update_chunk(&mut self, data: Vec<u8>, id: Uuid) {
-        let dir = Path::new(&self.chunks_dir);
-        let chunk_path = dir.join(id.to_string());
-        let chunk = self.find_chunk_mut(id);
-
-        match chunk {
-            Some(chunk) => {
-                let size = data.len();
-                chunk.data = data;
-                chunk._size = size;
-
-                // Update the file with the new data
-                std::fs::write(&chunk_path, &chunk.data).unwrap();
-            }
-            None => {}
+    pub fn delete_chunk(&mut self, id: Uuid) {
+        let chunk = self.find_chunk(id);
+        if let Some(_chunk) = chunk {
+            let chunk_path = Path::new(&self.chunks_dir).join(id.to_string());
+            std::fs::remove_file(&chunk_path).unwrap();
+            let _ = std::fs::remove_file(version_path(&chunk_path));
+            self.chunks.retain(|c| c.id != id);
+            self.chunk_count -= 1;
         }
-
     }
-    
+
     pub fn find_chunk(&self, id: Uuid) -> Option<&Chunk> {
         self.chunks.iter().find(|chunk| chunk.id == id)
     }
@@ -105,13 +104,27 @@ impl Chunks {
 pub struct ChunkManager {
     chunks: Chunks,
     chunks_dir: String,
+    // (version, serial) of the last mutation applied to each chunk
+    applied: HashMap<Uuid, (u64, u64)>,
+    // last serial assigned to each chunk this server is primary of
+    serials: HashMap<Uuid, u64>,
+    // chunks stored / deleted since the last chunk report
+    stored: HashSet<Uuid>,
+    removed: HashSet<Uuid>,
+    // set by the master, new chunks are refused while draining
+    draining: bool,
 }
 
 impl ChunkManager {
     pub fn new(chunk_size: usize, chunks_dir: String) -> Self {
         let mut chunk_manager = ChunkManager {
             chunks: Chunks::new(chunk_size, chunks_dir.clone()),
-            chunks_dir: chunks_dir,
+            chunks_dir,
+            applied: HashMap::new(),
+            serials: HashMap::new(),
+            stored: HashSet::new(),
+            removed: HashSet::new(),
+            draining: false,
         };
         chunk_manager.init();
         chunk_manager
@@ -142,6 +155,7 @@ impl ChunkManager {
                     data: chunk_data,
                     _size: chunk_data_size,
                     id: chunk_id,
+                    version: read_version(&chunk_path),
                 };
                 self.chunks.chunks.push(chunk);
                 self.chunks.chunk_count += 1;
@@ -153,12 +167,71 @@ impl ChunkManager {
         );
     }
 
-    pub fn add_chunk(&mut self, data: Vec<u8>, id: Uuid) {
-        self.chunks.add_chunk(data, id);
+    /*
+    *   Stores a chunk with the version it was written at. Writes
+    *   older than the stored version are refused.
+    */
+    pub fn add_chunk(&mut self, data: Vec<u8>, id: Uuid, version: u64) -> Result<(), String> {
+        match self.chunks.find_chunk(id) {
+            Some(chunk) if version < chunk.version => {
+                return Err(format!("Chunk {} is at version {}, write has version {}",
+                                   id, chunk.version, version));
+            }
+            None if self.draining => {
+                return Err(format!("Server is draining, chunk {} refused", id));
+            }
+            _ => {}
+        }
+        self.chunks.add_chunk(data, id, version);
+        self.removed.remove(&id);
+        self.stored.insert(id);
+        Ok(())
+    }
+
+    pub fn set_draining(&mut self, draining: bool) {
+        self.draining = draining;
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining
     }
 
     pub fn delete_chunk(&mut self, id: Uuid) {
+        if self.chunks.find_chunk(id).is_some() {
+            self.stored.remove(&id);
+            self.removed.insert(id);
+        }
         self.chunks.delete_chunk(id);
+        self.applied.remove(&id);
+        self.serials.remove(&id);
+    }
+
+    /*
+    *   Assigns the serial number of the next mutation of a
+    *   chunk this server is the primary of.
+    */
+    pub fn next_serial(&mut self, id: Uuid) -> u64 {
+        let serial = self.serials.entry(id).or_insert(0);
+        *serial += 1;
+        *serial
+    }
+
+    /*
+    *   Applies a mutation ordered by the primary. Mutations have to
+    *   arrive in (version, serial) order, a mutation that is not newer
+    *   than the last applied one is a duplicate or out of order.
+    */
+    pub fn apply_mutation(&mut self, data: Vec<u8>, id: Uuid, version: u64, serial: u64)
+        -> Result<(), String> {
+        if let Some(last) = self.applied.get(&id) {
+            if (version, serial) <= *last {
+                return Err(format!("Mutation ({}, {}) of chunk {} is not after ({}, {})",
+                                   version, serial, id, last.0, last.1));
+            }
+        }
+        self.add_chunk(data, id, version)?;
+        self.applied.insert(id, (version, serial));
+        Ok(())
     }
 
     pub fn get_chunk(&self, id: Uuid) -> Result<Vec<u8>, String> {
@@ -169,17 +242,45 @@ impl ChunkManager {
         }
     }
 
-    pub fn append_chunk(&mut self, data: Vec<u8>, id: Uuid) {
-        self.chunks.append_chunk(data, id);
+    pub fn get_chunk_version(&self, id: Uuid) -> Option<u64> {
+        self.chunks.find_chunk(id).map(|chunk| chunk.version)
     }
 
-    pub fn update_chunk(&mut self, data: Vec<u8>, id: Uuid) {
-        self.chunks.update_chunk(data, id);
+    /*
+    *   Version, size and checksum of a stored chunk.
+    */
+    pub fn get_chunk_report(&self, id: Uuid) -> Option<ChunkReport> {
+        self.chunks.find_chunk(id).map(|chunk| ChunkReport {
+            id,
+            version: chunk.version,
+            size: chunk.data.len() as u64,
+            checksum: chunk_checksum(&chunk.data),
+        })
     }
 
     pub fn get_chunk_list(&self) -> Vec<Uuid> {
         self.chunks.chunks.iter().map(|chunk| chunk.id).collect()
     }
+
+    /*
+    *   Chunk report for the master : every stored chunk if `full`,
+    *   else the chunks stored and deleted since the previous report,
+    *   as (stored chunks, deleted chunk handles).
+    */
+    pub fn take_chunk_report(&mut self, full: bool) -> (Vec<ChunkReport>, Vec<Uuid>) {
+        let stored: Vec<Uuid> = self.stored.drain().collect();
+        let removed: Vec<Uuid> = self.removed.drain().collect();
+        if full {
+            return (self.get_chunk_list().into_iter()
+                        .filter_map(|id| self.get_chunk_report(id))
+                        .collect(),
+                    Vec::new());
+        }
+        let stored = stored.into_iter()
+            .filter_map(|id| self.get_chunk_report(id))
+            .collect();
+        (stored, removed)
+    }
 }
 
 #[cfg(test)]
@@ -204,7 +305,7 @@ mod tests {
         let mut chunk_manager = ChunkManager::new(1024, dir.to_string());
         let data = vec![0; 1024];
         let id = Uuid::new_v4();
-        chunk_manager.add_chunk(data, id);
+        chunk_manager.add_chunk(data, id, 1).unwrap();
         assert_eq!(chunk_manager.chunks.chunk_count, 1);
     }
 
@@ -224,10 +325,67 @@ mod tests {
         pre_test(&dir);
 
         let mut chunk_manager = ChunkManager::new(1024, dir.to_string());
-        chunk_manager.add_chunk(vec![0; 1024], Uuid::new_v4());
-        chunk_manager.add_chunk(vec![0; 1024], Uuid::new_v4());
+        chunk_manager.add_chunk(vec![0; 1024], Uuid::new_v4(), 1).unwrap();
+        chunk_manager.add_chunk(vec![0; 1024], Uuid::new_v4(), 1).unwrap();
 
         let chunk_manager = ChunkManager::new(1024, dir.to_string());
         assert_eq!(chunk_manager.chunks.chunk_count, 2);
     }
+
+    #[test]
+    fn test_version_is_persisted() {
+        let dir = format!("{}/test_version_is_persisted", TEST_CHUNKS_DIR);
+        pre_test(&dir);
+
+        let id = Uuid::new_v4();
+        let mut chunk_manager = ChunkManager::new(1024, dir.to_string());
+        chunk_manager.add_chunk(vec![1; 16], id, 3).unwrap();
+        assert!(chunk_manager.add_chunk(vec![2; 16], id, 2).is_err());
+        // a replica takes the version of the write it applies
+        chunk_manager.apply_mutation(vec![1; 16], id, 4, 1).unwrap();
+        // a draining server keeps its chunks but takes no new ones
+        chunk_manager.set_draining(true);
+        assert!(chunk_manager.add_chunk(vec![2; 16], Uuid::new_v4(), 1).is_err());
+
+        let chunk_manager = ChunkManager::new(1024, dir.to_string());
+        assert_eq!(chunk_manager.get_chunk_list(), vec![id]);
+        assert_eq!(chunk_manager.get_chunk(id).unwrap(), vec![1; 16]);
+        let report = chunk_manager.get_chunk_report(id).unwrap();
+        assert_eq!((report.version, report.size), (4, 16));
+        assert_eq!(report.checksum, chunk_checksum(&[1; 16]));
+    }
+
+    #[test]
+    fn test_chunk_report_has_changes_since_last_one() {
+        let dir = format!("{}/test_chunk_report", TEST_CHUNKS_DIR);
+        pre_test(&dir);
+
+        let (kept, deleted) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut chunk_manager = ChunkManager::new(1024, dir.to_string());
+        chunk_manager.add_chunk(vec![1], kept, 1).unwrap();
+        chunk_manager.add_chunk(vec![2], deleted, 1).unwrap();
+        assert_eq!(chunk_manager.take_chunk_report(true).0.len(), 2);
+
+        chunk_manager.add_chunk(vec![3], kept, 2).unwrap();
+        chunk_manager.delete_chunk(deleted);
+        let (stored, removed) = chunk_manager.take_chunk_report(false);
+        assert_eq!(stored, vec![chunk_manager.get_chunk_report(kept).unwrap()]);
+        assert_eq!((stored[0].version, removed), (2, vec![deleted]));
+        assert_eq!(chunk_manager.take_chunk_report(false), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn test_mutations_apply_in_order() {
+        let dir = format!("{}/test_mutations_apply_in_order", TEST_CHUNKS_DIR);
+        pre_test(&dir);
+
+        let id = Uuid::new_v4();
+        let mut chunk_manager = ChunkManager::new(1024, dir.to_string());
+        chunk_manager.apply_mutation(vec![1], id, 2, 1).unwrap();
+        chunk_manager.apply_mutation(vec![2], id, 2, 2).unwrap();
+        assert!(chunk_manager.apply_mutation(vec![3], id, 2, 2).is_err());
+        assert!(chunk_manager.apply_mutation(vec![3], id, 1, 5).is_err());
+        chunk_manager.apply_mutation(vec![4], id, 3, 1).unwrap();
+        assert_eq!(chunk_manager.get_chunk(id).unwrap(), vec![4]);
+    }
 }
@@ -2,7 +2,19 @@ use rocket::tokio::time::{sleep, Duration};
 use std::{path::Path, time::SystemTime};
 use sysinfo::{Disks, System};
 use reqwest::Client;
-use lib::shared::master_chunk_utils::{Disk, Metadata, HEARTBEAT_INTERVAL};
+use lib::shared::cluster_auth;
+use lib::shared::master_chunk_utils::{Command, CommandAck, Disk, HeartbeatResponse, Metadata,
+                                     Topology, HEARTBEAT_INTERVAL};
+use crate::SharedChunkManager;
+
+// Heartbeats between two full chunk reports
+const FULL_REPORT_INTERVAL: u64 = 60;
+// Failure domains of the server, the host defaults to the host name
+const DATACENTER_ENV: &str = "DFS_DATACENTER";
+const RACK_ENV: &str = "DFS_RACK";
+const HOST_ENV: &str = "DFS_HOST";
+// IP the server reports in its heartbeats
+const SERVER_IP: &str = "localhost";
 
 #[allow(unused)]
 fn get_own_ip() -> Option<String> {
@@ -12,34 +24,41 @@ fn get_own_ip() -> Option<String> {
     socket.local_addr().map(|addr| addr.ip().to_string()).ok()
 }
 
-///
-/// Periodically sends a heartbeat to the master server.
-/// The heartbeat contains information about the chunkserver.
-/// The heartbeat interval is defined by `HEARTBEAT_INTERVAL`.
-///
-pub async fn heartbeat(port: u16) {
-    info!("Starting Chunkserver heartbeat...");
-    let interval = Duration::from_secs(HEARTBEAT_INTERVAL);
+/// Address the master knows the chunkserver by, from its heartbeats.
+pub fn server_address(port: u16) -> String {
+    format!("{}:{}", SERVER_IP, port)
+}
 
-    let mut sys = System::new_all();
-    sys.refresh_all();
+/// Topology labels of the chunkserver, from the environment.
+fn server_topology(host_name: &str) -> Topology {
+    let label = |name: &str, default: &str| std::env::var(name).unwrap_or_else(|_| default.to_string());
+    let default = Topology::default();
+    Topology {
+        datacenter: label(DATACENTER_ENV, &default.datacenter),
+        rack: label(RACK_ENV, &default.rack),
+        host: label(HOST_ENV, host_name),
+    }
+}
 
-    // Select the disk mounted at `/`
-    let mut disks = Disks::new_with_refreshed_list();
-    let mut selected_disk = disks.iter()
+/// Information about the chunkserver sent in its heartbeats,
+/// for the disk mounted at `/`.
+fn server_metadata(port: u16, disks: &Disks) -> Option<Metadata> {
+    let selected_disk = disks.iter()
         .find(|disk| disk.mount_point() == Path::new("/")
             || disk.mount_point() == Path::new("C:\\"));
     if selected_disk.is_none() {
         error!("No disk mounted at / or C:\\");
-        return;
+        return None;
     }
 
-    let mut metadata = Metadata {
+    let host_name = System::host_name().unwrap_or_else(|| "Unknown".to_string());
+    Some(Metadata {
         os_name: System::name().unwrap_or_else(|| "Unknown".to_string()),
         os_version: System::os_version().unwrap_or_else(|| "Unknown".to_string()),
-        host_name: System::host_name().unwrap_or_else(|| "Unknown".to_string()),
+        topology: server_topology(&host_name),
+        host_name,
         // ip: get_own_ip().unwrap_or_else(|| "localhost".to_string()),
-        ip: "localhost".to_string(),
+        ip: SERVER_IP.to_string(),
         chunkserver_id: port,
         last_heartbeat: 0,
         disk_info: Disk {
@@ -68,12 +87,43 @@ pub async fn heartbeat(port: u16) {
             total_space: selected_disk.as_ref().unwrap().total_space(),
             available_space: selected_disk.as_ref().unwrap().available_space(),
         },
+        chunks: Vec::new(),
+        removed_chunks: Vec::new(),
+        full_report: true,
+        draining: false,
+        acks: Vec::new(),
+    })
+}
+
+///
+/// Periodically sends a heartbeat to the master server.
+/// The heartbeat contains information about the chunkserver
+/// and a report of the chunks it stores : a full report when
+/// registering, then only the chunks stored or deleted since.
+/// A full report is sent again every `FULL_REPORT_INTERVAL`
+/// heartbeats, and whenever a heartbeat did not arrive.
+/// The reply carries commands of the master, which are executed
+/// and acknowledged in the next heartbeat.
+/// The heartbeat interval is defined by `HEARTBEAT_INTERVAL`.
+///
+pub async fn heartbeat(port: u16, chunk_manager: SharedChunkManager) {
+    info!("Starting Chunkserver heartbeat...");
+    let interval = Duration::from_secs(HEARTBEAT_INTERVAL);
+
+    let mut sys = System::new_all();
+    sys.refresh_all();
+
+    let mut disks = Disks::new_with_refreshed_list();
+    let mut metadata = match server_metadata(port, &disks) {
+        Some(metadata) => metadata,
+        None => return,
     };
 
     // print metadata
     debug!("OS Name: {}", metadata.os_name);
     debug!("OS Version: {}", metadata.os_version);
     debug!("Host Name: {}", metadata.host_name);
+    debug!("Topology: {:?}", metadata.topology);
     debug!("IP: {}", metadata.ip);
     debug!("Chunkserver ID: {}", metadata.chunkserver_id);
     debug!("Disk Name: {}", metadata.disk_info.name);
@@ -83,12 +133,15 @@ pub async fn heartbeat(port: u16) {
     debug!("Total Space: {}", metadata.disk_info.total_space);
     debug!("Available Space: {}", metadata.disk_info.available_space);
 
+    let client = Client::new();
+    let mut beats: u64 = 0;
     loop {
         sleep(interval).await;
+        beats += 1;
 
         // update disk info
         disks.refresh();
-        selected_disk = disks.iter()
+        let selected_disk = disks.iter()
             .find(|disk| disk.mount_point() == Path::new("/")
                 || disk.mount_point() == Path::new("C:\\"));
         if selected_disk.is_none() {
@@ -100,21 +153,93 @@ pub async fn heartbeat(port: u16) {
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_secs();
+        metadata.full_report |= beats.is_multiple_of(FULL_REPORT_INTERVAL);
+        {
+            let mut chunk_manager = chunk_manager.lock().await;
+            (metadata.chunks, metadata.removed_chunks) =
+                chunk_manager.take_chunk_report(metadata.full_report);
+            metadata.draining = chunk_manager.is_draining();
+        }
         debug!("Sending heartbeat...");
 
-        let _response = match Client::new()
+        let reply = match client
             .post("http://localhost:8000/heartbeat")
+            .bearer_auth(cluster_auth::server_token())
             .json(&metadata)
             .send()
             .await {
-            Ok(response) => response,
+            Ok(response) if response.status().is_success() =>
+                response.json::<HeartbeatResponse>().await.map_err(|error| error.to_string()),
+            Ok(response) => Err(format!("heartbeat refused ({})", response.status())),
+            Err(error) => Err(format!("server unreachable: {}", error)),
+        };
+        match reply {
+            Ok(reply) => {
+                metadata.full_report = false;
+                metadata.acks.clear();
+                for (id, command) in reply.commands {
+                    let result = execute(command, port, &chunk_manager, &mut metadata, &disks).await;
+                    if let Err(error) = &result {
+                        error!("Command {} failed: {}", id, error);
+                    }
+                    metadata.acks.push(CommandAck { id, error: result.err() });
+                }
+            }
+            // the changes of a report that did not arrive are only in the
+            // next full report, the acks are sent again
             Err(error) => {
-                error!("Server unreachable: {}", error);
-                continue;
+                error!("Heartbeat failed: {}", error);
+                metadata.full_report = true;
             }
-        };
+        }
 
         let metadata_json = serde_json::to_string(&metadata).unwrap();
         debug!("Metadata: {}", metadata_json);
     }
 }
+
+/// Executes a command of the master.
+async fn execute(command: Command, port: u16, chunk_manager: &SharedChunkManager,
+                 metadata: &mut Metadata, disks: &Disks) -> Result<(), String> {
+    match command {
+        Command::DeleteChunks { chunks } => {
+            let mut chunk_manager = chunk_manager.lock().await;
+            for id in chunks {
+                info!("Deleting chunk {} on request of the master", id);
+                chunk_manager.delete_chunk(id);
+            }
+            Ok(())
+        }
+        Command::ReplicateChunk { id, target, target_id } => {
+            let report = chunk_manager.lock().await.get_chunk_report(id)
+                .ok_or_else(|| format!("Chunk {} not found", id))?;
+            let url = format!(
+                "http://{}/replicate_from?id={}&source={}:{}&source_id={}&version={}&checksum={}",
+                target, target_id, metadata.ip, port, id, report.version, report.checksum);
+            match Client::new().post(&url).bearer_auth(cluster_auth::server_token()).send().await {
+                Ok(response) if response.status().is_success() => {
+                    info!("Chunk {} replicated to {} as {}", id, target, target_id);
+                    Ok(())
+                }
+                Ok(response) => Err(format!("{} answered {}", target, response.status())),
+                Err(error) => Err(error.to_string()),
+            }
+        }
+        Command::ReportChunks => {
+            metadata.full_report = true;
+            Ok(())
+        }
+        Command::Reregister => {
+            info!("Registering again with the master");
+            let acks = std::mem::take(&mut metadata.acks);
+            *metadata = server_metadata(port, disks).ok_or("No disk mounted at /")?;
+            metadata.acks = acks;
+            Ok(())
+        }
+        Command::Drain { enabled } => {
+            info!("Drain mode {}", if enabled { "on" } else { "off" });
+            chunk_manager.lock().await.set_draining(enabled);
+            Ok(())
+        }
+    }
+}
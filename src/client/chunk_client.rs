@@ -1,3 +1,4 @@
+use reqwest::RequestBuilder;
 use std::io::Error;
 
 pub struct ChunkClient {
@@ -13,58 +14,85 @@ impl ChunkClient {
         }
     }
 
-    pub async fn add_chunk(&self, id: &str, data: Vec<u8>) -> Result<String, Error> {
-        let url = format!("http://{}/add_chunk?id={}", self.base_url, id);
-        let response = self.client.post(&url).body(data).send()
-            .await.expect("Request failed");
+    /*
+    *   Adds the chunk token handed out by the master to a read or a write.
+    */
+    fn authorize(request: RequestBuilder, token: Option<&str>) -> RequestBuilder {
+        match token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    pub async fn add_chunk(&self, id: &str, version: u64, token: Option<&str>,
+                           data: Vec<u8>) -> Result<String, Error> {
+        let url = format!("http://{}/add_chunk?id={}&version={}", self.base_url, id, version);
+        let request = Self::authorize(self.client.post(&url), token);
+        let response = request.body(data).send()
+            .await.map_err(Error::other)?;
         if response.status().is_success() {
             Ok(response.status().to_string())
         } else {
-            Err(Error::new(std::io::ErrorKind::Other, String::from(response.status().as_str())))
+            Err(Error::other(String::from(response.status().as_str())))
         }
     }
 
-    pub async fn update_chunk(&self, id: &str, data: Vec<u8>) -> Result<String, Error> {
-        let url = format!("http://{}/update_chunk?id={}", self.base_url, id);
-        let response = self.client.post(&url).body(data).send()
-            .await.expect("Request failed");
+    /*
+    *   Writes a chunk through its primary replica, which forwards it to
+    *   the secondaries. The version and the secondaries are those the
+    *   master put in the token.
+    */
+    pub async fn write_chunk(&self, id: &str, token: Option<&str>, data: Vec<u8>)
+                             -> Result<String, Error> {
+        let url = format!("http://{}/write_chunk?id={}", self.base_url, id);
+        let request = Self::authorize(self.client.post(&url), token);
+        let response = request.body(data).send()
+            .await.map_err(Error::other)?;
         if response.status().is_success() {
             Ok(response.status().to_string())
         } else {
-            Err(Error::new(std::io::ErrorKind::Other, String::from(response.status().as_str())))
+            Err(Error::other(String::from(response.status().as_str())))
         }
     }
 
-    pub async fn get_chunk(&self, id: &str) -> Result<Vec<u8>, Error> {
-        let url = format!("http://{}/get_chunk?id={}", self.base_url, id);
-        let response = self.client.get(&url).send().await.expect("Request failed");
+    /*
+    *   Reads a chunk, the server refuses it unless
+    *   it holds the chunk at `version`.
+    */
+    pub async fn get_chunk(&self, id: &str, version: u64, token: Option<&str>)
+                           -> Result<Vec<u8>, Error> {
+        let url = format!("http://{}/get_chunk?id={}&version={}", self.base_url, id, version);
+        let request = Self::authorize(self.client.get(&url), token);
+        // an unreachable server is one more missing replica or fragment
+        let response = request.send().await.map_err(Error::other)?;
         if response.status().is_success() {
-            let result = response.bytes().await.expect("Failed to parse response");
+            let result = response.bytes().await.map_err(Error::other)?;
             Ok(result.to_vec())
         } else {
-            Err(Error::new(std::io::ErrorKind::Other, String::from(response.status().as_str())))
+            Err(Error::other(String::from(response.status().as_str())))
         }
     }
 
+    #[allow(unused)]
     pub async fn delete_chunk(&self, id: &str) -> Result<String, Error> {
         let url = format!("http://{}/delete_chunk?id={}", self.base_url, id);
-        let response = self.client.get(&url).send().await.expect("Request failed");
+        let response = self.client.get(&url).send().await.map_err(Error::other)?;
         if response.status().is_success() {
             Ok(response.status().to_string())
         } else {
-            Err(Error::new(std::io::ErrorKind::Other, String::from(response.status().as_str())))
+            Err(Error::other(String::from(response.status().as_str())))
         }
     }
 
     #[allow(unused)]
     pub async fn get_chunk_list(&self) -> Result<Vec<String>, Error> {
         let url = format!("http://{}/get_chunk_list", self.base_url);
-        let response = self.client.get(&url).send().await.expect("Request failed");
+        let response = self.client.get(&url).send().await.map_err(Error::other)?;
         if response.status().is_success() {
-            let result = response.json::<Vec<String>>().await.expect("Failed to parse response");
+            let result = response.json::<Vec<String>>().await.map_err(Error::other)?;
             Ok(result)
         } else {
-            Err(Error::new(std::io::ErrorKind::Other, String::from(response.status().as_str())))
+            Err(Error::other(String::from(response.status().as_str())))
         }
     }
 }
\ No newline at end of file
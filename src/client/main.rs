@@ -1,15 +1,19 @@
 use std::fs;
-use std::io::Error;
+use std::io::{Error, Read, Write};
 use std::path::Path;
 use clap::Parser;
 use master_client::MasterClient;
 use chunk_client::ChunkClient;
-use lib::shared::master_client_utils::{ChunkInfo, User};
+use lib::shared::erasure;
+use lib::shared::master_client_utils::{ChunkInfo, ErasureCoding, FileChunks, RebalancePlan, User};
 
 mod chunk_client;
 mod master_client;
 
 const MASTER_URL: &str = "http://localhost:8000";
+// Attempts of a write through a primary, a secondary that
+// missed the write gets it again with the next attempt
+const WRITE_ATTEMPTS: usize = 3;
 
 #[derive(Parser, Debug)]
 #[command(name = "client", about = "CRUD operations on files/directories")]
@@ -31,12 +35,54 @@ struct Opt {
 
     #[arg(short, long)]
     remote_path: Option<String>,
+
+    #[arg(short, long)]
+    new_path: Option<String>,
+
+    /// Octal permission bits, e.g. 755
+    #[arg(short, long)]
+    mode: Option<String>,
+
+    #[arg(short, long)]
+    owner: Option<String>,
+
+    #[arg(short, long)]
+    group: Option<String>,
+
+    /// User managed by the user target (admins only)
+    #[arg(long)]
+    account: Option<String>,
+
+    #[arg(long)]
+    new_password: Option<String>,
+
+    /// Replicas of each chunk, for create and setrep
+    #[arg(long)]
+    replication: Option<usize>,
+
+    /// Erasure code the chunks instead of replicating them, as data,parity fragments (e.g. 6,3)
+    #[arg(long)]
+    erasure: Option<String>,
+
+    /// Only print the moves of a rebalance
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Fraction of the average usage a server may differ by before it is rebalanced
+    #[arg(long)]
+    threshold: Option<f64>,
+
+    /// Bytes a rebalance may move
+    #[arg(long)]
+    budget: Option<u64>,
 }
 
 #[derive(Debug)]
 enum Target {
     Directory,
     File,
+    User,
+    Cluster,
 }
 
 #[derive(Debug)]
@@ -45,6 +91,16 @@ enum Action {
     Read,
     Update,
     Delete,
+    Rename,
+    Chmod,
+    Chown,
+    SetReplication,
+    Password,
+    Disable,
+    Enable,
+    Promote,
+    Demote,
+    Rebalance,
 }
 
 #[tokio::main]
@@ -57,6 +113,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         "d" => Target::Directory,
         "file" => Target::File,
         "f" => Target::File,
+        "user" => Target::User,
+        "cluster" => Target::Cluster,
         _ => {
             panic!("Invalid target");
         }
@@ -71,6 +129,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         "u" => Action::Update,
         "delete" => Action::Delete,
         "d" => Action::Delete,
+        "rename" => Action::Rename,
+        "mv" => Action::Rename,
+        "m" => Action::Rename,
+        "chmod" => Action::Chmod,
+        "chown" => Action::Chown,
+        "setrep" => Action::SetReplication,
+        "passwd" => Action::Password,
+        "disable" => Action::Disable,
+        "enable" => Action::Enable,
+        "promote" => Action::Promote,
+        "demote" => Action::Demote,
+        "rebalance" => Action::Rebalance,
         _ => {
             panic!("Invalid action");
         }
@@ -85,15 +155,37 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let local_path = binding.as_str();
     let binding = opt.remote_path.unwrap_or_default();
     let remote_path = binding.as_str();
+    let binding = opt.new_path.unwrap_or_default();
+    let new_path = binding.as_str();
+    let mode = opt.mode.as_deref();
+    let owner = opt.owner.as_deref().unwrap_or_default();
+    let group = opt.group.as_deref();
+    let account = opt.account.unwrap_or_default();
 
     let master_client = MasterClient::new(MASTER_URL);
     user_authenticate(&master_client, &user).await?;
 
+    // permissions and replication are changed the same way for files and directories
+    match action {
+        Action::Chmod => {
+            master_client.chmod(remote_path, mode.unwrap_or_default()).await?;
+        }
+        Action::Chown => {
+            master_client.chown(remote_path, owner, group).await?;
+        }
+        Action::SetReplication => {
+            let replication = opt.replication.expect("--replication is required");
+            master_client.set_replication(remote_path, replication).await?;
+        }
+        _ => {}
+    }
+
     match target {
         Target::Directory => {
             match action {
                 Action::Create => {
-                    let result = master_client.create_directory(remote_path).await?;
+                    let result = master_client.create_directory(remote_path, mode, opt.replication,
+                                                                opt.erasure.as_deref()).await?;
                     println!("{}", result);
                 }
                 Action::Read => {
@@ -101,7 +193,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     println!("{}", result);
                 }
                 Action::Delete => {
-                    let _result = master_client.delete_directory(remote_path).await?;
+                    master_client.delete_directory(remote_path).await?;
+                }
+                Action::Rename => {
+                    master_client.rename_directory(remote_path, new_path).await?;
                 }
                 _ => {}
             }
@@ -109,7 +204,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Target::File => {
             match action {
                 Action::Create => {
-                    create_file(&master_client, local_path, remote_path).await?;
+                    create_file(&master_client, local_path, remote_path, mode, opt.replication,
+                                opt.erasure.as_deref()).await?;
                 }
                 Action::Read => {
                     read_file(&master_client, local_path, remote_path).await?;
@@ -120,92 +216,208 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 Action::Delete => {
                     delete_file(&master_client, remote_path).await?;
                 }
+                Action::Rename => {
+                    master_client.rename_file(remote_path, new_path).await?;
+                }
+                _ => {}
+            }
+        }
+        Target::User => {
+            match action {
+                Action::Read => {
+                    for user in master_client.list_users().await? {
+                        println!("{}\tadmin={}\tdisabled={}",
+                                 user.username, user.admin, user.disabled);
+                    }
+                }
+                Action::Password => {
+                    let user = User {
+                        username: account,
+                        password: opt.new_password.expect("--new-password is required"),
+                    };
+                    master_client.change_password(&user).await?;
+                }
+                Action::Disable => master_client.set_user_disabled(&account, true).await?,
+                Action::Enable => master_client.set_user_disabled(&account, false).await?,
+                Action::Delete => master_client.delete_user(&account).await?,
+                Action::Promote => master_client.set_user_admin(&account, true).await?,
+                Action::Demote => master_client.set_user_admin(&account, false).await?,
+                _ => {}
+            }
+        }
+        Target::Cluster => {
+            if let Action::Rebalance = action {
+                let plan = master_client.rebalance(opt.threshold, opt.budget, opt.dry_run).await?;
+                print_rebalance(&plan);
             }
         }
     }
 
+    master_client.logout().await?;
     Ok(())
 }
 
+fn print_rebalance(plan: &RebalancePlan) {
+    println!("average {} bytes, threshold {}, budget {} bytes{}",
+             plan.average, plan.threshold, plan.budget, if plan.dry_run { " (dry run)" } else { "" });
+    for server in &plan.servers {
+        println!("{}\t{} bytes\t{} chunks", server.server, server.used, server.chunk_count);
+    }
+    for planned in &plan.moves {
+        let status = match (&planned.error, planned.done) {
+            (Some(error), _) => format!("failed: {}", error),
+            (None, true) => "moved".to_string(),
+            (None, false) => "planned".to_string(),
+        };
+        println!("{}[{}] {} -> {}\t{} bytes\t{}",
+                 planned.path, planned.index, planned.source, planned.target, planned.size, status);
+    }
+}
+
 async fn user_authenticate(master_client: &MasterClient, user: &User) -> Result<(), Error> {
     master_client.user_authenticate(user).await?;
     Ok(())
 }
 
-async fn create_file(master_client: &MasterClient, local_path: &str, remote_path: &str)
-                     -> Result<(), Error> {
-    // read local file
-    let file: Vec<u8> = fs::read(local_path)?;
-    let size = file.len();
-
+async fn create_file(master_client: &MasterClient, local_path: &str, remote_path: &str,
+                     mode: Option<&str>, replication: Option<usize>,
+                     erasure: Option<&str>) -> Result<(), Error> {
     // Create remote file on master
-    let _result = master_client.create_file(remote_path).await?;
-    // println!("{:?}", result);
+    let _result = master_client.create_file(remote_path, mode, replication, erasure).await?;
 
-    // Signal master with file size
-    let result: Vec<ChunkInfo> = master_client.update_file(remote_path, size).await?;
+    write_file(master_client, local_path, remote_path).await
+}
 
-    // Write to all chunks
-    for chunk in result.iter() {
-        let chunk_client = ChunkClient::new(&chunk.server_ip.as_str());
-        let _result = chunk_client.add_chunk(&chunk.uuid, file.clone()).await.unwrap();
-        // println!("{}", result);
+/*
+*   Writes a local file to a remote one : the master allocates one
+*   chunk per chunk size bytes and every range of the local file is
+*   sent to the primary replica of its chunk, which forwards it to
+*   the other replicas. The chunks of an erasure-coded file are
+*   encoded here and each fragment is written to its own server.
+*/
+async fn write_file(master_client: &MasterClient, local_path: &str, remote_path: &str)
+                    -> Result<(), Error> {
+    let mut file = fs::File::open(local_path)?;
+    let size = file.metadata()?.len();
+
+    // Signal master with file size
+    let result: FileChunks = master_client.update_file(remote_path, size).await?;
+
+    // Write each range to all replicas of its chunk
+    for chunk in result.chunks.iter() {
+        let mut data = Vec::new();
+        (&mut file).take(result.chunk_size).read_to_end(&mut data)?;
+        if let Some(erasure) = &chunk.erasure {
+            write_fragments(chunk, erasure, &data).await?;
+            continue;
+        }
+        match &chunk.primary {
+            Some(primary) => {
+                let chunk_client = ChunkClient::new(primary);
+                let mut attempt = 1;
+                while let Err(e) = chunk_client.write_chunk(&chunk.uuid, chunk.token.as_deref(),
+                                                            data.clone()).await {
+                    if attempt == WRITE_ATTEMPTS {
+                        return Err(e);
+                    }
+                    eprintln!("Write of chunk {} failed ({}), retrying", chunk.uuid, e);
+                    attempt += 1;
+                }
+            }
+            // no lease was granted, write every replica directly
+            None => {
+                for server in chunk.locations.iter() {
+                    let chunk_client = ChunkClient::new(server);
+                    chunk_client.add_chunk(&chunk.uuid, chunk.version, chunk.token.as_deref(),
+                                           data.clone()).await?;
+                }
+            }
+        }
     }
 
     Ok(())
 }
 
+/*
+*   Encodes a chunk of an erasure-coded file and
+*   stores each fragment on its server.
+*/
+async fn write_fragments(chunk: &ChunkInfo, erasure: &ErasureCoding, data: &[u8])
+                         -> Result<(), Error> {
+    let fragments = erasure::encode(erasure, data).map_err(Error::other)?;
+    for (fragment, server) in fragments.into_iter().zip(chunk.fragments.iter()) {
+        let server = server.as_ref()
+            .ok_or_else(|| Error::other(format!("No server for a fragment of {}", chunk.uuid)))?;
+        ChunkClient::new(server)
+            .add_chunk(&chunk.uuid, chunk.version, chunk.token.as_deref(), fragment).await?;
+    }
+    Ok(())
+}
+
+/*
+*   Reads `len` bytes of an erasure-coded chunk : its data fragments,
+*   or any `data` of its fragments when some are missing.
+*/
+async fn read_fragments(chunk: &ChunkInfo, erasure: &ErasureCoding, len: usize)
+                        -> Result<Vec<u8>, Error> {
+    let mut fragments: Vec<Option<Vec<u8>>> = vec![None; erasure.fragments()];
+    let mut fetched = 0;
+    for (fragment, server) in chunk.fragments.iter().enumerate() {
+        if fetched == erasure.data {
+            break;
+        }
+        if let Some(server) = server {
+            let chunk_client = ChunkClient::new(server);
+            let token = chunk.token.as_deref();
+            if let Ok(data) = chunk_client.get_chunk(&chunk.uuid, chunk.version, token).await {
+                fragments[fragment] = Some(data);
+                fetched += 1;
+            }
+        }
+    }
+    erasure::decode(erasure, fragments, len).map_err(Error::other)
+}
+
 async fn read_file(master_client: &MasterClient, source_path: &str, destination_path: &str)
                    -> Result<(), Error> {
     let result = master_client.read_file(destination_path).await?;
 
-    // pick the first chunk
-    if result.is_empty() {
-        return Err(Error::new(std::io::ErrorKind::NotFound, "File not found"));
+    // Read the chunks in order, each from the first replica that answers
+    let mut file = fs::File::create(Path::new(source_path))?;
+    for (index, chunk) in result.chunks.iter().enumerate() {
+        if let Some(erasure) = &chunk.erasure {
+            let len = result.chunk_size.min(result.size - index as u64 * result.chunk_size);
+            file.write_all(&read_fragments(chunk, erasure, len as usize).await?)?;
+            continue;
+        }
+        let mut data = None;
+        for server in chunk.locations.iter() {
+            let chunk_client = ChunkClient::new(server);
+            let token = chunk.token.as_deref();
+            if let Ok(chunk_data) = chunk_client.get_chunk(&chunk.uuid, chunk.version, token).await {
+                data = Some(chunk_data);
+                break;
+            }
+        }
+        match data {
+            Some(data) => file.write_all(&data)?,
+            None => return Err(Error::new(std::io::ErrorKind::NotFound,
+                                          format!("No replica of chunk {} available", index))),
+        }
     }
-    let chunk = result.first().unwrap();
-    println!("{:?}", chunk);
-
-    // Read from chunk
-    let chunk_client = ChunkClient::new(&chunk.server_ip.as_str());
-    let result = chunk_client.get_chunk(&chunk.uuid).await.unwrap();
-
-    // Write to local file
-    let path = Path::new(source_path);
-    fs::write(path, result).expect("Failed to write file");
 
     Ok(())
 }
 
 async fn update_file(master_client: &MasterClient, source_path: &str, destination_path: &str)
                      -> Result<(), Error> {
-    let file: Vec<u8> = fs::read(source_path)?;
-
-    // get all chunks from master
-    let result: Vec<ChunkInfo> = master_client.read_all_file(destination_path).await?;
-
-    // update across all chunks
-    for chunk in result.iter() {
-        let chunk_client = ChunkClient::new(&chunk.server_ip.as_str());
-        let _result = chunk_client.update_chunk(&chunk.uuid, file.clone()).await.unwrap();
-        // println!("{}", result);
-    }
-
-    Ok(())
+    write_file(master_client, source_path, destination_path).await
 }
 
+/*
+*   Deletes a file on the master, which reclaims its chunks
+*   on the chunkservers (see the master's gc manager).
+*/
 async fn delete_file(master_client: &MasterClient, remote_path: &str) -> Result<(), Error> {
-    // get file info from master
-    let result = master_client.read_file(remote_path).await?;
-
-    // delete file from master
-    let _result = master_client.delete_file(remote_path).await?;
-
-    // delete file from all chunks
-    for chunk in result.iter() {
-        let chunk_client = ChunkClient::new(&chunk.server_ip.as_str());
-        let _result = chunk_client.delete_chunk(&chunk.uuid).await.unwrap();
-    }
-    Ok(())
+    master_client.delete_file(remote_path).await
 }
-
@@ -1,10 +1,17 @@
-use reqwest::Client;
+use std::sync::Mutex;
+use chrono::{Duration, Utc};
+use reqwest::{Client, RequestBuilder};
 use std::io::Error;
-use lib::shared::master_client_utils::{ChunkInfo, DirectoryInfo, FileInfo, User};
+use lib::shared::master_client_utils::{DirectoryInfo, FileChunks, FileInfo, RebalancePlan,
+                                       SessionToken, User, UserSummary};
+
+// Refresh the session token when it expires within this many seconds
+const REFRESH_MARGIN: i64 = 60;
 
 pub struct MasterClient {
     base_url: String,
     client: Client,
+    session: Mutex<Option<SessionToken>>,
 }
 
 impl MasterClient {
@@ -12,115 +19,291 @@ impl MasterClient {
         MasterClient {
             base_url: base_url.to_string(),
             client: Client::new(),
+            session: Mutex::new(None),
+        }
+    }
+
+    /*
+    *   Adds the session token to a request, refreshing it
+    *   first when it is about to expire.
+    */
+    async fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        let session = self.session.lock().unwrap().clone();
+        let session = match session {
+            Some(session) if session.expires - Utc::now() < Duration::seconds(REFRESH_MARGIN) =>
+                Some(self.refresh(&session).await.unwrap_or(session)),
+            session => session,
+        };
+        match session {
+            Some(session) => request.bearer_auth(session.token),
+            None => request,
         }
     }
 
-    pub async fn user_authenticate(&self, user:&User) -> Result<String, Error> {
-        // register if user does not exist
+    async fn get(&self, url: &str) -> RequestBuilder {
+        self.authorize(self.client.get(url)).await
+    }
+
+    async fn post(&self, url: &str) -> RequestBuilder {
+        self.authorize(self.client.post(url)).await
+    }
+
+    async fn refresh(&self, session: &SessionToken) -> Option<SessionToken> {
+        let url = format!("{}/user/refresh", self.base_url);
+        let response = self.client.post(&url).bearer_auth(&session.token).send().await.ok()?;
+        if !response.status().is_success() {
+            return None;
+        }
+        let session = response.json::<SessionToken>().await.ok()?;
+        *self.session.lock().unwrap() = Some(session.clone());
+        Some(session)
+    }
+
+    pub async fn user_authenticate(&self, user:&User) -> Result<(), Error> {
+        // register if user does not exist, an existing user just fails here
         let url = format!("{}/user/register", self.base_url);
+        self.client.post(&url).json(user).send().await.expect("Request failed");
+
+        let url = format!("{}/user/login", self.base_url);
         let response = self.client.post(&url).json(user).send().await.expect("Request failed");
         if response.status().is_success() {
-            let result = response.text().await.expect("Failed to parse response");
-            Ok(result)
+            let session = response.json::<SessionToken>().await.expect("Failed to parse response");
+            *self.session.lock().unwrap() = Some(session);
+            Ok(())
         } else {
-            // login if user exists
-            let url = format!("{}/user/login", self.base_url);
-            let response = self.client.post(&url).json(user).send().await.expect("Request failed");
-            if response.status().is_success() {
-                let result = response.text().await.expect("Failed to parse response");
-                Ok(result)
-            } else {
-                Err(Error::new(std::io::ErrorKind::Other, String::from("Failed to authenticate user")))
-            }
+            Err(Error::other(String::from("Failed to authenticate user")))
         }
     }
 
-    pub async fn create_file(&self, path: &str) -> Result<FileInfo, Error> {
-        let url = format!("{}/file/create?path=/{}", self.base_url, path);
-        let response = self.client.post(&url).send().await.expect("Request failed");
+    pub async fn logout(&self) -> Result<(), Error> {
+        let url = format!("{}/user/logout", self.base_url);
+        let response = self.post(&url).await.send().await.expect("Request failed");
         if response.status().is_success() {
-            let result = response.json::<FileInfo>().await.expect("Failed to parse response");
-            Ok(result)
+            *self.session.lock().unwrap() = None;
+            Ok(())
         } else {
-            Err(Error::new(std::io::ErrorKind::Other, String::from("Failed to create file")))
+            Err(Error::other(String::from("Failed to log out")))
         }
     }
 
-    pub async fn read_file(&self, path: &str) -> Result<Vec<ChunkInfo>, Error> {
-        let url = format!("{}/file/read?path=/{}", self.base_url, path);
-        let response = self.client.get(&url).send().await.expect("Request failed");
+    pub async fn create_file(&self, path: &str, mode: Option<&str>, replication: Option<usize>,
+                             erasure: Option<&str>) -> Result<FileInfo, Error> {
+        let mut url = format!("{}/file/create?path=/{}", self.base_url, path);
+        if let Some(mode) = mode {
+            url = format!("{}&mode={}", url, mode);
+        }
+        if let Some(replication) = replication {
+            url = format!("{}&replication={}", url, replication);
+        }
+        if let Some(erasure) = erasure {
+            url = format!("{}&erasure={}", url, erasure);
+        }
+        let response = self.post(&url).await.send().await.expect("Request failed");
         if response.status().is_success() {
-            let result = response.json::<Vec<ChunkInfo>>().await.expect("Failed to parse response");
+            let result = response.json::<FileInfo>().await.expect("Failed to parse response");
             Ok(result)
         } else {
-            Err(Error::new(std::io::ErrorKind::Other, String::from("Failed to read file")))
+            Err(Error::other(String::from("Failed to create file")))
         }
     }
 
-    pub async fn read_all_file(&self, path: &str) -> Result<Vec<ChunkInfo>, Error> {
-        let url = format!("{}/file/read/all?path=/{}", self.base_url, path);
-        let response = self.client.get(&url).send().await.expect("Request failed");
+    pub async fn read_file(&self, path: &str) -> Result<FileChunks, Error> {
+        let url = format!("{}/file/read?path=/{}", self.base_url, path);
+        let response = self.get(&url).await.send().await.expect("Request failed");
         if response.status().is_success() {
-            let result = response.json::<Vec<ChunkInfo>>().await.expect("Failed to parse response");
+            let result = response.json::<FileChunks>().await.expect("Failed to parse response");
             Ok(result)
         } else {
-            Err(Error::new(std::io::ErrorKind::Other, String::from("Failed to read all file chunks")))
+            Err(Error::other(String::from("Failed to read file")))
         }
     }
 
-    pub async fn update_file(&self, path: &str, size: usize) -> Result<Vec<ChunkInfo>, Error> {
+    pub async fn update_file(&self, path: &str, size: u64) -> Result<FileChunks, Error> {
         let url = format!("{}/file/update?path=/{}&size={}", self.base_url, path, size);
-        let response = self.client.post(&url).send().await.expect("Request failed");
+        let response = self.post(&url).await.send().await.expect("Request failed");
         if response.status().is_success() {
-            let result = response.json::<Vec<ChunkInfo>>().await.expect("Failed to parse response");
+            let result = response.json::<FileChunks>().await.expect("Failed to parse response");
             Ok(result)
         } else {
-            Err(Error::new(std::io::ErrorKind::Other, String::from("Failed to update file")))
+            Err(Error::other(String::from("Failed to update file")))
         }
     }
 
     pub async fn delete_file(&self, path: &str) -> Result<(), Error> {
         let url = format!("{}/file/delete?path=/{}", self.base_url, path);
-        let response = self.client.get(&url).send().await.expect("Request failed");
+        let response = self.get(&url).await.send().await.expect("Request failed");
         if response.status().is_success() {
             Ok(())
         } else {
-            Err(Error::new(std::io::ErrorKind::Other, String::from("Failed to delete file")))
+            Err(Error::other(String::from("Failed to delete file")))
         }
     }
 
-    pub async fn create_directory(&self, path: &str) -> Result<String, Error> {
-        let url = format!("{}/dir/create?path=/{}", self.base_url, path);
-        let response = self.client.post(&url).send().await.expect("Request failed");
+    pub async fn create_directory(&self, path: &str, mode: Option<&str>, replication: Option<usize>,
+                                  erasure: Option<&str>) -> Result<String, Error> {
+        let mut url = format!("{}/dir/create?path=/{}", self.base_url, path);
+        if let Some(mode) = mode {
+            url = format!("{}&mode={}", url, mode);
+        }
+        if let Some(replication) = replication {
+            url = format!("{}&replication={}", url, replication);
+        }
+        if let Some(erasure) = erasure {
+            url = format!("{}&erasure={}", url, erasure);
+        }
+        let response = self.post(&url).await.send().await.expect("Request failed");
         if response.status().is_success() {
             let result = response.text().await.expect("Failed to parse response");
             Ok(result)
         } else {
-            Err(Error::new(std::io::ErrorKind::Other, String::from("Failed to create directory")))
+            Err(Error::other(String::from("Failed to create directory")))
         }
     }
 
     pub async fn read_directory(&self, path: &str) -> Result<String, Error> {
         let url = format!("{}/dir/read?path=/{}", self.base_url, path);
-        let response = self.client.get(&url).send().await.expect("Request failed");
+        let response = self.get(&url).await.send().await.expect("Request failed");
         if response.status().is_success() {
             let result = response.json::<DirectoryInfo>().await.expect("Failed to parse response");
             // convert to Json string
             let result = serde_json::to_string(&result).expect("Failed to convert to JSON string");
             Ok(result)
         } else {
-            Err(Error::new(std::io::ErrorKind::Other, String::from("Failed to read directory")))
+            Err(Error::other(String::from("Failed to read directory")))
         }
     }
 
     pub async fn delete_directory(&self, path: &str) -> Result<(), Error> {
         let url = format!("{}/dir/delete?path=/{}", self.base_url, path);
-        let response = self.client.post(&url).send().await.expect("Request failed");
+        let response = self.post(&url).await.send().await.expect("Request failed");
         if response.status().is_success() {
             Ok(())
         } else {
-            Err(Error::new(std::io::ErrorKind::Other, String::from("Failed to delete directory")))
+            Err(Error::other(String::from("Failed to delete directory")))
+        }
+    }
+
+    pub async fn rename_file(&self, from: &str, to: &str) -> Result<(), Error> {
+        let url = format!("{}/file/rename?from=/{}&to=/{}", self.base_url, from, to);
+        let response = self.post(&url).await.send().await.expect("Request failed");
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::other(String::from("Failed to rename file")))
+        }
+    }
+
+    pub async fn rename_directory(&self, from: &str, to: &str) -> Result<(), Error> {
+        let url = format!("{}/dir/rename?from=/{}&to=/{}", self.base_url, from, to);
+        let response = self.post(&url).await.send().await.expect("Request failed");
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::other(String::from("Failed to rename directory")))
+        }
+    }
+
+    pub async fn chmod(&self, path: &str, mode: &str) -> Result<(), Error> {
+        let url = format!("{}/chmod?path=/{}&mode={}", self.base_url, path, mode);
+        let response = self.post(&url).await.send().await.expect("Request failed");
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::other(String::from("Failed to change mode")))
         }
     }
-}
 
+    pub async fn set_replication(&self, path: &str, replication: usize) -> Result<(), Error> {
+        let url = format!("{}/setrep?path=/{}&replication={}", self.base_url, path, replication);
+        let response = self.post(&url).await.send().await.expect("Request failed");
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::other(String::from("Failed to change replication")))
+        }
+    }
+
+    pub async fn chown(&self, path: &str, owner: &str, group: Option<&str>) -> Result<(), Error> {
+        let mut url = format!("{}/chown?path=/{}&owner={}", self.base_url, path, owner);
+        if let Some(group) = group {
+            url = format!("{}&group={}", url, group);
+        }
+        let response = self.post(&url).await.send().await.expect("Request failed");
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::other(String::from("Failed to change owner")))
+        }
+    }
+
+    pub async fn list_users(&self) -> Result<Vec<UserSummary>, Error> {
+        let url = format!("{}/admin/users", self.base_url);
+        let response = self.get(&url).await.send().await.expect("Request failed");
+        if response.status().is_success() {
+            let result = response.json::<Vec<UserSummary>>().await.expect("Failed to parse response");
+            Ok(result)
+        } else {
+            Err(Error::other(String::from("Failed to list users")))
+        }
+    }
+
+    pub async fn change_password(&self, user: &User) -> Result<(), Error> {
+        let url = format!("{}/admin/user/password", self.base_url);
+        let response = self.post(&url).await.json(user).send().await.expect("Request failed");
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::other(String::from("Failed to change password")))
+        }
+    }
+
+    pub async fn set_user_disabled(&self, username: &str, disabled: bool) -> Result<(), Error> {
+        let url = format!("{}/admin/user/disable?username={}&disabled={}",
+                          self.base_url, username, disabled);
+        let response = self.post(&url).await.send().await.expect("Request failed");
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::other(String::from("Failed to change user state")))
+        }
+    }
+
+    pub async fn delete_user(&self, username: &str) -> Result<(), Error> {
+        let url = format!("{}/admin/user/delete?username={}", self.base_url, username);
+        let response = self.post(&url).await.send().await.expect("Request failed");
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::other(String::from("Failed to delete user")))
+        }
+    }
+
+    pub async fn set_user_admin(&self, username: &str, admin: bool) -> Result<(), Error> {
+        let url = format!("{}/admin/user/admin?username={}&admin={}",
+                          self.base_url, username, admin);
+        let response = self.post(&url).await.send().await.expect("Request failed");
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::other(String::from("Failed to change admin rights")))
+        }
+    }
+
+    pub async fn rebalance(&self, threshold: Option<f64>, budget: Option<u64>, dry_run: bool)
+        -> Result<RebalancePlan, Error> {
+        let mut url = format!("{}/rebalance?dry_run={}", self.base_url, dry_run);
+        if let Some(threshold) = threshold {
+            url.push_str(&format!("&threshold={}", threshold));
+        }
+        if let Some(budget) = budget {
+            url.push_str(&format!("&budget={}", budget));
+        }
+        let response = self.post(&url).await.send().await.expect("Request failed");
+        if response.status().is_success() {
+            let result = response.json::<RebalancePlan>().await.expect("Failed to parse response");
+            Ok(result)
+        } else {
+            Err(Error::other(String::from("Failed to rebalance")))
+        }
+    }
+}
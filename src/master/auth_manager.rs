@@ -0,0 +1,273 @@
+/*
+*   Issues and checks the session tokens of the master.
+*
+*   A user logs in once with username and password and gets back a
+*   signed token that expires after SESSION_DURATION. Every namespace
+*   route takes an `AuthUser` argument, which Rocket fills in from the
+*   "Authorization: Bearer <token>" header of the request. Requests
+*   without a valid token are rejected with 401 before the route runs.
+*
+*   Token Format : base64(claims).base64(HMAC-SHA256(claims))
+*       Claims {
+*           Username        : String
+*           Session ID      : Uuid
+*           Expires         : DateTime
+*       }
+*
+*   Passwords are stored as salted Argon2id hashes in PHC string
+*   format ($argon2id$v=19$...), never in plaintext.
+*
+*   The signing key is generated when the master starts and the live
+*   sessions are kept in memory, so a restart logs everybody out.
+*   Logging out (or refreshing) removes the session, which revokes
+*   its token even though the signature is still valid.
+*
+*   Admin routes take an `AdminUser` argument instead, which also
+*   requires the caller to be an enabled admin of the user store.
+*/
+
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::{Mutex, OnceLock};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+use lib::shared::master_client_utils::SessionToken;
+use crate::user_manager::UserDatabase;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SESSION_DURATION: i64 = 3600;
+const BEARER_PREFIX: &str = "Bearer ";
+
+static SIGNING_KEY: OnceLock<[u8; 32]> = OnceLock::new();
+// Session ID -> (Username, Session Expiry)
+type SessionMap = HashMap<Uuid, (String, DateTime<Utc>)>;
+static SESSIONS: Mutex<Option<SessionMap>> = Mutex::new(None);
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Claims {
+    username: String,
+    session: Uuid,
+    expires: DateTime<Utc>,
+}
+
+/// The authenticated caller of a request.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub username: String,
+    pub session: Uuid,
+}
+
+pub fn auth_manager_init() {
+    SIGNING_KEY.get_or_init(|| {
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        key
+    });
+    SESSIONS.lock().unwrap().get_or_insert_with(HashMap::new);
+}
+
+fn mac() -> HmacSha256 {
+    let key = SIGNING_KEY.get().expect("Auth manager not initialized");
+    HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size")
+}
+
+/*
+*   Starts a new session for the user and returns its token.
+*/
+pub fn issue_token(username: &str) -> SessionToken {
+    let now = Utc::now();
+    let claims = Claims {
+        username: username.to_string(),
+        session: Uuid::new_v4(),
+        expires: now + Duration::seconds(SESSION_DURATION),
+    };
+
+    let mut sessions = SESSIONS.lock().unwrap();
+    let sessions = sessions.get_or_insert_with(HashMap::new);
+    // forget the sessions that ended on their own
+    sessions.retain(|_, (_, expires)| *expires > now);
+    sessions.insert(claims.session, (claims.username.clone(), claims.expires));
+
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+    let mut mac = mac();
+    mac.update(payload.as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+    SessionToken {
+        token: format!("{}.{}", payload, signature),
+        expires: claims.expires,
+    }
+}
+
+/*
+*   Returns the caller of a token if its signature is valid and
+*   its session has neither expired nor been revoked.
+*/
+pub fn verify_token(token: &str) -> Option<AuthUser> {
+    let (payload, signature) = token.split_once('.')?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    let mut mac = mac();
+    mac.update(payload.as_bytes());
+    // constant time comparison
+    mac.verify_slice(&signature).ok()?;
+
+    let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    if claims.expires <= Utc::now() {
+        return None;
+    }
+    let sessions = SESSIONS.lock().unwrap();
+    if !sessions.as_ref()?.contains_key(&claims.session) {
+        return None;
+    }
+    Some(AuthUser { username: claims.username, session: claims.session })
+}
+
+/*
+*   Ends a session, invalidating its token.
+*/
+pub fn revoke(session: &Uuid) {
+    if let Some(sessions) = SESSIONS.lock().unwrap().as_mut() {
+        sessions.remove(session);
+    }
+}
+
+/*
+*   Ends every session of a user, e.g. when the account is
+*   disabled or deleted.
+*/
+pub fn revoke_user(username: &str) {
+    if let Some(sessions) = SESSIONS.lock().unwrap().as_mut() {
+        sessions.retain(|_, (user, _)| user != username);
+    }
+}
+
+/////////////////////////////////////////////////////
+// Passwords
+
+/*
+*   Hashes a password with a fresh random salt.
+*/
+pub fn hash_password(password: &str) -> Result<String, Error> {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).map_err(|e| Error::other(e.to_string()))?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| Error::other(e.to_string()))
+}
+
+/*
+*   Checks a password against a stored hash. The hashes are
+*   compared in constant time.
+*/
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/*
+*   Whether a stored password is already hashed, as opposed
+*   to a plaintext entry from before passwords were hashed.
+*/
+pub fn is_password_hash(stored: &str) -> bool {
+    PasswordHash::new(stored).is_ok()
+}
+
+/////////////////////////////////////////////////////
+// Request Guard
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthUser {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = request.headers().get_one("Authorization")
+            .and_then(|header| header.strip_prefix(BEARER_PREFIX));
+        match token.map(verify_token) {
+            Some(Some(user)) => Outcome::Success(user),
+            Some(None) => Outcome::Error((Status::Unauthorized, "Invalid or expired token")),
+            None => Outcome::Error((Status::Unauthorized, "Missing token")),
+        }
+    }
+}
+
+/// The authenticated caller of an admin route.
+#[derive(Debug, Clone)]
+pub struct AdminUser {
+    pub username: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminUser {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user = match AuthUser::from_request(request).await {
+            Outcome::Success(user) => user,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        let user_db = match request.guard::<&State<UserDatabase>>().await {
+            Outcome::Success(user_db) => user_db,
+            _ => return Outcome::Error((Status::InternalServerError, "User database not found")),
+        };
+        if !user_db.is_admin(&user.username).await {
+            return Outcome::Error((Status::Forbidden, "Admin rights required"));
+        }
+        Outcome::Success(AdminUser { username: user.username })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_roundtrip_and_revoke() {
+        auth_manager_init();
+        let token = issue_token("alice");
+        let user = verify_token(&token.token).unwrap();
+        assert_eq!(user.username, "alice");
+
+        revoke(&user.session);
+        assert!(verify_token(&token.token).is_none());
+    }
+
+    #[test]
+    fn test_password_hash() {
+        let hash = hash_password("secret").unwrap();
+        assert!(is_password_hash(&hash));
+        assert!(!is_password_hash("secret"));
+        assert!(verify_password("secret", &hash));
+        assert!(!verify_password("Secret", &hash));
+        assert_ne!(hash, hash_password("secret").unwrap());
+    }
+
+    #[test]
+    fn test_tampered_token_is_rejected() {
+        auth_manager_init();
+        let token = issue_token("alice");
+        let (_, signature) = token.token.split_once('.').unwrap();
+        let claims = Claims {
+            username: "root".to_string(),
+            session: Uuid::new_v4(),
+            expires: Utc::now() + Duration::seconds(SESSION_DURATION),
+        };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+        assert!(verify_token(&format!("{}.{}", payload, signature)).is_none());
+    }
+}
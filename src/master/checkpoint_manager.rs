@@ -0,0 +1,244 @@
+/*
+*   Periodically checkpoints the master's in-memory state so that
+*   the operation log does not grow forever and a restart only
+*   needs to replay the operations done after the last checkpoint.
+*
+*
+*   Checkpoint Format : checkpoints/checkpoint-<lsn>.json
+*       Checkpoint {
+*           Version         : u32 (format of the file)
+*           LSN             : u64 (last operation covered)
+*           Created         : DateTime
+*           Directories     : List<Directory Info>
+*           Chunks          : List<(Chunk Handle, Chunk Info)>
+*       }
+*
+*   The chunks held by each chunkserver are not checkpointed, the
+*   chunkservers report them in their heartbeats.
+*
+*   Functions :
+*       1. Create Checkpoint
+*           --> Snapshot the maps at a log position
+*           --> Write to a temp file, fsync, rename
+*           --> Drop covered records from the operation log
+*       2. Load Latest Checkpoint
+*/
+
+use std::fs::File;
+use std::io::{Error, Write};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Duration};
+use uuid::Uuid;
+use lib::shared::master_client_utils::DirectoryInfo;
+use crate::{chunk_manager, lock_manager, log_manager, namespace_manager};
+use crate::chunk_manager::{ChunkInfo, Replica};
+
+const CHECKPOINT_VERSION: u32 = 3;
+const CHECKPOINT_PREFIX: &str = "checkpoint-";
+const CHECKPOINT_INTERVAL: u64 = 60;
+const CHECKPOINTS_KEPT: usize = 2;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Checkpoint {
+    pub version: u32,
+    pub lsn: u64,
+    pub created: DateTime<Utc>,
+    pub directories: Vec<DirectoryInfo>,
+    pub chunks: Vec<(Uuid, ChunkInfo)>,
+}
+
+pub fn checkpoint_manager_init(dir: &str) -> Result<(), Error> {
+    std::fs::create_dir_all(dir)
+}
+
+fn checkpoint_path(dir: &Path, lsn: u64) -> PathBuf {
+    // zero padded so that the file names sort by lsn
+    dir.join(format!("{}{:020}.json", CHECKPOINT_PREFIX, lsn))
+}
+
+/*
+*   Lists the checkpoint files of a directory, newest first.
+*/
+fn list_checkpoints(dir: &Path) -> Result<Vec<(u64, PathBuf)>, Error> {
+    let mut checkpoints = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let lsn = path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(CHECKPOINT_PREFIX))
+            .and_then(|name| name.strip_suffix(".json"))
+            .and_then(|lsn| lsn.parse::<u64>().ok());
+        if let Some(lsn) = lsn {
+            checkpoints.push((lsn, path));
+        }
+    }
+    checkpoints.sort_by_key(|(lsn, _)| std::cmp::Reverse(*lsn));
+    Ok(checkpoints)
+}
+
+/*
+*   Writes a checkpoint atomically : the data goes to a temp
+*   file which is fsynced and then renamed to its final name.
+*/
+pub fn write_checkpoint(dir: &Path, checkpoint: &Checkpoint) -> Result<PathBuf, Error> {
+    let path = checkpoint_path(dir, checkpoint.lsn);
+    let tmp_path = path.with_extension("tmp");
+
+    let data = serde_json::to_vec(checkpoint)?;
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, &path)?;
+
+    // make the rename itself durable
+    File::open(dir)?.sync_all()?;
+    Ok(path)
+}
+
+/*
+*   Returns the newest checkpoint that can be read and has
+*   the current format, skipping damaged ones.
+*/
+pub fn load_latest_checkpoint(dir: &Path) -> Result<Option<Checkpoint>, Error> {
+    for (lsn, path) in list_checkpoints(dir)? {
+        let checkpoint = std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| serde_json::from_slice::<Checkpoint>(&data)
+                .map_err(|e| e.to_string()));
+        match checkpoint {
+            Ok(checkpoint) if checkpoint.version == CHECKPOINT_VERSION
+                && checkpoint.lsn == lsn => return Ok(Some(checkpoint)),
+            Ok(checkpoint) => warn!("Skipping checkpoint {:?} with version {}",
+                                    path, checkpoint.version),
+            Err(e) => warn!("Skipping invalid checkpoint {:?}: {}", path, e),
+        }
+    }
+    Ok(None)
+}
+
+/*
+*   Loads the newest valid checkpoint into the namespace and chunk
+*   maps. Returns the lsn it covers (0 if there is none).
+*/
+pub fn restore_latest_checkpoint(dir: &str) -> Result<u64, Error> {
+    match load_latest_checkpoint(Path::new(dir))? {
+        Some(checkpoint) => {
+            namespace_manager::restore_dir_state(checkpoint.directories);
+            chunk_manager::restore_chunk_map(checkpoint.chunks);
+            Ok(checkpoint.lsn)
+        }
+        None => Ok(0),
+    }
+}
+
+/*
+*   Takes a checkpoint of the current state, then drops the
+*   operation log records and older checkpoints it makes useless.
+*/
+pub fn create_checkpoint(dir: &str) -> Result<u64, Error> {
+    let dir = Path::new(dir);
+    let checkpoint = log_manager::checkpoint(|lsn| Checkpoint {
+        version: CHECKPOINT_VERSION,
+        lsn,
+        created: Utc::now(),
+        directories: namespace_manager::snapshot_dir_state(),
+        chunks: chunk_manager::snapshot_chunk_map(),
+    })?;
+
+    let path = write_checkpoint(dir, &checkpoint)?;
+    let dropped = log_manager::truncate(checkpoint.lsn)?;
+    info!("Wrote checkpoint {:?}, dropped {} log records", path, dropped);
+
+    for (_, old) in list_checkpoints(dir)?.into_iter().skip(CHECKPOINTS_KEPT) {
+        std::fs::remove_file(old)?;
+    }
+    Ok(checkpoint.lsn)
+}
+
+/*
+*   Background task creating a checkpoint every CHECKPOINT_INTERVAL
+*   seconds when operations were logged since the last one.
+*/
+pub async fn checkpoint_loop(dir: String, mut last_lsn: u64) {
+    let interval = Duration::from_secs(CHECKPOINT_INTERVAL);
+    loop {
+        sleep(interval).await;
+
+        let current_lsn = lock_manager::blocking(|| log_manager::checkpoint(|lsn| lsn)).await
+            .unwrap_or(last_lsn);
+        if current_lsn == last_lsn {
+            continue;
+        }
+
+        let dir = dir.clone();
+        match tokio::task::spawn_blocking(move || create_checkpoint(&dir)).await {
+            Ok(Ok(lsn)) => last_lsn = lsn,
+            Ok(Err(e)) => error!("Failed to create checkpoint: {}", e),
+            Err(e) => error!("Checkpoint task failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::shared::master_client_utils::ErasureCoding;
+
+    const TEST_CHECKPOINT_DIR: &str = "/tmp/dfs_checkpoints";
+
+    fn pre_test(dir: &str) {
+        // if directory exists, clear it
+        if Path::new(dir).exists() {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+        std::fs::create_dir_all(dir).unwrap();
+    }
+
+    fn checkpoint(lsn: u64) -> Checkpoint {
+        Checkpoint {
+            version: CHECKPOINT_VERSION,
+            lsn,
+            created: Utc::now(),
+            directories: Vec::new(),
+            chunks: vec![(Uuid::new_v4(), ChunkInfo {
+                version: 1,
+                last_modified: Utc::now(),
+                replicas: vec![Replica {
+                    server: "localhost:8100".to_string(),
+                    version: 1,
+                    size: 64,
+                    checksum: None,
+                    fragment: Some(0),
+                }],
+                replication: 2,
+                erasure: Some(ErasureCoding { data: 1, parity: 1 }),
+            })],
+        }
+    }
+
+    #[test]
+    fn test_load_latest_checkpoint() {
+        let dir = format!("{}/test_load_latest", TEST_CHECKPOINT_DIR);
+        pre_test(&dir);
+
+        write_checkpoint(Path::new(&dir), &checkpoint(3)).unwrap();
+        write_checkpoint(Path::new(&dir), &checkpoint(12)).unwrap();
+
+        let latest = load_latest_checkpoint(Path::new(&dir)).unwrap().unwrap();
+        assert_eq!(latest.lsn, 12);
+    }
+
+    #[test]
+    fn test_load_skips_damaged_checkpoint() {
+        let dir = format!("{}/test_load_damaged", TEST_CHECKPOINT_DIR);
+        pre_test(&dir);
+
+        write_checkpoint(Path::new(&dir), &checkpoint(3)).unwrap();
+        std::fs::write(checkpoint_path(Path::new(&dir), 7), b"{\"version\":2,").unwrap();
+
+        let latest = load_latest_checkpoint(Path::new(&dir)).unwrap().unwrap();
+        assert_eq!(latest.lsn, 3);
+    }
+}
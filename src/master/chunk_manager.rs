@@ -7,48 +7,162 @@
 *   Data Structures :
 *       1.  Chunk Map           : Map (Chunk Handle -> Chunk Info)
 *       2.  Chunk Info {
-*               Version         : uint
-*               Locations       : List<Server Locations (IP String)>
-*               Size            : int
+*               Version         : u64 (current version of the chunk)
 *               Last Modified   : DateTime
-*               Valid Lease     : bool
-*               Primary Server  : Server Location (IP String)
+*               Replicas        : List<Replica>
+*           }
+*           Replica {
+*               Server          : Server Location (IP String)
+*               Fragment        : Option<usize> (index of the fragment held,
+*                                 for a chunk of an erasure-coded file)
+*               Version         : u64 (last reported by the server)
+*               Size            : u64 (last reported by the server)
+*               Checksum        : SHA-256 (last reported by the server)
 *           }
 *       3. Chunkserver Map      : Map (IP (String) -> List<Uuid>)
+*               Not persisted : rebuilt from the chunk reports
+*               in the heartbeats of the chunkservers.
+*       4. Lease Map            : Map (Chunk Handle -> Lease { Primary Server, Expiry })
+*       5. Deletion Queue       : Map (IP (String) -> List<Uuid>)
+*               Chunks dropped from the namespace that still have
+*               to be deleted on the chunkserver. They are sent to
+*               the chunkserver as a DeleteChunks command in the
+*               reply to its next heartbeat.
+*       6. Report Map           : Map (IP (String) -> Set<Uuid>)
+*               Chunks each chunkserver reported holding, whether
+*               the chunk map knows them or not.
+*
+*   Files are split into chunks of CHUNK_SIZE bytes (64 MB unless
+*   set with the DFS_CHUNK_SIZE environment variable). Every chunk
+*   index of a file has one chunk handle, stored under that handle on
+*   each of the chunkservers holding a replica. A chunk has as many
+*   replicas as the replication of its file (REPLICATION_FACTOR unless
+*   set on the file or one of its directories).
+*
+*   Erasure coding :
+*       A chunk of an erasure-coded file is not replicated. It is cut
+*       into data + parity fragments (see shared::erasure), fragment i
+*       is stored under the chunk handle on the i-th of data + parity
+*       distinct chunkservers. Its "replicas" are these fragments, each
+*       tagged with its index. Fragments are written once : a write to
+*       an erasure-coded file allocates new chunks, so they get no lease.
 *
+*   Versions :
+*       Every write to a chunk bumps its version when the lease is granted.
+*       A replica only takes the new version when it applies the write,
+*       together with its data, so a replica at the version of the master
+*       has the data written at that version.
+*       Chunkservers report the version of each chunk they hold in their
+*       heartbeats. The master only acts on them once every live replica
+*       of the chunk was in a full report since the master started, so it
+*       never decides on part of the replicas. A replica still reporting
+*       an older version STALE_GRACE_PERIOD seconds after the bump missed
+*       the write : it is stale, so it is dropped from the replicas of the
+*       chunk (no longer handed out) and deleted. If no replica reports the
+*       version of the master by then, the write was abandoned and the
+*       master rolls back to the newest version reported instead, so the
+*       last replicas of the data are never dropped. A replica ahead of the
+*       master saw a write the master does not know about, its version is
+*       adopted. Rollbacks and adoptions are logged (SetChunkVersion) so
+*       that the version is the same after a restart.
+*
+*   Leases :
+*       A write to a chunk grants (or extends) a lease of LEASE_DURATION
+*       seconds to the server of one of its replicas, the primary. Clients send the
+*       data only to the primary, which orders the mutations with serial
+*       numbers and forwards them to the other replicas (secondaries),
+*       so every replica applies the writes in the same order. Leases are
+*       not logged : they are lost when the master restarts.
 */
 #![allow(unused)]
 
 use crate::safe_map::SafeMap;
+use crate::heartbeat_manager;
+use crate::log_manager::{self, Operation};
+use crate::namespace_manager;
+use crate::placement_manager;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
 use chrono::{DateTime, Utc};
+use lib::shared::master_chunk_utils::{ChunkReport, CHUNK_SIZE_MAX};
 use rand::prelude::*;
-use rand::seq::SliceRandom;
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use serde::{Deserialize, Serialize};
+use lib::shared::master_client_utils::ChunkInfo as ChunkLocations;
+use lib::shared::master_client_utils::{ErasureCoding, DEFAULT_REPLICATION};
 use uuid::Uuid;
 
-static CHUNK_MAP: SafeMap<Uuid, String> = SafeMap::new();
+static CHUNK_MAP: SafeMap<Uuid, ChunkInfo> = SafeMap::new();
 pub static SERVER_MAP: SafeMap<String, Vec<Uuid>> = SafeMap::new();
+static LEASE_MAP: SafeMap<Uuid, Lease> = SafeMap::new();
+static DELETION_QUEUE: SafeMap<String, Vec<Uuid>> = SafeMap::new();
+static REPORT_MAP: SafeMap<String, HashSet<Uuid>> = SafeMap::new();
+
+const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024 * 64; // 64 MB
+const CHUNK_SIZE_ENV: &str = "DFS_CHUNK_SIZE";
+pub const REPLICATION_FACTOR: usize = DEFAULT_REPLICATION;
+const STALE_GRACE_PERIOD: i64 = 60;
+const LEASE_DURATION: i64 = 60;
+
+static CHUNK_SIZE: AtomicU64 = AtomicU64::new(DEFAULT_CHUNK_SIZE);
 
-// pub struct ChunkInfo {
-//     version: u16,
-//     locations: Vec<String>,
-//     size: i64,
-//     last_modified: DateTime<Utc>,
-//     primary_server: String,
-// }
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkInfo {
+    pub version: u64,
+    pub last_modified: DateTime<Utc>,
+    pub replicas: Vec<Replica>,
+    // replicas the chunk should have, the replication of its file
+    #[serde(default = "default_replication")]
+    pub replication: usize,
+    // layout of the fragments, for a chunk of an erasure-coded file
+    #[serde(default)]
+    pub erasure: Option<ErasureCoding>,
+}
+
+fn default_replication() -> usize {
+    REPLICATION_FACTOR
+}
+
+/*
+*   A copy of a chunk on a chunkserver, as last reported by the
+*   server (version 0 and no checksum until the first report).
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Replica {
+    pub server: String,
+    pub version: u64,
+    pub size: u64,
+    #[serde(default)]
+    pub checksum: Option<String>,
+    // index of the fragment held, for a chunk of an erasure-coded file
+    #[serde(default)]
+    pub fragment: Option<usize>,
+}
 
-// impl ChunkInfo {
-//     pub fn new(locations : Vec<String>, ) -> Self{
+impl Replica {
+    fn new(server: &str, version: u64) -> Self {
+        Replica { server: server.to_string(), version, size: 0, checksum: None, fragment: None }
+    }
+}
 
-//     }
-// }
+#[derive(Debug, Clone)]
+struct Lease {
+    primary: String,
+    expires: DateTime<Utc>,
+}
 
 pub fn chunk_manager_init() {
     SERVER_MAP.init();
     CHUNK_MAP.init();
-    load_chunk_map();
-    load_server_map();
+    LEASE_MAP.init();
+    DELETION_QUEUE.init();
+    REPORT_MAP.init();
+
+    if let Ok(size) = std::env::var(CHUNK_SIZE_ENV) {
+        match size.parse::<u64>() {
+            Ok(size) if size > 0 && size <= CHUNK_SIZE_MAX as u64 => set_chunk_size(size),
+            _ => panic!("{} must be between 1 and {} bytes", CHUNK_SIZE_ENV, CHUNK_SIZE_MAX),
+        }
+    }
     /*
      *  Some dummy servers init
      */
@@ -59,124 +173,630 @@ pub fn chunk_manager_init() {
     // SERVER_MAP.insert("host4".to_string(), Vec::new());
 }
 
-// TODO: pass CHUNK_FILE from main
-pub async fn save_chunk_map() {
-    let mut file = OpenOptions::new()
-        .write(true).create(true)
-        .open("chunk.json").await.unwrap();
+/*
+*   Copy of the chunk map for a checkpoint.
+*/
+pub fn snapshot_chunk_map() -> Vec<(Uuid, ChunkInfo)> {
+    CHUNK_MAP.to_map().into_iter().collect()
+}
 
-    for (uuid, id) in CHUNK_MAP.to_map().iter() {
-        file.write_all(format!("{},{}\n", uuid.to_string(), id)
-            .as_bytes()).await.unwrap();
+/*
+*   Loads the chunk map from a checkpoint.
+*/
+pub fn restore_chunk_map(chunks: Vec<(Uuid, ChunkInfo)>) {
+    for (uuid, info) in chunks {
+        CHUNK_MAP.insert(uuid, info);
     }
 }
 
-pub async fn load_chunk_map() {
-    let mut file = OpenOptions::new()
-        .read(true).create(true)
-        .open("chunk.json").await.unwrap();
+pub fn chunk_size() -> u64 {
+    CHUNK_SIZE.load(Ordering::Relaxed)
+}
+
+pub fn set_chunk_size(size: u64) {
+    CHUNK_SIZE.store(size, Ordering::Relaxed);
+}
+
+/*
+*   Number of chunks holding a file of `size` bytes.
+*/
+pub fn chunk_count(size: u64) -> usize {
+    size.div_ceil(chunk_size()) as usize
+}
 
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
+/*
+*   Function that returns a new chunk handle and the best
+*   chunkservers to hold one chunk index of a file. Nothing is
+*   recorded until the allocation is registered, so that it
+*   can be logged first.
+*/
+pub fn allocate_chunks(replication: usize) -> Result<(Uuid, Vec<String>), String> {
+    /*
+     *  1. Find best chunkservers (see the placement manager)
+     *  2. Generate a chunk handle
+     *  3. Return chunk handle + chunk locations
+     */
+    let servers = placement_manager::place(replication, &[])
+        .inspect_err(|e| warn!("Chunk allocation failed: {}", e))?;
+    Ok((Uuid::new_v4(), servers))
+}
 
-    while let Some(line_result) = lines.next_line().await.unwrap() {
-        // stores as <uuid>,<string> at each line
-        let mut parts = line_result.split(",");
-        let uuid = Uuid::parse_str(parts.next().unwrap()).unwrap();
-        CHUNK_MAP.insert(uuid, parts.next().unwrap().to_string());
+fn add_to_server(server: &str, uuid: Uuid) {
+    match SERVER_MAP.get(&server.to_string()) {
+        Some(uuids) => {
+            let mut uuids = uuids.write().unwrap();
+            if !uuids.contains(&uuid) {
+                uuids.push(uuid);
+            }
+        }
+        None => { SERVER_MAP.insert(server.to_string(), vec![uuid]); }
     }
 }
 
-// TODO: pass SERVER_FILE from main
-pub async fn save_server_map() {
-    let mut file = OpenOptions::new()
-        .write(true).create(true)
-        .open("server.json").await.unwrap();
+fn remove_from_server(server: &str, uuid: &Uuid) {
+    if let Some(uuids) = SERVER_MAP.get(&server.to_string()) {
+        uuids.write().unwrap().retain(|id| id != uuid);
+    }
+}
 
-    for (server, uuids) in SERVER_MAP.to_map().iter() {
-        let mut line = format!("{},", server);
-        uuids.iter().for_each(|uuid| {
-            line.push_str(&uuid.to_string());
-            line.push(',');
-        });
-        file.write_all(line.as_bytes()).await.unwrap();
+/*
+*   Records the chunk a write goes to. A new chunk starts at
+*   version 1 on the given servers, the version of an existing
+*   one is bumped.
+*/
+pub fn register_chunks(uuid: Uuid, servers: &[String]) {
+    let now = Utc::now();
+    if let Some(info) = CHUNK_MAP.get(&uuid) {
+        let mut info = info.write().unwrap();
+        info.version += 1;
+        info.last_modified = now;
+        return;
     }
+    for server in servers {
+        add_to_server(server, uuid);
+    }
+    CHUNK_MAP.insert(uuid, ChunkInfo {
+        version: 1,
+        last_modified: now,
+        replicas: servers.iter().map(|server| Replica::new(server, 1)).collect(),
+        replication: REPLICATION_FACTOR,
+        erasure: None,
+    });
 }
 
-pub async fn load_server_map() {
-    let mut file = OpenOptions::new()
-        .read(true).create(true)
-        .open("server.json").await.unwrap();
+/*
+*   Records a new chunk of an erasure-coded file, fragment i
+*   on servers[i]. An existing chunk gets its version bumped.
+*/
+pub fn register_fragments(uuid: Uuid, servers: &[String], erasure: ErasureCoding) {
+    if CHUNK_MAP.get(&uuid).is_some() {
+        register_chunks(uuid, servers);
+        return;
+    }
+    for server in servers {
+        add_to_server(server, uuid);
+    }
+    CHUNK_MAP.insert(uuid, ChunkInfo {
+        version: 1,
+        last_modified: Utc::now(),
+        replicas: servers.iter()
+            .enumerate()
+            .map(|(i, server)| Replica { fragment: Some(i), ..Replica::new(server, 1) })
+            .collect(),
+        replication: erasure.fragments(),
+        erasure: Some(erasure),
+    });
+}
+
+/*
+*   Sets the number of replicas a chunk should have, the replication
+*   manager adds or removes replicas to get there. Chunks of
+*   erasure-coded files keep one copy of each fragment.
+*/
+pub fn set_replication(uuid: &Uuid, replication: usize) {
+    if let Some(info) = CHUNK_MAP.get(uuid) {
+        let mut info = info.write().unwrap();
+        if info.erasure.is_none() {
+            info.replication = replication;
+        }
+    }
+}
+
+pub fn chunk_erasure(uuid: &Uuid) -> Option<ErasureCoding> {
+    CHUNK_MAP.get(uuid).and_then(|info| info.read().unwrap().erasure)
+}
 
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
+pub fn chunk_replication(uuid: &Uuid) -> usize {
+    CHUNK_MAP.get(uuid)
+        .map(|info| info.read().unwrap().replication)
+        .unwrap_or(REPLICATION_FACTOR)
+}
 
-    while let Some(line_result) = lines.next_line().await.unwrap() {
-        // stores as <string>,<uuid>,<uuid>,... at each line
-        let mut parts = line_result.split(",");
-        let server = parts.next().unwrap().to_string();
-        let mut uuids = Vec::new();
-        for part in parts {
-            uuids.push(Uuid::parse_str(part).unwrap());
+/*
+*   Records a replica copied from another one by re-replication,
+*   it starts at the version of its source.
+*/
+pub fn add_replica(uuid: Uuid, server: &str, version: u64) -> Result<(), String> {
+    let info = CHUNK_MAP.get(&uuid).ok_or_else(|| format!("Chunk {} is gone", uuid))?;
+    let mut info = info.write().unwrap();
+    if !info.replicas.iter().any(|replica| replica.server == server) {
+        info.replicas.push(Replica::new(server, version));
+    }
+    add_to_server(server, uuid);
+    Ok(())
+}
+
+/*
+*   Records a fragment of a chunk rebuilt (or copied) on a server.
+*/
+pub fn add_fragment(uuid: Uuid, server: &str, fragment: usize, version: u64) -> Result<(), String> {
+    let info = CHUNK_MAP.get(&uuid).ok_or_else(|| format!("Chunk {} is gone", uuid))?;
+    let mut info = info.write().unwrap();
+    if info.erasure.is_none_or(|erasure| fragment >= erasure.fragments()) {
+        return Err(format!("Chunk {} has no fragment {}", uuid, fragment));
+    }
+    if !info.replicas.iter().any(|replica| replica.server == server) {
+        info.replicas.push(Replica { fragment: Some(fragment), ..Replica::new(server, version) });
+    }
+    add_to_server(server, uuid);
+    Ok(())
+}
+
+/*
+*   Servers each fragment of an erasure-coded chunk is
+*   recorded on, dead or not, in fragment order.
+*/
+pub fn fragment_servers(uuid: &Uuid) -> Vec<Vec<String>> {
+    let info = match chunk_info(uuid) {
+        Some(info) => info,
+        None => return Vec::new(),
+    };
+    let count = info.erasure.map(|erasure| erasure.fragments()).unwrap_or(0);
+    let mut servers = vec![Vec::new(); count];
+    for replica in info.replicas {
+        if let Some(holders) = replica.fragment.and_then(|fragment| servers.get_mut(fragment)) {
+            holders.push(replica.server);
         }
-        SERVER_MAP.insert(server, uuids);
     }
+    servers
+}
+
+pub fn chunk_version(uuid: &Uuid) -> Option<u64> {
+    CHUNK_MAP.get(uuid).map(|info| info.read().unwrap().version)
+}
+
+pub fn chunk_info(uuid: &Uuid) -> Option<ChunkInfo> {
+    CHUNK_MAP.get(uuid).map(|info| info.read().unwrap().clone())
 }
 
 /*
-*   Function that returns the best chunks to hold the
-*   data
+*   Servers a replica of the chunk is recorded on, dead or not.
 */
-pub fn write_chunks(_size: usize) -> Vec<(Uuid, String)> {
-    /*
-     *  1. Find best chunkservers (At the moment we do it randomly)
-     *  2. Generate chunk handles
-     *  3. Send chunk handles to chunkservers
-     *  4. Assign chunk informations
-     *  5. Return chunk handles + chunk locations
-     */
-    let mut thingy: Vec<(Uuid, String)> = Vec::new(); // Initialize as mutable
-    let all_keys = SERVER_MAP.keys(); // Collect keys into a Vec first
-    println!("{:?}", all_keys);
-    // Sample 3 random keys
-    let sample = all_keys
-        .choose_multiple(&mut rand::thread_rng(), 3)
-        .collect::<Vec<_>>();
-    println!("{:?}", sample);
-    // Generate 3 UUIDs
-    let uuids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
-
-    // Zip sample and UUIDs together and process them
-    for (uuid, key) in uuids.iter().zip(sample.iter()) {
-        if let Some(server) = SERVER_MAP.get(key) {
-            if let Ok(mut server_write) = server.write() {
-                server_write.push(*uuid); // Assuming join takes a UUID
-                thingy.push((*uuid, key.to_string())); // Store the UUID and key
+pub fn chunk_servers(uuid: &Uuid) -> Vec<String> {
+    CHUNK_MAP.get(uuid)
+        .map(|info| info.read().unwrap().replicas.iter().map(|r| r.server.clone()).collect())
+        .unwrap_or_default()
+}
+
+/*
+*   Servers holding a replica of the chunk,
+*   leaving out the dead ones.
+*/
+pub fn get_replicas(uuid: &Uuid) -> Vec<String> {
+    chunk_servers(uuid)
+        .into_iter()
+        .filter(|server| !heartbeat_manager::is_dead(server))
+        .collect()
+}
+
+/*
+*   Locations and version of a chunk as handed out
+*   to clients. Dead servers are left out.
+*/
+pub fn get_chunk_info(uuid: &Uuid) -> ChunkLocations {
+    ChunkLocations {
+        uuid: uuid.to_string(),
+        version: chunk_version(uuid).unwrap_or(0),
+        locations: get_replicas(uuid),
+        primary: lease_holder(uuid),
+        erasure: chunk_erasure(uuid),
+        fragments: fragment_servers(uuid)
+            .into_iter()
+            .map(|holders| {
+                holders
+                    .into_iter()
+                    .find(|server| !heartbeat_manager::is_dead(server))
+            })
+            .collect(),
+        token: None,
+    }
+}
+
+/*
+*   Whether a server is recorded holding the chunk and is not dead.
+*/
+fn is_available(uuid: &Uuid, server: &str) -> bool {
+    chunk_servers(uuid).iter().any(|holder| holder == server) && !heartbeat_manager::is_dead(server)
+}
+
+/////////////////////////////////////////////////////
+// Leases
+
+pub fn lease_holder(uuid: &Uuid) -> Option<String> {
+    let lease = LEASE_MAP.get(uuid)?;
+    let lease = lease.read().unwrap();
+    (lease.expires > Utc::now()).then(|| lease.primary.clone())
+}
+
+/*
+*   Grants the mutation lease of a chunk to one of its replicas, or
+*   extends the lease its primary already holds. Returns the primary.
+*/
+pub fn grant_lease(uuid: &Uuid) -> Option<String> {
+    let expires = Utc::now() + chrono::Duration::seconds(LEASE_DURATION);
+    let primary = match lease_holder(uuid) {
+        Some(primary) if is_available(uuid, &primary) => primary,
+        _ => {
+            let primary = get_replicas(uuid).into_iter().next()?;
+            debug!("Granted lease of chunk {} to {} until {}", uuid, primary, expires);
+            primary
+        }
+    };
+    LEASE_MAP.insert(*uuid, Lease { primary: primary.clone(), expires });
+    Some(primary)
+}
+
+/*
+*   Revokes the lease of a chunk if `server` holds
+*   it, e.g. when its replica is dropped.
+*/
+pub fn revoke_lease(uuid: &Uuid, server: &str) {
+    if lease_holder(uuid).is_some_and(|primary| primary == server) {
+        LEASE_MAP.remove(uuid);
+        debug!("Revoked lease of chunk {} on {}", uuid, server);
+    }
+}
+
+/*
+*   Sets the version of a chunk if it is still at `from`,
+*   when a SetChunkVersion operation is applied or replayed.
+*/
+pub fn set_chunk_version(uuid: &Uuid, from: u64, to: u64) -> Result<(), String> {
+    let info = CHUNK_MAP.get(uuid).ok_or_else(|| format!("Chunk {} not found", uuid))?;
+    let mut info = info.write().unwrap();
+    if info.version != from {
+        return Err(format!("Chunk {} is at version {}, not {}", uuid, info.version, from));
+    }
+    info.version = to;
+    Ok(())
+}
+
+/*
+*   Whether every replica of a chunk on a live server was in a full
+*   report, so that the versions of the replicas are all known.
+*/
+fn all_replicas_reported(info: &ChunkInfo) -> bool {
+    info.replicas.iter()
+        .filter(|replica| !heartbeat_manager::is_dead(&replica.server))
+        .all(|replica| REPORT_MAP.get(&replica.server).is_some())
+}
+
+/*
+*   Compares the chunk versions reported by a chunkserver with the
+*   chunk map and records what the server reported. Once every live
+*   replica of a chunk reported, the version of the master is rolled
+*   back or moved ahead to the newest version of the replicas if
+*   needed (see Versions above), and replicas that stayed behind it
+*   for longer than STALE_GRACE_PERIOD are stale and get deleted.
+*   Returns the stale chunk handles.
+*/
+pub fn check_chunk_versions(server: &str, reported: &[ChunkReport]) -> Vec<Uuid> {
+    let grace = chrono::Duration::seconds(STALE_GRACE_PERIOD);
+    let now = Utc::now();
+    let mut stale = Vec::new();
+    for report in reported {
+        let info = match CHUNK_MAP.get(&report.id) {
+            Some(info) => info,
+            None => continue,
+        };
+        let (version, newest, settled) = {
+            let mut info = info.write().unwrap();
+            let replica = match info.replicas.iter_mut().find(|replica| replica.server == server) {
+                Some(replica) => replica,
+                None => continue,
+            };
+            replica.version = report.version;
+            replica.size = report.size;
+            replica.checksum = Some(report.checksum.clone());
+            if !all_replicas_reported(&info) {
+                continue;
+            }
+            let newest = info.replicas.iter()
+                .filter(|replica| !heartbeat_manager::is_dead(&replica.server))
+                .map(|replica| replica.version)
+                .max()
+                .unwrap_or(report.version);
+            (info.version, newest, now - info.last_modified > grace)
+        };
+
+        let mut target = version;
+        if newest > version {
+            // a replica saw a write the master does not know about
+            warn!("Replica of {} is ahead (version {} > {}), adopting it",
+                  report.id, newest, version);
+            target = newest;
+        } else if newest < version && settled {
+            // the write that bumped the version never reached a
+            // replica : keep the data they have
+            warn!("No replica of {} reached version {}, rolling back to {}",
+                  report.id, version, newest);
+            target = newest;
+        }
+        if target != version {
+            let operation = Operation::SetChunkVersion { uuid: report.id, from: version, to: target };
+            match log_manager::commit(operation, namespace_manager::apply_operation) {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => continue,
+                Err(e) => {
+                    warn!("Failed to log version {} of {}: {}", target, report.id, e);
+                    continue;
+                }
             }
         }
+
+        if report.version < target && settled {
+            warn!("Replica of {} on {} is stale (version {} < {})",
+                  report.id, server, report.version, target);
+            stale.push(report.id);
+        }
+    }
+    for uuid in stale.iter() {
+        drop_replica(uuid, server);
     }
+    stale
+}
 
-    for thing in thingy.clone() {
-        CHUNK_MAP.insert(thing.0, thing.1);
+/*
+*   Rebuilds the chunk list of a server from a full chunk report.
+*   Replicas the chunk map places on the server that it does not
+*   hold are lost, unless they were written less than
+*   STALE_GRACE_PERIOD ago (the client may still be writing them).
+*   Returns the lost chunk handles.
+*/
+pub fn apply_full_report(server: &str, reported: &[ChunkReport]) -> Vec<Uuid> {
+    let grace = chrono::Duration::seconds(STALE_GRACE_PERIOD);
+    let now = Utc::now();
+    let mut held = Vec::new();
+    let mut lost = Vec::new();
+    for (uuid, info) in CHUNK_MAP.to_map() {
+        if !info.replicas.iter().any(|replica| replica.server == server) {
+            continue;
+        }
+        if reported.iter().any(|report| report.id == uuid) || now - info.last_modified < grace {
+            held.push(uuid);
+        } else {
+            lost.push(uuid);
+        }
     }
+    SERVER_MAP.insert(server.to_string(), held);
+    REPORT_MAP.insert(server.to_string(), reported.iter().map(|report| report.id).collect());
+    forget_replicas(server, &lost);
+    check_chunk_versions(server, reported);
+    lost
+}
 
-    save_chunk_map();
-    save_server_map();
+/*
+*   Updates the chunk list of a server with the chunks it stored
+*   and deleted since its previous chunk report.
+*/
+pub fn apply_chunk_delta(server: &str, stored: &[ChunkReport], removed: &[Uuid]) {
+    for report in stored {
+        if chunk_servers(&report.id).iter().any(|holder| holder == server) {
+            add_to_server(server, report.id);
+        }
+    }
+    if let Some(report) = REPORT_MAP.get(&server.to_string()) {
+        let mut report = report.write().unwrap();
+        report.extend(stored.iter().map(|chunk| chunk.id));
+        report.retain(|uuid| !removed.contains(uuid));
+    }
+    check_chunk_versions(server, stored);
+    forget_replicas(server, removed);
+}
 
-    /*
-     *  TODO : Send the update to the actual chunkservers
-     */
-    return thingy;
+/*
+*   (server, chunk handle) of every replica in the chunk map
+*   and of every chunk reported by a chunkserver.
+*/
+pub fn chunk_locations() -> HashSet<(String, Uuid)> {
+    let mut locations: HashSet<(String, Uuid)> = CHUNK_MAP.to_map()
+        .into_iter()
+        .flat_map(|(uuid, info)| info.replicas.into_iter().map(move |replica| (replica.server, uuid)))
+        .collect();
+    for (server, report) in REPORT_MAP.to_map() {
+        locations.extend(report.into_iter().map(|uuid| (server.clone(), uuid)));
+    }
+    locations
 }
 
-pub fn get_chunks(chunk_handles : Vec<Uuid>) -> Vec<(Uuid, String)>{
-    let mut tuples: Vec<(Uuid, String)> = Vec::new();
+/*
+*   Drops the replicas a server no longer holds from the chunk maps.
+*/
+fn forget_replicas(server: &str, chunk_handles: &[Uuid]) {
     for uuid in chunk_handles {
-        if let Some(id) = CHUNK_MAP.get(&uuid) {
-            if let Ok(id_read) = id.read() {
-                tuples.push((uuid, id_read.to_string()));
+        if let Some(info) = CHUNK_MAP.get(uuid) {
+            let mut info = info.write().unwrap();
+            if info.replicas.iter().any(|replica| replica.server == server) {
+                warn!("Replica of {} is no longer on {}", uuid, server);
+                info.replicas.retain(|replica| replica.server != server);
             }
         }
+        revoke_lease(uuid, server);
+        remove_from_server(server, uuid);
+    }
+}
+
+/*
+*   Drops one replica of a chunk and queues it for deletion on its
+*   server. The chunk is forgotten once it has no replica left.
+*/
+pub fn drop_replica(uuid: &Uuid, server: &str) {
+    if let Some(info) = CHUNK_MAP.get(uuid) {
+        let empty = {
+            let mut info = info.write().unwrap();
+            info.replicas.retain(|replica| replica.server != server);
+            info.replicas.is_empty()
+        };
+        if empty {
+            CHUNK_MAP.remove(uuid);
+        }
+    }
+    revoke_lease(uuid, server);
+    remove_from_server(server, uuid);
+    queue_chunk_deletion(server, vec![*uuid]);
+}
+
+/*
+*   Forgets the given chunks and queues them for deletion
+*   on the chunkservers holding them.
+*/
+pub fn schedule_chunk_deletion(chunk_handles: &[Uuid]) {
+    for uuid in chunk_handles {
+        let info = match CHUNK_MAP.remove(uuid) {
+            Some(info) => info.read().unwrap().clone(),
+            None => continue,
+        };
+        LEASE_MAP.remove(uuid);
+        for replica in info.replicas {
+            remove_from_server(&replica.server, uuid);
+            queue_chunk_deletion(&replica.server, vec![*uuid]);
+        }
+    }
+}
+
+/*
+*   Queues chunks for deletion on a chunkserver, also
+*   for chunks the chunk map never recorded.
+*/
+pub fn queue_chunk_deletion(server: &str, uuids: Vec<Uuid>) {
+    match DELETION_QUEUE.get(&server.to_string()) {
+        Some(queue) => queue.write().unwrap().extend(uuids),
+        None => { DELETION_QUEUE.insert(server.to_string(), uuids); }
+    }
+}
+
+/*
+*   Takes the chunks queued for deletion on a chunkserver, they
+*   are sent in the reply to its heartbeat as a DeleteChunks
+*   command, which is sent again until acknowledged.
+*/
+pub fn take_chunk_deletions(server: &str) -> Vec<Uuid> {
+    DELETION_QUEUE.remove(&server.to_string())
+        .map(|uuids| uuids.read().unwrap().clone())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(id: Uuid, version: u64) -> ChunkReport {
+        ChunkReport { id, version, size: 10, checksum: "00".to_string() }
+    }
+
+    #[test]
+    fn test_stale_replica_is_dropped() {
+        chunk_manager_init();
+        heartbeat_manager::heartbeat_manager_init();
+        let log = "/tmp/dfs_chunk_manager.log";
+        let _ = std::fs::remove_file(log);
+        log_manager::log_manager_init(log, 0).unwrap();
+        let servers: Vec<String> = (0..2).map(|i| format!("localhost:820{}", i)).collect();
+        for server in &servers {
+            heartbeat_manager::expect_heartbeat(server);
+        }
+        let uuid = Uuid::new_v4();
+        register_chunks(uuid, &servers);
+        // a second write bumps the chunk to version 2, long ago
+        register_chunks(uuid, &servers);
+        // a write no replica received
+        let abandoned = Uuid::new_v4();
+        register_chunks(abandoned, &servers);
+        register_chunks(abandoned, &servers);
+        for chunk in [uuid, abandoned] {
+            CHUNK_MAP.get(&chunk).unwrap().write().unwrap().last_modified =
+                Utc::now() - chrono::Duration::seconds(STALE_GRACE_PERIOD + 1);
+        }
+
+        // nothing is decided before every replica was in a full report
+        assert!(check_chunk_versions(&servers[1], &[report(uuid, 1)]).is_empty());
+        apply_full_report(&servers[0], &[report(uuid, 2), report(abandoned, 1)]);
+        assert_eq!(get_replicas(&uuid), servers);
+        assert_eq!(chunk_version(&abandoned), Some(2));
+
+        apply_full_report(&servers[1], &[report(uuid, 1), report(abandoned, 1)]);
+        assert_eq!(get_replicas(&uuid), vec![servers[0].clone()]);
+        let info = chunk_info(&uuid).unwrap();
+        assert_eq!(info.replicas[0].checksum.as_deref(), Some("00"));
+        assert!(DELETION_QUEUE.get(&servers[1]).unwrap().read().unwrap().contains(&uuid));
+
+        // the abandoned write is rolled back in the log, its replicas are kept
+        assert_eq!(chunk_version(&abandoned), Some(1));
+        assert_eq!(get_replicas(&abandoned), servers);
+        let records = log_manager::read_log(std::path::Path::new(log)).unwrap();
+        assert!(records.iter().any(|record| matches!(record.operation,
+            Operation::SetChunkVersion { uuid, from: 2, to: 1 } if uuid == abandoned)));
     }
-    return tuples;
-}
\ No newline at end of file
+
+    #[test]
+    fn test_full_report_rebuilds_server_chunks() {
+        chunk_manager_init();
+        let server = "localhost:8210".to_string();
+        let servers = vec![server.clone()];
+        let (held, lost, recent) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        for uuid in [held, lost, recent] {
+            register_chunks(uuid, &servers);
+        }
+        for uuid in [held, lost] {
+            CHUNK_MAP.get(&uuid).unwrap().write().unwrap().last_modified =
+                Utc::now() - chrono::Duration::seconds(STALE_GRACE_PERIOD + 1);
+        }
+
+        assert_eq!(apply_full_report(&server, &[report(held, 1), report(Uuid::new_v4(), 1)]),
+                   vec![lost]);
+        let mut uuids = SERVER_MAP.get(&server).unwrap().read().unwrap().clone();
+        uuids.sort();
+        let mut expected = vec![held, recent];
+        expected.sort();
+        assert_eq!(uuids, expected);
+        assert!(chunk_servers(&lost).is_empty());
+
+        apply_chunk_delta(&server, &[], &[held]);
+        assert!(chunk_servers(&held).is_empty());
+        assert_eq!(*SERVER_MAP.get(&server).unwrap().read().unwrap(), vec![recent]);
+    }
+
+    #[test]
+    fn test_lease_is_extended_for_same_primary() {
+        chunk_manager_init();
+        let servers: Vec<String> = (0..3).map(|i| format!("localhost:822{}", i)).collect();
+        heartbeat_manager::heartbeat_manager_init();
+        for server in &servers {
+            heartbeat_manager::expect_heartbeat(server);
+        }
+        let uuid = Uuid::new_v4();
+        register_chunks(uuid, &servers);
+
+        let primary = grant_lease(&uuid).unwrap();
+        assert_eq!(grant_lease(&uuid), Some(primary.clone()));
+        let info = get_chunk_info(&uuid);
+        assert_eq!(info.locations.len(), 3);
+        assert_eq!(info.primary, Some(primary.clone()));
+
+        // once the primary is gone another replica gets the lease
+        drop_replica(&uuid, &primary);
+        let next = grant_lease(&uuid).unwrap();
+        assert_ne!(next, primary);
+        assert_eq!(get_chunk_info(&uuid).locations.len(), 2);
+    }
+}
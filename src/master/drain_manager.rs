@@ -0,0 +1,227 @@
+/*
+*   Takes chunkservers out of service.
+*
+*   Draining a chunkserver :
+*       1. The server is sent a Drain command, it refuses new chunks
+*          from then on, and no new replicas are placed on it.
+*       2. Its replicas no longer count towards the replication
+*          of their chunks, so the replication manager copies them to
+*          other servers (the draining server can still be the source),
+*          fragments of erasure-coded files are copied the same way.
+*       3. Every DRAIN_CHECK_INTERVAL seconds the chunks that still
+*          lack replicas on other servers are counted. Once there are
+*          none the server is drained : its replicas are dropped and
+*          it is safe to remove.
+*
+*   A drain can be cancelled, the server then takes new chunks again.
+*   Drains are not logged : a chunkserver reports that it is draining
+*   in its heartbeats, which restarts the drain after a master restart.
+*
+*   Data Structures :
+*       1. Drain Map            : Map (Server Address -> Drain)
+*/
+#![allow(unused)]
+
+use crate::chunk_manager;
+use crate::heartbeat_manager;
+use crate::namespace_manager;
+use crate::replication_manager;
+use crate::safe_map::SafeMap;
+use chrono::{DateTime, Utc};
+use lib::shared::master_chunk_utils::Command;
+use serde::Serialize;
+use tokio::time::{sleep, Duration};
+use uuid::Uuid;
+
+static DRAIN_MAP: SafeMap<String, Drain> = SafeMap::new();
+
+const DRAIN_CHECK_INTERVAL: u64 = 10;
+
+#[derive(Debug, Clone)]
+struct Drain {
+    started: DateTime<Utc>,
+    // chunks of the server when the drain started
+    chunk_count: usize,
+    drained: Option<DateTime<Utc>>,
+}
+
+/*
+*   Progress of a drain, as returned by /servers/drain.
+*/
+#[derive(Serialize, Debug, Clone)]
+pub struct DrainView {
+    pub server: String,
+    pub started: DateTime<Utc>,
+    pub chunk_count: usize,
+    pub remaining: usize,
+    pub drained: Option<DateTime<Utc>>,
+    pub safe_to_remove: bool,
+}
+
+pub fn drain_manager_init() {
+    DRAIN_MAP.init();
+}
+
+pub fn is_draining(server: &str) -> bool {
+    DRAIN_MAP.get(&server.to_string()).is_some()
+}
+
+/*
+*   Chunks with a replica on `server` that have fewer live replicas
+*   than their replication on servers not draining. For a chunk of an
+*   erasure-coded file, one of whose fragments has no live holder
+*   that is not draining.
+*/
+pub fn remaining_chunks(server: &str, sets: &[(String, usize, Uuid)]) -> Vec<Uuid> {
+    let kept = |holders: &[String]| holders.iter().filter(|holder| !is_draining(holder)).count();
+    sets.iter()
+        .map(|(_, _, uuid)| *uuid)
+        .filter(|uuid| chunk_manager::chunk_servers(uuid).iter().any(|holder| holder == server))
+        .filter(|uuid| match chunk_manager::chunk_erasure(uuid) {
+            Some(_) => replication_manager::live_fragments(uuid).iter()
+                .any(|holders| kept(holders) == 0),
+            None => kept(&chunk_manager::get_replicas(uuid)) < chunk_manager::chunk_replication(uuid),
+        })
+        .collect()
+}
+
+fn chunk_count(server: &str) -> usize {
+    chunk_manager::SERVER_MAP.get(&server.to_string())
+        .map(|uuids| uuids.read().unwrap().len())
+        .unwrap_or(0)
+}
+
+/*
+*   Starts draining a server, returns its progress.
+*/
+pub fn start_drain(server: &str) -> Result<DrainView, String> {
+    if chunk_manager::SERVER_MAP.get(&server.to_string()).is_none() {
+        return Err(format!("Unknown chunkserver {}", server));
+    }
+    if !is_draining(server) {
+        info!("Draining chunkserver {}", server);
+        DRAIN_MAP.insert(server.to_string(), Drain {
+            started: Utc::now(),
+            chunk_count: chunk_count(server),
+            drained: None,
+        });
+    }
+    heartbeat_manager::send_command(server, Command::Drain { enabled: true });
+    drain_view(server).ok_or_else(|| format!("Chunkserver {} is not draining", server))
+}
+
+/*
+*   Stops draining a server, it takes new chunks again.
+*/
+pub fn cancel_drain(server: &str) -> Result<(), String> {
+    if DRAIN_MAP.remove(&server.to_string()).is_none() {
+        return Err(format!("Chunkserver {} is not draining", server));
+    }
+    info!("Cancelled the drain of chunkserver {}", server);
+    heartbeat_manager::send_command(server, Command::Drain { enabled: false });
+    Ok(())
+}
+
+/*
+*   A server reporting that it is draining, e.g. after a master
+*   restart, is drained again.
+*/
+pub fn resume_drain(server: &str) {
+    if !is_draining(server) {
+        let _ = start_drain(server);
+    }
+}
+
+fn drain_view(server: &str) -> Option<DrainView> {
+    let drain = DRAIN_MAP.get(&server.to_string())?.read().unwrap().clone();
+    let remaining = match drain.drained {
+        Some(_) => 0,
+        None => remaining_chunks(server, &namespace_manager::chunk_sets()).len(),
+    };
+    Some(DrainView {
+        server: server.to_string(),
+        started: drain.started,
+        chunk_count: drain.chunk_count,
+        remaining,
+        drained: drain.drained,
+        safe_to_remove: drain.drained.is_some(),
+    })
+}
+
+pub fn drain_views() -> Vec<DrainView> {
+    let mut views: Vec<DrainView> = DRAIN_MAP.keys()
+        .iter()
+        .filter_map(|server| drain_view(server))
+        .collect();
+    views.sort_by(|a, b| a.server.cmp(&b.server));
+    views
+}
+
+/*
+*   Marks the servers whose chunks all have enough replicas
+*   elsewhere as drained, and drops their replicas.
+*/
+pub fn check_drains() {
+    let sets = namespace_manager::chunk_sets();
+    for server in DRAIN_MAP.keys() {
+        let drain = match DRAIN_MAP.get(&server) {
+            Some(drain) => drain,
+            None => continue,
+        };
+        if drain.read().unwrap().drained.is_some() || !remaining_chunks(&server, &sets).is_empty() {
+            continue;
+        }
+        for (_, _, uuid) in sets.iter() {
+            if chunk_manager::chunk_servers(uuid).contains(&server) {
+                chunk_manager::drop_replica(uuid, &server);
+            }
+        }
+        drain.write().unwrap().drained = Some(Utc::now());
+        info!("Chunkserver {} is drained, it is safe to remove", server);
+    }
+}
+
+/*
+*   Background task checking the progress of the
+*   drains every DRAIN_CHECK_INTERVAL seconds.
+*/
+pub async fn drain_loop() {
+    let interval = Duration::from_secs(DRAIN_CHECK_INTERVAL);
+    loop {
+        sleep(interval).await;
+        check_drains();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drained_once_chunks_are_replicated_elsewhere() {
+        drain_manager_init();
+        chunk_manager::chunk_manager_init();
+        heartbeat_manager::heartbeat_manager_init();
+        let servers: Vec<String> = (0..4).map(|i| format!("localhost:823{}", i)).collect();
+        for server in &servers {
+            heartbeat_manager::expect_heartbeat(server);
+        }
+        let uuid = Uuid::new_v4();
+        chunk_manager::register_chunks(uuid, &servers[..3]);
+        let sets = vec![("/a".to_string(), 0, uuid)];
+
+        start_drain(&servers[0]).unwrap();
+        assert!(is_draining(&servers[0]));
+        assert_eq!(remaining_chunks(&servers[0], &sets), vec![uuid]);
+        // copied elsewhere, the draining replica is kept
+        let repairs = crate::replication_manager::plan_repairs(sets.clone());
+        assert_eq!(repairs.len(), 1);
+        assert_eq!(repairs[0].lost, None);
+
+        chunk_manager::add_replica(uuid, &servers[3], 1).unwrap();
+        assert!(remaining_chunks(&servers[0], &sets).is_empty());
+
+        cancel_drain(&servers[0]).unwrap();
+        assert!(!is_draining(&servers[0]));
+    }
+}
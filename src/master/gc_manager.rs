@@ -0,0 +1,120 @@
+/*
+*   Garbage collection of the chunks no file references.
+*
+*   Deleting a file only removes it from the namespace, its chunks
+*   are reclaimed lazily. Every GC_INTERVAL seconds the chunk copies
+*   known from the chunk map and from the chunkservers' chunk reports
+*   are compared with the replicas of the chunks reachable from the
+*   namespace. A copy that stayed unreachable (its chunk is not in the
+*   namespace, or the server is not one of its replicas) for
+*   GC_GRACE_PERIOD seconds is an orphan : it is dropped from the chunk
+*   map and the chunkserver is told to delete it in the reply to its
+*   next heartbeat.
+*
+*   The grace period covers the copies that are not in the namespace
+*   yet, e.g. a re-replicated copy before it is logged.
+*
+*   Data Structures :
+*       1. Orphan Map           : Map ((Server, Chunk Handle) -> First Seen)
+*/
+#![allow(unused)]
+
+use crate::chunk_manager;
+use crate::namespace_manager;
+use crate::safe_map::SafeMap;
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use tokio::time::{sleep, Duration};
+use uuid::Uuid;
+
+static ORPHAN_MAP: SafeMap<(String, Uuid), DateTime<Utc>> = SafeMap::new();
+
+const GC_INTERVAL: u64 = 30;
+const GC_GRACE_PERIOD: i64 = 120;
+
+pub fn gc_manager_init() {
+    ORPHAN_MAP.init();
+}
+
+/*
+*   Returns the chunk locations that have been unreachable for
+*   longer than GC_GRACE_PERIOD at `now`, and forgets them.
+*/
+pub fn find_orphans(locations: HashSet<(String, Uuid)>, reachable: &HashSet<(String, Uuid)>,
+                    now: DateTime<Utc>) -> Vec<(String, Uuid)> {
+    let unreachable: HashSet<(String, Uuid)> = locations.into_iter()
+        .filter(|location| !reachable.contains(location))
+        .collect();
+    for location in ORPHAN_MAP.keys() {
+        if !unreachable.contains(&location) {
+            ORPHAN_MAP.remove(&location);
+        }
+    }
+
+    let grace = chrono::Duration::seconds(GC_GRACE_PERIOD);
+    let mut orphans = Vec::new();
+    for location in unreachable {
+        match ORPHAN_MAP.get(&location) {
+            Some(first_seen) if now - *first_seen.read().unwrap() > grace => {
+                ORPHAN_MAP.remove(&location);
+                orphans.push(location);
+            }
+            Some(_) => {}
+            None => { ORPHAN_MAP.insert(location, now); }
+        }
+    }
+    orphans
+}
+
+/*
+*   One garbage collection pass, returns the number of chunks collected.
+*/
+pub fn collect_garbage() -> usize {
+    let reachable: HashSet<(String, Uuid)> = namespace_manager::chunk_sets()
+        .into_iter()
+        .flat_map(|(_, _, uuid)| chunk_manager::chunk_servers(&uuid)
+            .into_iter()
+            .map(move |server| (server, uuid)))
+        .collect();
+    let orphans = find_orphans(chunk_manager::chunk_locations(), &reachable, Utc::now());
+    for (server, uuid) in orphans.iter() {
+        chunk_manager::drop_replica(uuid, server);
+    }
+    orphans.len()
+}
+
+/*
+*   Background task collecting the orphaned chunks
+*   every GC_INTERVAL seconds.
+*/
+pub async fn gc_loop() {
+    let interval = Duration::from_secs(GC_INTERVAL);
+    loop {
+        sleep(interval).await;
+
+        let collected = collect_garbage();
+        if collected > 0 {
+            info!("Collected {} orphaned chunks", collected);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_orphans_are_collected_after_grace_period() {
+        gc_manager_init();
+        let server = "localhost:8160".to_string();
+        let (referenced, orphan) = (Uuid::new_v4(), Uuid::new_v4());
+        let locations: HashSet<(String, Uuid)> =
+            [(server.clone(), referenced), (server.clone(), orphan)].into_iter().collect();
+        let reachable: HashSet<(String, Uuid)> = [(server.clone(), referenced)].into_iter().collect();
+        let now = Utc::now();
+
+        assert!(find_orphans(locations.clone(), &reachable, now).is_empty());
+        let later = now + chrono::Duration::seconds(GC_GRACE_PERIOD + 1);
+        assert_eq!(find_orphans(locations, &reachable, later), vec![(server, orphan)]);
+    }
+}
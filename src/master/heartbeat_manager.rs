@@ -5,36 +5,401 @@
 *
 *
 *
-*   Data Structures : 
-*       1. Server Status        : Map (Server Id -> Server Status)
+*   Data Structures :
+*       1. Server Status        : Map (Server Address -> Server Status)
+*       2. Server Status {
+*               Metadata        : Last heartbeat sent by the server
+*               Last Heartbeat  : DateTime the heartbeat was received
+*               State           : Alive | Suspect | Dead
+*           }
+*       3. Command Map          : Map (Server Address -> List<Pending Command>)
 *
-*   Functions : 
+*   Functions :
 *       1. Update Server Status
-*       2. Receive heartbeats 
+*       2. Receive heartbeats
+*
+*   Liveness :
+*       A server that missed SUSPECT_AFTER heartbeats (one every
+*       HEARTBEAT_INTERVAL seconds) is suspect : no new chunks are
+*       placed on it. After DEAD_AFTER missed heartbeats it is dead :
+*       its replicas are no longer handed out either. Both can be set
+*       with the DFS_SUSPECT_AFTER / DFS_DEAD_AFTER environment variables.
+*       The state is derived from the time of the last heartbeat, the
+*       liveness loop only logs the transitions.
+*
+*   Commands :
+*       The reply to a heartbeat carries the commands queued for the
+*       server (delete chunks, replicate a chunk, report chunks, register
+*       again, drain). The server acknowledges them in its next heartbeat,
+*       commands not acknowledged within COMMAND_TIMEOUT seconds are sent
+*       again, so executing a command twice has to be harmless.
 */
 #![allow(unused)]
 
 use crate::safe_map::SafeMap;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::SystemTime;
 use std::time::Duration;
+use chrono::{DateTime, Utc};
 use sysinfo::{Disks, System};
 use reqwest::{Error, Client};
+use rocket::http::Status;
 use rocket::serde::json::Json;
-pub use lib::shared::master_chunk_utils::{Disk, Metadata, HEARTBEAT_INTERVAL};
+use serde::{Deserialize, Serialize};
+pub use lib::shared::master_chunk_utils::{Command, CommandAck, Disk, HeartbeatResponse, Metadata,
+                                          HEARTBEAT_INTERVAL};
+use crate::chunk_manager;
 use crate::chunk_manager::SERVER_MAP;
+use crate::topology_manager;
+use crate::drain_manager;
+
+static SERVER_STATUS_MAP: SafeMap<String, ServerStatus> = SafeMap::new();
+static COMMAND_MAP: SafeMap<String, Vec<PendingCommand>> = SafeMap::new();
+static NEXT_COMMAND_ID: AtomicU64 = AtomicU64::new(1);
+
+const DEFAULT_SUSPECT_AFTER: u64 = 3;
+const DEFAULT_DEAD_AFTER: u64 = 10;
+const SUSPECT_AFTER_ENV: &str = "DFS_SUSPECT_AFTER";
+const DEAD_AFTER_ENV: &str = "DFS_DEAD_AFTER";
+const COMMAND_TIMEOUT: i64 = 30;
+
+static SUSPECT_AFTER: AtomicU64 = AtomicU64::new(DEFAULT_SUSPECT_AFTER);
+static DEAD_AFTER: AtomicU64 = AtomicU64::new(DEFAULT_DEAD_AFTER);
 
-#[path = "../shared/master_chunk_utils"]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerState {
+    Alive,
+    Suspect,
+    Dead,
+}
 
-// static SERVER_STATUS_MAP: SafeMap<u16, Metadata> = SafeMap::new();
+#[derive(Debug, Clone)]
+struct ServerStatus {
+    // None for servers registered without a heartbeat (tests)
+    metadata: Option<Metadata>,
+    last_heartbeat: DateTime<Utc>,
+    state: ServerState,
+}
 
-// pub fn heartbeat_manager_init() {
-//     SERVER_STATUS_MAP.init();
-// }
+#[derive(Debug, Clone)]
+struct PendingCommand {
+    id: u64,
+    command: Command,
+    // when it was last sent, None if not yet
+    sent: Option<DateTime<Utc>>,
+}
 
-pub async fn receive_heartbeat(metadata: Json<Metadata>) {
+/*
+*   Liveness of one chunkserver, as returned by /servers.
+*/
+#[derive(Serialize, Debug)]
+pub struct ServerView {
+    pub address: String,
+    pub state: ServerState,
+    pub last_heartbeat: DateTime<Utc>,
+    pub missed_heartbeats: u64,
+    pub chunk_count: usize,
+    pub pending_commands: usize,
+    pub metadata: Option<Metadata>,
+}
+
+pub fn heartbeat_manager_init() {
+    SERVER_STATUS_MAP.init();
+    COMMAND_MAP.init();
+    let suspect_after = env_threshold(SUSPECT_AFTER_ENV, DEFAULT_SUSPECT_AFTER);
+    let dead_after = env_threshold(DEAD_AFTER_ENV, DEFAULT_DEAD_AFTER);
+    if dead_after <= suspect_after {
+        panic!("{} must be greater than {}", DEAD_AFTER_ENV, SUSPECT_AFTER_ENV);
+    }
+    SUSPECT_AFTER.store(suspect_after, Ordering::Relaxed);
+    DEAD_AFTER.store(dead_after, Ordering::Relaxed);
+}
+
+fn env_threshold(name: &str, default: u64) -> u64 {
+    match std::env::var(name) {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(value) if value > 0 => value,
+            _ => panic!("{} must be a positive number of heartbeats", name),
+        },
+        Err(_) => default,
+    }
+}
+
+/*
+*   Registers a server without a chunk report.
+*/
+#[cfg(test)]
+pub fn expect_heartbeat(server: &str) {
+    if SERVER_STATUS_MAP.get(&server.to_string()).is_none() {
+        SERVER_STATUS_MAP.insert(server.to_string(), ServerStatus {
+            metadata: None,
+            last_heartbeat: Utc::now(),
+            state: ServerState::Alive,
+        });
+    }
+}
+
+/*
+*   Queues a command for a server, sent in the reply to its next
+*   heartbeat. Returns the command id, the one of the same command
+*   if it is already waiting to be sent.
+*/
+pub fn send_command(server: &str, command: Command) -> u64 {
+    let server = server.to_string();
+    let commands = match COMMAND_MAP.get(&server) {
+        Some(commands) => commands,
+        None => {
+            COMMAND_MAP.insert(server.clone(), Vec::new());
+            COMMAND_MAP.get(&server).unwrap()
+        }
+    };
+    let mut commands = commands.write().unwrap();
+    if let Some(pending) = commands.iter().find(|pending| pending.sent.is_none() && pending.command == command) {
+        return pending.id;
+    }
+    let id = NEXT_COMMAND_ID.fetch_add(1, Ordering::Relaxed);
+    debug!("Queued command {} for {}: {:?}", id, server, command);
+    commands.push(PendingCommand { id, command, sent: None });
+    id
+}
+
+/*
+*   Whether the command was queued for the server and not acknowledged yet.
+*/
+pub fn is_pending(server: &str, command: &Command) -> bool {
+    COMMAND_MAP.get(&server.to_string())
+        .map(|commands| commands.read().unwrap().iter().any(|pending| pending.command == *command))
+        .unwrap_or(false)
+}
+
+/*
+*   Commands to send to a server at `now` : the new ones and
+*   those left unacknowledged for COMMAND_TIMEOUT seconds.
+*/
+fn take_commands(server: &str, now: DateTime<Utc>) -> Vec<(u64, Command)> {
+    let timeout = chrono::Duration::seconds(COMMAND_TIMEOUT);
+    let commands = match COMMAND_MAP.get(&server.to_string()) {
+        Some(commands) => commands,
+        None => return Vec::new(),
+    };
+    let mut commands = commands.write().unwrap();
+    commands.iter_mut()
+        .filter(|pending| pending.sent.is_none_or(|sent| now - sent > timeout))
+        .map(|pending| {
+            pending.sent = Some(now);
+            (pending.id, pending.command.clone())
+        })
+        .collect()
+}
+
+fn acknowledge(server: &str, acks: &[CommandAck]) {
+    let commands = match COMMAND_MAP.get(&server.to_string()) {
+        Some(commands) => commands,
+        None => return,
+    };
+    let mut commands = commands.write().unwrap();
+    for ack in acks {
+        if let Some(error) = &ack.error {
+            warn!("Command {} failed on {}: {}", ack.id, server, error);
+        }
+        commands.retain(|pending| pending.id != ack.id);
+    }
+}
+
+/*
+*   A server registers (again) with a full chunk report, from which
+*   its chunk list is rebuilt. Later heartbeats only report changes.
+*   A server the master does not know (e.g. after a restart) is told
+*   to register again, one coming back from the dead to report all
+*   its chunks. The reply carries the commands queued for the server.
+*   Version changes found in the reports are logged, so this runs
+*   on the blocking thread pool.
+*/
+pub fn receive_heartbeat(metadata: Json<Metadata>) -> Result<Json<HeartbeatResponse>, Status> {
     let mut metadata = metadata.into_inner();
-    // SERVER_STATUS_MAP.insert(metadata.chunkserver_id, metadata);
     let addr = format!("{}:{}", metadata.ip, metadata.chunkserver_id);
-    SERVER_MAP.insert(addr, Vec::new());
+    acknowledge(&addr, &metadata.acks);
+    if metadata.full_report {
+        let lost = chunk_manager::apply_full_report(&addr, &metadata.chunks);
+        info!("Chunkserver {} reported {} chunks, {} replicas lost",
+              addr, metadata.chunks.len(), lost.len());
+    } else if SERVER_STATUS_MAP.get(&addr).is_some() {
+        if is_dead(&addr) {
+            send_command(&addr, Command::ReportChunks);
+        }
+        chunk_manager::apply_chunk_delta(&addr, &metadata.chunks, &metadata.removed_chunks);
+    } else {
+        send_command(&addr, Command::Reregister);
+        return Ok(Json(HeartbeatResponse { commands: take_commands(&addr, Utc::now()) }));
+    }
+
+    topology_manager::update_topology(&addr, &metadata.topology);
+    // a drain the master does not know of, e.g. after a restart
+    if metadata.draining && !is_pending(&addr, &Command::Drain { enabled: false }) {
+        drain_manager::resume_drain(&addr);
+    }
+    // the chunks of the server are in the chunk maps
+    metadata.chunks.clear();
+    metadata.removed_chunks.clear();
+    metadata.acks.clear();
+    let previous = SERVER_STATUS_MAP.insert(addr.clone(), ServerStatus {
+        metadata: Some(metadata),
+        last_heartbeat: Utc::now(),
+        state: ServerState::Alive,
+    });
+    if let Some(previous) = previous {
+        let previous = previous.read().unwrap().state;
+        if previous != ServerState::Alive {
+            info!("Chunkserver {} is alive again (was {:?})", addr, previous);
+        }
+    }
+
+    let deletions = chunk_manager::take_chunk_deletions(&addr);
+    if !deletions.is_empty() {
+        send_command(&addr, Command::DeleteChunks { chunks: deletions });
+    }
+    Ok(Json(HeartbeatResponse { commands: take_commands(&addr, Utc::now()) }))
+}
+
+/*
+*   Heartbeats missed since the last one. The next heartbeat only
+*   counts as missed once a whole interval passed after it was due,
+*   so that a heartbeat arriving a little late is not.
+*/
+fn missed_heartbeats(last_heartbeat: DateTime<Utc>) -> u64 {
+    let elapsed = (Utc::now() - last_heartbeat).num_seconds().max(0) as u64;
+    (elapsed / HEARTBEAT_INTERVAL).saturating_sub(1)
+}
+
+fn state_after(missed: u64) -> ServerState {
+    if missed >= DEAD_AFTER.load(Ordering::Relaxed) {
+        ServerState::Dead
+    } else if missed >= SUSPECT_AFTER.load(Ordering::Relaxed) {
+        ServerState::Suspect
+    } else {
+        ServerState::Alive
+    }
+}
+
+/*
+*   Current state of a server. Servers that never sent
+*   a heartbeat are dead.
+*/
+pub fn server_state(server: &str) -> ServerState {
+    match SERVER_STATUS_MAP.get(&server.to_string()) {
+        Some(status) => state_after(missed_heartbeats(status.read().unwrap().last_heartbeat)),
+        None => ServerState::Dead,
+    }
+}
+
+pub fn is_dead(server: &str) -> bool {
+    server_state(server) == ServerState::Dead
+}
+
+/*
+*   Last heartbeat metadata of a server.
+*/
+pub fn server_metadata(server: &str) -> Option<Metadata> {
+    SERVER_STATUS_MAP.get(&server.to_string())
+        .and_then(|status| status.read().unwrap().metadata.clone())
+}
+
+/*
+*   Servers new chunks can be placed on.
+*/
+pub fn alive_servers() -> Vec<String> {
+    SERVER_MAP.keys()
+        .into_iter()
+        .filter(|server| server_state(server) == ServerState::Alive)
+        .collect()
+}
+
+pub fn server_views() -> Vec<ServerView> {
+    let mut views: Vec<ServerView> = SERVER_STATUS_MAP.to_map()
+        .into_iter()
+        .map(|(address, status)| {
+            let missed = missed_heartbeats(status.last_heartbeat);
+            ServerView {
+                chunk_count: SERVER_MAP.get(&address)
+                    .map(|uuids| uuids.read().unwrap().len())
+                    .unwrap_or(0),
+                pending_commands: COMMAND_MAP.get(&address)
+                    .map(|commands| commands.read().unwrap().len())
+                    .unwrap_or(0),
+                address,
+                state: state_after(missed),
+                last_heartbeat: status.last_heartbeat,
+                missed_heartbeats: missed,
+                metadata: status.metadata,
+            }
+        })
+        .collect();
+    views.sort_by(|a, b| a.address.cmp(&b.address));
+    views
+}
+
+/*
+*   Background task recording the state changes of the
+*   chunkservers every HEARTBEAT_INTERVAL seconds.
+*/
+pub async fn liveness_loop() {
+    let interval = tokio::time::Duration::from_secs(HEARTBEAT_INTERVAL);
+    loop {
+        tokio::time::sleep(interval).await;
+
+        for server in SERVER_STATUS_MAP.keys() {
+            let status = match SERVER_STATUS_MAP.get(&server) {
+                Some(status) => status,
+                None => continue,
+            };
+            let mut status = status.write().unwrap();
+            let state = state_after(missed_heartbeats(status.last_heartbeat));
+            if state != status.state {
+                warn!("Chunkserver {} is {:?} (last heartbeat {})",
+                      server, state, status.last_heartbeat);
+                status.state = state;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_state_after_missed_heartbeats() {
+        heartbeat_manager_init();
+        let server = "localhost:8180".to_string();
+        assert_eq!(server_state(&server), ServerState::Dead);
+
+        expect_heartbeat(&server);
+        assert_eq!(server_state(&server), ServerState::Alive);
+
+        for (missed, state) in [(DEFAULT_SUSPECT_AFTER, ServerState::Suspect),
+                                (DEFAULT_DEAD_AFTER, ServerState::Dead)] {
+            SERVER_STATUS_MAP.get(&server).unwrap().write().unwrap().last_heartbeat =
+                Utc::now() - chrono::Duration::seconds(((missed + 1) * HEARTBEAT_INTERVAL) as i64);
+            assert_eq!(server_state(&server), state);
+        }
+    }
+
+    #[test]
+    fn test_commands_are_sent_again_until_acknowledged() {
+        heartbeat_manager_init();
+        let server = "localhost:8181";
+        let now = Utc::now();
+        let first = send_command(server, Command::ReportChunks);
+        assert_eq!(send_command(server, Command::ReportChunks), first);
+        let second = send_command(server, Command::Drain { enabled: true });
+
+        assert_eq!(take_commands(server, now).len(), 2);
+        assert!(take_commands(server, now).is_empty());
+
+        acknowledge(server, &[CommandAck { id: first, error: None }]);
+        let later = now + chrono::Duration::seconds(COMMAND_TIMEOUT + 1);
+        assert_eq!(take_commands(server, later), vec![(second, Command::Drain { enabled: true })]);
+    }
 }
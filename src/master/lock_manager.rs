@@ -0,0 +1,242 @@
+/*
+*   Per-filepath read-write locks for the namespace.
+*
+*   Example :
+*            To modify /a/b/c,
+*            acquire read locks on /, /a, /a/b,
+*            write lock on /a/b/c
+*
+*   A write lock on a directory therefore excludes every operation
+*   on anything below it, while operations on different files of a
+*   directory only share read locks on it and can run concurrently.
+*
+*   Locks of one operation are always acquired in sorted path order,
+*   which is the same for every operation, so two operations can never
+*   wait on each other (no deadlock). Parents sort before their
+*   children, e.g. / < /a < /a/b < /a/b/c.
+*
+*   A writer waiting for a path keeps new readers out of it, so a
+*   steady stream of readers cannot starve it.
+*
+*   Waiting for a lock blocks the thread. Async code (the routes and
+*   the background tasks) runs namespace operations through `blocking`,
+*   on the blocking thread pool, so a contended path never stalls a
+*   worker of the runtime.
+*
+*   Data Structures :
+*       1. Lock Table       : Map (Path -> Lock State)
+*       2. Lock State {
+*               Readers         : usize
+*               Writer          : bool
+*               Waiting Writers : usize
+*           }
+*/
+
+use std::collections::BTreeMap;
+use std::sync::{Condvar, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Default)]
+struct LockState {
+    readers: usize,
+    writer: bool,
+    waiting_writers: usize,
+}
+
+static LOCK_TABLE: Mutex<BTreeMap<String, LockState>> = Mutex::new(BTreeMap::new());
+static LOCK_RELEASED: Condvar = Condvar::new();
+
+/// Locks held by one namespace operation, released when dropped.
+#[derive(Debug)]
+pub struct PathLockGuard {
+    held: Vec<(String, LockMode)>,
+}
+
+impl Drop for PathLockGuard {
+    fn drop(&mut self) {
+        let mut table = LOCK_TABLE.lock().unwrap();
+        for (path, mode) in self.held.drain(..) {
+            if let Some(state) = table.get_mut(&path) {
+                match mode {
+                    LockMode::Read => state.readers -= 1,
+                    LockMode::Write => state.writer = false,
+                }
+                if state.readers == 0 && !state.writer && state.waiting_writers == 0 {
+                    table.remove(&path);
+                }
+            }
+        }
+        LOCK_RELEASED.notify_all();
+    }
+}
+
+/*
+*   Returns the ancestors of a path, root first.
+*   Example : /a/b/c -> [/, /a, /a/b]
+*/
+pub fn ancestors(path: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    if path == "/" {
+        return result;
+    }
+    result.push("/".to_string());
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+    for i in 1..components.len() {
+        result.push(format!("/{}", components[..i].join("/")));
+    }
+    result
+}
+
+/*
+*   Acquires read locks on `read_paths` and write locks on
+*   `write_paths`, plus read locks on all their ancestors.
+*   Blocks until every lock is held.
+*/
+pub fn lock_paths(read_paths: &[&str], write_paths: &[&str]) -> PathLockGuard {
+    let mut wanted: BTreeMap<String, LockMode> = BTreeMap::new();
+    for path in read_paths.iter().chain(write_paths.iter()) {
+        for ancestor in ancestors(path) {
+            wanted.entry(ancestor).or_insert(LockMode::Read);
+        }
+    }
+    for path in read_paths {
+        wanted.entry(path.to_string()).or_insert(LockMode::Read);
+    }
+    for path in write_paths {
+        wanted.insert(path.to_string(), LockMode::Write);
+    }
+
+    let mut guard = PathLockGuard { held: Vec::new() };
+    let mut table = LOCK_TABLE.lock().unwrap();
+    for (path, mode) in wanted {
+        let mut waiting = false;
+        loop {
+            let state = table.entry(path.clone()).or_default();
+            let free = match mode {
+                LockMode::Read => !state.writer && state.waiting_writers == 0,
+                LockMode::Write => !state.writer && state.readers == 0,
+            };
+            if free {
+                match mode {
+                    LockMode::Read => state.readers += 1,
+                    LockMode::Write => state.writer = true,
+                }
+                if waiting {
+                    state.waiting_writers -= 1;
+                }
+                break;
+            }
+            if mode == LockMode::Write && !waiting {
+                state.waiting_writers += 1;
+                waiting = true;
+            }
+            table = LOCK_RELEASED.wait(table).unwrap();
+        }
+        guard.held.push((path, mode));
+    }
+    guard
+}
+
+/*
+*   Runs a namespace operation on the blocking thread pool. It may
+*   wait for path locks and fsync the operation log, which must not
+*   happen on a worker of the async runtime.
+*/
+pub async fn blocking<T, F>(operation: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(operation).await {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Read locks on the path and its ancestors.
+pub fn read_lock(path: &str) -> PathLockGuard {
+    lock_paths(&[path], &[])
+}
+
+/// Write lock on the path and read locks on its ancestors.
+pub fn write_lock(path: &str) -> PathLockGuard {
+    lock_paths(&[], &[path])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    const WAIT: Duration = Duration::from_millis(200);
+
+    /// Takes a write lock on `path` in another thread and reports when it got it.
+    fn spawn_writer(path: &'static str) -> mpsc::Receiver<()> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _guard = write_lock(path);
+            tx.send(()).unwrap();
+        });
+        rx
+    }
+
+    #[test]
+    fn test_ancestors() {
+        assert_eq!(ancestors("/"), Vec::<String>::new());
+        assert_eq!(ancestors("/a"), vec!["/"]);
+        assert_eq!(ancestors("/a/b/c"), vec!["/", "/a", "/a/b"]);
+    }
+
+    #[test]
+    fn test_write_lock_excludes_same_path() {
+        let guard = write_lock("/test_same/file");
+        let acquired = spawn_writer("/test_same/file");
+        assert!(acquired.recv_timeout(WAIT).is_err());
+
+        drop(guard);
+        assert!(acquired.recv_timeout(WAIT).is_ok());
+    }
+
+    #[test]
+    fn test_directory_write_lock_excludes_children() {
+        let guard = write_lock("/test_dir");
+        let acquired = spawn_writer("/test_dir/file");
+        assert!(acquired.recv_timeout(WAIT).is_err());
+
+        drop(guard);
+        assert!(acquired.recv_timeout(WAIT).is_ok());
+    }
+
+    #[test]
+    fn test_waiting_writer_blocks_new_readers() {
+        let reader = read_lock("/test_fair/file");
+        let writer = spawn_writer("/test_fair/file");
+        assert!(writer.recv_timeout(WAIT).is_err());
+
+        // a reader arriving after the writer waits for it
+        let (tx, late_reader) = mpsc::channel();
+        thread::spawn(move || {
+            let _guard = read_lock("/test_fair/file");
+            tx.send(()).unwrap();
+        });
+        assert!(late_reader.recv_timeout(WAIT).is_err());
+
+        drop(reader);
+        assert!(writer.recv_timeout(WAIT).is_ok());
+        assert!(late_reader.recv_timeout(WAIT).is_ok());
+    }
+
+    #[test]
+    fn test_siblings_share_parent() {
+        let _guard = write_lock("/test_siblings/a");
+        let acquired = spawn_writer("/test_siblings/b");
+        assert!(acquired.recv_timeout(WAIT).is_ok());
+    }
+}
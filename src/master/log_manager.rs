@@ -1,18 +1,283 @@
 /*
 *   The operation logger.
-*   The purpose is to log each operation before doing it 
+*   The purpose is to log each operation before doing it
 *   so that if an operation fails, we could redo the opertaions
 *   in logs.
 *
-*   Purpose : 
-*       1. Crash Recovery 
+*   Purpose :
+*       1. Crash Recovery
 *       2. Replication to other masters (eventually)
 *
 *
-*   Functions : 
+*   Functions :
 *       1. Log Operation Write ( Synchronous Call From Namespace Manager)
 *       2. Recover
 *       3. Create Checkpoint
 *       4. Log Operation Read
-*           
-*/
\ No newline at end of file
+*
+*   Log Format :
+*       One JSON encoded record per line, each record is
+*       { lsn : u64, operation : Operation }
+*       Records are appended and fsynced before the mutation
+*       is applied to the in-memory namespace.
+*
+*       If a record cannot be written or fsynced, what reached the
+*       disk is unknown and the log can no longer be trusted, so the
+*       master stops and recovers from the log when restarted.
+*       Callers run on the blocking thread pool, never on an async
+*       worker, since the log lock is held across the fsync.
+*
+*       Once a checkpoint covering lsn N is durable, the records
+*       up to N are dropped from the log.
+*/
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use lib::shared::master_client_utils::Metadata;
+
+/// A namespace mutation as it is recorded in the operation log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Operation {
+    FileCreate { path: String, metadata: Metadata },
+    FileDelete { path: String },
+    // (chunk handle, servers of its replicas) of each chunk index
+    FileWrite { path: String, size: u64, chunk_size: u64, chunks: Vec<(Uuid, Vec<String>)> },
+    DirectoryCreate { path: String, metadata: Metadata },
+    DirectoryDelete { path: String },
+    FileRename { from: String, to: String },
+    DirectoryRename { from: String, to: String },
+    ChangeMode { path: String, mode: i32 },
+    ChangeOwner { path: String, owner: String, group: String },
+    // `server` copied chunk `index` from another replica and replaces the replica on `old`
+    ReplaceReplica { path: String, index: usize, old: Option<String>, server: String, version: u64 },
+    // replication of a file, or of a directory and everything below it
+    SetReplication { path: String, replication: usize },
+    // the replica of chunk `index` on `server` is beyond the replication of the file
+    RemoveReplica { path: String, index: usize, server: String },
+    // `server` holds fragment `fragment` of chunk `index`, rebuilt or copied, in place of `old`
+    ReplaceFragment { path: String, index: usize, fragment: usize, old: Option<String>,
+                      server: String, version: u64 },
+    // the replicas of chunk `uuid` all reported : its version moves from `from` to `to`
+    SetChunkVersion { uuid: Uuid, from: u64, to: u64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogRecord {
+    pub lsn: u64,
+    pub operation: Operation,
+}
+
+struct OperationLog {
+    path: PathBuf,
+    file: File,
+    next_lsn: u64,
+}
+
+static OPERATION_LOG: Mutex<Option<OperationLog>> = Mutex::new(None);
+
+/*
+*   Opens (or creates) the operation log and returns every record
+*   found in it so that the caller can replay them. New records are
+*   numbered after both the log and the checkpoint at `checkpoint_lsn`.
+*/
+pub fn log_manager_init(path: &str, checkpoint_lsn: u64) -> Result<Vec<LogRecord>, Error> {
+    let (records, valid_len) = scan_log(Path::new(path))?;
+    let last_lsn = records.last().map(|record| record.lsn).unwrap_or(0);
+    let next_lsn = last_lsn.max(checkpoint_lsn) + 1;
+
+    let file = OpenOptions::new()
+        .append(true).create(true)
+        .open(path)?;
+    // drop a torn tail so that new records start on a fresh line
+    if file.metadata()?.len() > valid_len {
+        warn!("Truncating operation log to {} bytes", valid_len);
+        file.set_len(valid_len)?;
+        file.sync_all()?;
+    }
+
+    let mut guard = OPERATION_LOG.lock().unwrap();
+    *guard = Some(OperationLog { path: PathBuf::from(path), file, next_lsn });
+    Ok(records)
+}
+
+/*
+*   Opens the operation log and redoes, in log order, every logged
+*   operation that is not already part of the checkpoint at
+*   `checkpoint_lsn`. Returns the number of replayed operations.
+*/
+pub fn recover(
+    path: &str,
+    checkpoint_lsn: u64,
+    apply: impl Fn(&Operation),
+) -> Result<usize, Error> {
+    let records = log_manager_init(path, checkpoint_lsn)?;
+    let mut count = 0;
+    for record in records.iter().filter(|record| record.lsn > checkpoint_lsn) {
+        debug!("Replaying operation {}: {:?}", record.lsn, record.operation);
+        apply(&record.operation);
+        count += 1;
+    }
+    Ok(count)
+}
+
+/*
+*   Reads all the complete records of a log file.
+*/
+pub fn read_log(path: &Path) -> Result<Vec<LogRecord>, Error> {
+    scan_log(path).map(|(records, _)| records)
+}
+
+/*
+*   Returns the complete records of a log file together with the
+*   length in bytes they occupy. A torn last line (crash in the
+*   middle of a write) is ignored, since its operation was never
+*   acknowledged.
+*/
+fn scan_log(path: &Path) -> Result<(Vec<LogRecord>, u64), Error> {
+    if !path.exists() {
+        return Ok((Vec::new(), 0));
+    }
+
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    let mut valid_len = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 || !line.ends_with('\n') {
+            break;
+        }
+        if !line.trim().is_empty() {
+            match serde_json::from_str::<LogRecord>(&line) {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    warn!("Ignoring incomplete log record: {}", e);
+                    break;
+                }
+            }
+        }
+        valid_len += read as u64;
+    }
+    Ok((records, valid_len))
+}
+
+/*
+*   Appends the operation to the log, fsyncs it and then applies it
+*   while still holding the log lock, so the order of the records in
+*   the log is the order in which they were applied in memory.
+*/
+pub fn commit<T>(
+    operation: Operation,
+    apply: impl FnOnce(&Operation) -> T,
+) -> Result<T, Error> {
+    let mut guard = OPERATION_LOG.lock()
+        .map_err(|_| Error::other("Failed to acquire lock on operation log"))?;
+    let log = guard.as_mut()
+        .ok_or_else(|| Error::new(ErrorKind::NotConnected, "Operation log not initialized"))?;
+
+    let record = LogRecord { lsn: log.next_lsn, operation };
+    let mut data = serde_json::to_vec(&record)?;
+    data.push(b'\n');
+    if let Err(e) = log.file.write_all(&data).and_then(|()| log.file.sync_data()) {
+        error!("Failed to log operation {}, stopping: {}", record.lsn, e);
+        std::process::exit(1);
+    }
+    log.next_lsn += 1;
+
+    debug!("Logged operation {}: {:?}", record.lsn, record.operation);
+    Ok(apply(&record.operation))
+}
+
+/*
+*   Runs `snapshot` with the lsn of the last applied operation while
+*   no operation can be committed, so that the snapshot is exactly the
+*   state after that operation.
+*/
+pub fn checkpoint<T>(snapshot: impl FnOnce(u64) -> T) -> Result<T, Error> {
+    let guard = OPERATION_LOG.lock()
+        .map_err(|_| Error::other("Failed to acquire lock on operation log"))?;
+    let log = guard.as_ref()
+        .ok_or_else(|| Error::new(ErrorKind::NotConnected, "Operation log not initialized"))?;
+    Ok(snapshot(log.next_lsn - 1))
+}
+
+/*
+*   Drops every record up to `lsn` from the log. The remaining records
+*   are written to a temporary file which then replaces the log, so a
+*   crash leaves either the old or the new log. Returns the number of
+*   dropped records. The directory is fsynced after the rename so
+*   that the new log survives a crash; the master stops if that fails.
+*/
+pub fn truncate(lsn: u64) -> Result<usize, Error> {
+    let mut guard = OPERATION_LOG.lock()
+        .map_err(|_| Error::other("Failed to acquire lock on operation log"))?;
+    let log = guard.as_mut()
+        .ok_or_else(|| Error::new(ErrorKind::NotConnected, "Operation log not initialized"))?;
+
+    let records = read_log(&log.path)?;
+    let (dropped, kept): (Vec<_>, Vec<_>) = records.into_iter()
+        .partition(|record| record.lsn <= lsn);
+    if dropped.is_empty() {
+        return Ok(0);
+    }
+
+    let tmp_path = log.path.with_extension("tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    for record in kept.iter() {
+        let mut data = serde_json::to_vec(record)?;
+        data.push(b'\n');
+        tmp.write_all(&data)?;
+    }
+    tmp.sync_all()?;
+    std::fs::rename(&tmp_path, &log.path)?;
+
+    // new records must not go to the old, now unlinked, log
+    let dir = log.path.parent().filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let reopened = File::open(dir).and_then(|dir| dir.sync_all())
+        .and_then(|()| OpenOptions::new().append(true).open(&log.path));
+    match reopened {
+        Ok(file) => log.file = file,
+        Err(e) => {
+            error!("Failed to switch to the truncated log, stopping: {}", e);
+            std::process::exit(1);
+        }
+    }
+    Ok(dropped.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_LOG_DIR: &str = "/tmp/dfs_log_manager";
+
+    fn pre_test(name: &str) -> String {
+        std::fs::create_dir_all(TEST_LOG_DIR).unwrap();
+        let path = format!("{}/{}", TEST_LOG_DIR, name);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_read_log_skips_torn_record() {
+        let path = pre_test("torn.log");
+        let record = LogRecord {
+            lsn: 1,
+            operation: Operation::FileDelete { path: "/a.txt".to_string() },
+        };
+        let mut data = serde_json::to_string(&record).unwrap();
+        data.push('\n');
+        data.push_str("{\"lsn\":2,\"operation\":{\"FileDel");
+        std::fs::write(&path, data).unwrap();
+
+        let records = read_log(Path::new(&path)).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].lsn, 1);
+    }
+}
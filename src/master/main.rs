@@ -5,82 +5,45 @@
 #[macro_use]
 extern crate rocket;
 
-use std::collections::HashMap;
 use std::io::Error;
 use log::warn;
 use rocket::{get, post, routes, State};
+use rocket::http::Status;
 use rocket::futures::StreamExt;
 use rocket::serde::{json::Json, Serialize, Deserialize};
-use rocket::tokio::fs::OpenOptions;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::sync::RwLock;
 use uuid::Uuid;
-use lib::shared::{log_manager, master_client_utils::ChunkInfo};
-use lib::shared::master_client_utils::{DirectoryInfo, FileInfo, User};
-use namespace_manager::{directory_create, directory_delete, list_directory,
-                        file_create, file_read, file_write, file_delete};
-use crate::namespace_manager::file_read_all;
+use lib::shared::master_client_utils::FileChunks;
+use lib::shared::master_client_utils::{DirectoryInfo, ErasureCoding, FileInfo, RebalancePlan,
+                                       SessionToken, User, UserSummary};
+use namespace_manager::{directory_create, directory_delete, directory_rename, list_directory,
+                        file_create, file_read, file_write, file_delete, file_rename,
+                        change_mode, change_owner, set_replication};
+use auth_manager::{AdminUser, AuthUser};
+use lock_manager::blocking;
+use lib::shared::cluster_auth::{self, ClusterServer, WriteGrant};
+use user_manager::UserDatabase;
 
 mod namespace_manager;
 mod chunk_manager;
 mod safe_map;
 mod heartbeat_manager;
+mod log_manager;
+mod checkpoint_manager;
+mod lock_manager;
+mod auth_manager;
+mod user_manager;
+mod replication_manager;
+mod gc_manager;
+mod placement_manager;
+mod topology_manager;
+mod drain_manager;
+mod rebalance_manager;
 
 const USER_INFO:&str = "users.json";
-const DIR_FILE:&str = "dir.json";
-const CHUNK_FILE:&str = "chunk.json";
-const SERVER_FILE:&str = "server.json";
-
-struct UserDatabase {
-    users: RwLock<HashMap<String, String>>,
-}
-
-impl UserDatabase {
-    async fn new() -> Self {
-        let users = RwLock::new(HashMap::new());
-
-        let file = OpenOptions::new()
-            .read(true).write(true).create(true)
-            .open(USER_INFO).await.unwrap();
-
-        let reader = BufReader::new(file);
-        let mut lines = reader.lines();
-
-        while let Some(line_result) = lines.next_line().await.unwrap() {
-            let user: User = serde_json::from_str(&line_result).unwrap();
-            users.write().await.insert(
-                user.username.clone(),
-                user.password.clone());
-        }
-        Self { users }
-    }
-
-    async fn save(&self, user: User) -> Result<(), Error> {
-        let mut file = OpenOptions::new()
-            .append(true).create(true)
-            .open(USER_INFO).await.unwrap();
-
-        let data = serde_json::to_string(&user)?;
-        file.write_all(data.as_bytes()).await?;
-        file.write_all(b"\n").await?;
-        Ok(())
-    }
-}
-
-async fn persistent_storage_init() {
-    let file = OpenOptions::new()
-        .read(true).write(true).create(true)
-        .open(DIR_FILE).await.unwrap();
-    let reader = BufReader::new(file);
-
-    let file = OpenOptions::new()
-        .read(true).write(true).create(true)
-        .open(CHUNK_FILE).await.unwrap();
-
-    let file = OpenOptions::new()
-        .read(true).write(true).create(true)
-        .open(SERVER_FILE).await.unwrap();
-}
+const LOG_FILE:&str = "operation.log";
+const CHECKPOINT_DIR:&str = "checkpoints";
+// Password of the superuser account, which cannot be registered
+const ROOT_PASSWORD_ENV:&str = "DFS_ROOT_PASSWORD";
 
 /*
 *   Maintains filesystem's metadata in memory :
@@ -122,20 +85,51 @@ async fn persistent_storage_init() {
 
 #[rocket::main]
 async fn main() {
-    log_manager::set_logging(&[
+    lib::shared::log_manager::set_logging(&[
         //log::Level::Info,
         //log::Level::Debug,
         // log::Level::Warn,
         log::Level::Error,
     ]);
 
-    persistent_storage_init().await;
-
+    auth_manager::auth_manager_init();
     namespace_manager::namespace_manager_init();
+    // Chunkservers and the master sign their requests with the cluster secret
+    if let Err(e) = cluster_auth::cluster_auth_init() {
+        panic!("{}", e);
+    }
     chunk_manager::chunk_manager_init();
-    // heartbeat_manager::heartbeat_manager_init();
-
-    let user_db = UserDatabase::new().await;
+    placement_manager::placement_manager_init();
+    topology_manager::topology_manager_init();
+    drain_manager::drain_manager_init();
+    rebalance_manager::rebalance_manager_init();
+
+    // Load the newest checkpoint, then redo the operations logged after it
+    checkpoint_manager::checkpoint_manager_init(CHECKPOINT_DIR).unwrap();
+    let checkpoint_lsn = match checkpoint_manager::restore_latest_checkpoint(CHECKPOINT_DIR) {
+        Ok(lsn) => lsn,
+        Err(e) => panic!("Failed to load checkpoint from {}: {}", CHECKPOINT_DIR, e),
+    };
+    match log_manager::recover(LOG_FILE, checkpoint_lsn, |operation| {
+        let _ = namespace_manager::apply_operation(operation);
+    }) {
+        Ok(count) => info!("Recovered {} operations from {}", count, LOG_FILE),
+        Err(e) => panic!("Failed to recover operation log {}: {}", LOG_FILE, e),
+    }
+    tokio::spawn(checkpoint_manager::checkpoint_loop(
+        CHECKPOINT_DIR.to_string(), checkpoint_lsn));
+    heartbeat_manager::heartbeat_manager_init();
+    tokio::spawn(heartbeat_manager::liveness_loop());
+    tokio::spawn(replication_manager::replication_loop());
+    tokio::spawn(drain_manager::drain_loop());
+    tokio::spawn(rebalance_manager::rebalance_loop());
+    gc_manager::gc_manager_init();
+    tokio::spawn(gc_manager::gc_loop());
+
+    let user_db = match UserDatabase::new(USER_INFO, std::env::var(ROOT_PASSWORD_ENV).ok()).await {
+        Ok(user_db) => user_db,
+        Err(e) => panic!("Failed to load users from {}: {}", USER_INFO, e),
+    };
     /*
     *   Input  : Get (file name, chunk index) from client
     *   Output : Ret (chunk handle, chunk locations) to client
@@ -153,14 +147,30 @@ async fn main() {
         .mount("/", routes![
             register,
             login,
+            logout,
+            refresh,
+            list_users,
+            change_password,
+            disable_user,
+            delete_user,
+            promote_user,
+            list_servers,
+            view_topology,
+            drain_server,
+            list_drains,
+            rebalance,
             create_file,
             read_file,
-            read_all_file,
             update_file,
             delete_file,
+            rename_file,
             create_directory,
             read_directory,
             delete_directory,
+            rename_directory,
+            chmod,
+            chown,
+            setrep,
             chunkserver_heartbeat
         ]);
     app.launch().await.unwrap();
@@ -177,99 +187,280 @@ async fn main() {
 */
 
 
-#[post("/file/create?<path>")]
-async fn create_file(path:String) -> Result<Json<FileInfo>, Error> {
-    match file_create(path) {
+/*
+*   Parses a mode given in octal, e.g. "755".
+*/
+fn parse_mode(mode: Option<String>) -> Result<Option<i32>, Error> {
+    match mode {
+        Some(mode) => i32::from_str_radix(&mode, 8)
+            .ok()
+            .filter(|mode| (0..=0o777).contains(mode))
+            .map(Some)
+            .ok_or_else(|| Error::new(std::io::ErrorKind::InvalidInput,
+                                      format!("Invalid mode: {}", mode))),
+        None => Ok(None),
+    }
+}
+
+/*
+*   Parses an erasure coding given as "data,parity", e.g. "6,3".
+*/
+fn parse_erasure(erasure: Option<String>) -> Result<Option<ErasureCoding>, Error> {
+    erasure.map(|erasure| erasure.parse::<ErasureCoding>()
+            .map_err(|e| Error::new(std::io::ErrorKind::InvalidInput, e)))
+        .transpose()
+}
+
+#[post("/file/create?<path>&<mode>&<replication>&<erasure>")]
+async fn create_file(path:String, mode:Option<String>, replication:Option<usize>,
+                     erasure:Option<String>, user:AuthUser) -> Result<Json<FileInfo>, Error> {
+    let (mode, erasure) = (parse_mode(mode)?, parse_erasure(erasure)?);
+    match blocking(move || file_create(path, &user.username, mode, replication, erasure)).await {
         Ok(file) => Ok(Json(file)),
         Err(e) => Err(e)
     }
 }
 
 #[get("/file/read?<path>")]
-async fn read_file(path:String) -> Json<Vec<ChunkInfo>>{
-    let chunks = file_read(path).unwrap();
-    Json(ChunkInfo::serialize(chunks))
-    // TODO: error handling for non-existent chunk index
-}
-
-#[get("/file/read/all?<path>")]
-async fn read_all_file(path:String) -> Json<Vec<ChunkInfo>>{
-    let chunks = file_read_all(path).unwrap();
-    Json(ChunkInfo::serialize(chunks))
+async fn read_file(path:String, user:AuthUser) -> Result<Json<FileChunks>, Error> {
+    let mut chunks = blocking(move || file_read(path, &user.username)).await
+        .map_err(Error::other)?;
+    for chunk in chunks.chunks.iter_mut() {
+        let uuid = Uuid::parse_str(&chunk.uuid).map_err(Error::other)?;
+        chunk.token = Some(cluster_auth::read_token(uuid));
+    }
+    Ok(Json(chunks))
 }
 
+/*
+*   Called before writing `size` bytes to a file. Returns
+*   where each chunk of the new content has to be written. The
+*   token of a chunk grants the write at its version, through the
+*   lease holder to the other replicas when it has a lease.
+*/
 #[post("/file/update?<path>&<size>")]
-async fn update_file(path:String, size:usize) -> Json<Vec<ChunkInfo>>{
-    let chunks = file_write(path, size).unwrap();
-    Json(ChunkInfo::serialize(chunks))
+async fn update_file(path:String, size:u64, user:AuthUser) -> Result<Json<FileChunks>, Error> {
+    let mut chunks = blocking(move || file_write(path, size, &user.username)).await
+        .map_err(Error::other)?;
+    for chunk in chunks.chunks.iter_mut() {
+        let uuid = Uuid::parse_str(&chunk.uuid).map_err(Error::other)?;
+        let secondaries = match &chunk.primary {
+            Some(primary) => chunk.locations.iter()
+                .filter(|server| *server != primary)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        chunk.token = Some(cluster_auth::write_token(WriteGrant {
+            chunk: uuid,
+            version: chunk.version,
+            primary: chunk.primary.clone(),
+            secondaries,
+        }));
+    }
+    Ok(Json(chunks))
 }
 
 #[get("/file/delete?<path>")]
-async fn delete_file(path:String) -> Result<(), Error> {
-    let result = file_delete(path);
+async fn delete_file(path:String, user:AuthUser) -> Result<(), Error> {
+    let result = blocking(move || file_delete(path, &user.username)).await;
     match result {
         Ok(_) => Ok(()),
         Err(e) => Err(Error::new(std::io::ErrorKind::NotFound, e))
     }
 }
 
-#[post("/dir/create?<path>")]
-async fn create_directory(path:String) -> Result<String, Error> {
+#[post("/file/rename?<from>&<to>")]
+async fn rename_file(from:String, to:String, user:AuthUser) -> Result<(), Error> {
+    blocking(move || file_rename(from, to, &user.username)).await
+}
+
+#[post("/dir/create?<path>&<mode>&<replication>&<erasure>")]
+async fn create_directory(path:String, mode:Option<String>, replication:Option<usize>,
+                          erasure:Option<String>, user:AuthUser) -> Result<String, Error> {
     println!("{}", path);
-    let result = directory_create(path);
+    let (mode, erasure) = (parse_mode(mode)?, parse_erasure(erasure)?);
+    let result = blocking(move || directory_create(path, &user.username, mode, replication,
+                                                   erasure)).await;
     Ok(result)
 }
 
 #[get("/dir/read?<path>")]
-async fn read_directory(path:String) -> Result<Json<DirectoryInfo>, Error> {
-    match list_directory(path) {
+async fn read_directory(path:String, user:AuthUser) -> Result<Json<DirectoryInfo>, Error> {
+    match blocking(move || list_directory(path, &user.username)).await {
         Ok(dir) => Ok(Json(dir)),
         Err(e) => Err(e)
     }
 }
 
 #[post("/dir/delete?<path>")]
-async fn delete_directory(path:String) -> Result<(), Error> {
-    let result = directory_delete(path);
+async fn delete_directory(path:String, user:AuthUser) -> Result<(), Error> {
+    let result = blocking(move || directory_delete(path, &user.username)).await;
     match result {
         Ok(_) => Ok(()),
         Err(e) => Err(Error::new(std::io::ErrorKind::NotFound, e))
     }
 }
 
+#[post("/dir/rename?<from>&<to>")]
+async fn rename_directory(from:String, to:String, user:AuthUser) -> Result<(), Error> {
+    blocking(move || directory_rename(from, to, &user.username)).await
+}
+
+#[post("/chmod?<path>&<mode>")]
+async fn chmod(path:String, mode:String, user:AuthUser) -> Result<(), Error> {
+    let mode = parse_mode(Some(mode))?.unwrap();
+    blocking(move || change_mode(path, mode, &user.username)).await
+}
+
+/*
+*   Groups are per user (a user's group is named after the user), so
+*   the owner and the group both have to be existing users.
+*/
+#[post("/chown?<path>&<owner>&<group>")]
+async fn chown(path:String, owner:String, group:Option<String>, user:AuthUser,
+               user_db: &State<UserDatabase>) -> Result<(), Error> {
+    let group = group.unwrap_or_else(|| owner.clone());
+    for name in [&owner, &group] {
+        if !user_db.exists(name).await {
+            return Err(Error::new(std::io::ErrorKind::InvalidInput,
+                                  format!("No such user or group: {}", name)));
+        }
+    }
+    blocking(move || change_owner(path, owner, group, &user.username)).await
+}
+
+/*
+*   Replication of a file, or of a directory and everything below it.
+*/
+#[post("/setrep?<path>&<replication>")]
+async fn setrep(path:String, replication:usize, user:AuthUser) -> Result<(), Error> {
+    blocking(move || set_replication(path, replication, &user.username)).await
+}
+
 #[post("/user/register", data = "<user>")]
 async fn register(user:Json<User>, user_db: &State<UserDatabase>) -> Result<(), Error> {
-    let user = user.into_inner();
-    let mut users_lock = user_db.users.write().await;
+    user_db.register(user.into_inner()).await
+}
 
-    // check if user already exists
-    if users_lock.contains_key(&user.username) {
-        return Err(Error::new(std::io::ErrorKind::AlreadyExists, "User already exists"));
-    }
-    users_lock.insert(user.username.clone(), user.password.clone());
+#[post("/user/login", data = "<user>")]
+async fn login(user:Json<User>, user_db: &State<UserDatabase>) -> Result<Json<SessionToken>, Error> {
+    let user = user.into_inner();
+    user_db.authenticate(&user).await?;
+    Ok(Json(auth_manager::issue_token(&user.username)))
+}
 
-    // write to persistent storage
-    user_db.save(user).await?;
+#[post("/user/logout")]
+async fn logout(user:AuthUser) -> Result<(), Error> {
+    auth_manager::revoke(&user.session);
     Ok(())
 }
 
-#[post("/user/login", data = "<user>")]
-async fn login(user:Json<User>, user_db: &State<UserDatabase>) -> Result<(), Error> {
+/*
+*   Replaces the caller's token by a new one with a later expiry.
+*/
+#[post("/user/refresh")]
+async fn refresh(user:AuthUser) -> Result<Json<SessionToken>, Error> {
+    let token = auth_manager::issue_token(&user.username);
+    auth_manager::revoke(&user.session);
+    Ok(Json(token))
+}
+
+
+/*
+*   User administration, only for admins.
+*/
+
+#[get("/admin/users")]
+async fn list_users(_admin:AdminUser, user_db: &State<UserDatabase>) -> Json<Vec<UserSummary>> {
+    Json(user_db.list().await)
+}
+
+#[post("/admin/user/password", data = "<user>")]
+async fn change_password(user:Json<User>, _admin:AdminUser, user_db: &State<UserDatabase>)
+    -> Result<(), Error> {
     let user = user.into_inner();
-    let users_lock = user_db.users.read().await;
+    user_db.set_password(&user.username, user.password).await?;
+    auth_manager::revoke_user(&user.username);
+    Ok(())
+}
 
-    // check if user exists
-    if !users_lock.contains_key(&user.username) {
-        return Err(Error::new(std::io::ErrorKind::NotFound, "User not found"));
+#[post("/admin/user/disable?<username>&<disabled>")]
+async fn disable_user(username:String, disabled:bool, _admin:AdminUser,
+                      user_db: &State<UserDatabase>) -> Result<(), Error> {
+    user_db.set_disabled(&username, disabled).await?;
+    if disabled {
+        auth_manager::revoke_user(&username);
     }
+    Ok(())
+}
 
-    // check if password matches
-    if users_lock.get(&user.username).unwrap() != &user.password {
-        return Err(Error::new(std::io::ErrorKind::InvalidData, "Invalid password"));
-    }
+#[post("/admin/user/delete?<username>")]
+async fn delete_user(username:String, _admin:AdminUser, user_db: &State<UserDatabase>)
+    -> Result<(), Error> {
+    user_db.delete(&username).await?;
+    auth_manager::revoke_user(&username);
     Ok(())
 }
 
+#[post("/admin/user/admin?<username>&<admin>")]
+async fn promote_user(username:String, admin:bool, _caller:AdminUser,
+                      user_db: &State<UserDatabase>) -> Result<(), Error> {
+    user_db.set_admin(&username, admin).await
+}
+
+/*
+*   Liveness of the chunkservers, only for admins.
+*/
+#[get("/servers")]
+async fn list_servers(_admin:AdminUser) -> Json<Vec<heartbeat_manager::ServerView>> {
+    Json(heartbeat_manager::server_views())
+}
+
+/*
+*   Datacenters, racks and hosts of the chunkservers, and the
+*   failure domains each chunk is spread over, only for admins.
+*/
+#[get("/topology")]
+async fn view_topology(_admin:AdminUser) -> Json<topology_manager::TopologyView> {
+    Json(topology_manager::topology_view())
+}
+
+/*
+*   Drains a chunkserver : no new chunks are placed on it and its
+*   chunks are copied to other servers, only for admins.
+*   enabled=false cancels the drain.
+*/
+#[post("/servers/drain?<server>&<enabled>")]
+async fn drain_server(server:String, enabled:Option<bool>, _admin:AdminUser) -> Result<(), Error> {
+    let result = match enabled.unwrap_or(true) {
+        true => drain_manager::start_drain(&server).map(|_| ()),
+        false => drain_manager::cancel_drain(&server),
+    };
+    result.map_err(|e| Error::new(std::io::ErrorKind::InvalidInput, e))
+}
+
+/*
+*   Progress of the drains, a drained server is safe to remove.
+*/
+#[get("/servers/drain")]
+async fn list_drains(_admin:AdminUser) -> Json<Vec<drain_manager::DrainView>> {
+    Json(drain_manager::drain_views())
+}
+
+/*
+*   Moves replicas from over-full to under-full chunkservers, only
+*   for admins. A dry run only returns the planned moves.
+*/
+#[post("/rebalance?<threshold>&<budget>&<dry_run>")]
+async fn rebalance(threshold:Option<f64>, budget:Option<u64>, dry_run:Option<bool>,
+                   _admin:AdminUser) -> Result<Json<RebalancePlan>, Error> {
+    let threshold = threshold.unwrap_or_else(rebalance_manager::default_threshold);
+    let budget = budget.unwrap_or_else(rebalance_manager::default_budget);
+    rebalance_manager::rebalance(threshold, budget, dry_run.unwrap_or(false)).await
+        .map(Json)
+        .map_err(Error::other)
+}
 
 // fn update_namespace(){
 //
@@ -282,9 +473,9 @@ async fn login(user:Json<User>, user_db: &State<UserDatabase>) -> Result<(), Err
 *   Output : Chunk Location - Send Data to Chunk
 */
 #[post("/heartbeat", format = "json", data = "<metadata>")]
-async fn chunkserver_heartbeat(metadata: Json<heartbeat_manager::Metadata>) -> Result<(), Error> {
+async fn chunkserver_heartbeat(metadata: Json<heartbeat_manager::Metadata>, _server: ClusterServer)
+    -> Result<Json<heartbeat_manager::HeartbeatResponse>, Status> {
     debug!("{:?}", metadata);
     debug!("Received heartbeat from chunkserver id: {}", metadata.chunkserver_id);
-    heartbeat_manager::receive_heartbeat(metadata).await;
-    Ok(())
+    blocking(move || heartbeat_manager::receive_heartbeat(metadata)).await
 }
\ No newline at end of file
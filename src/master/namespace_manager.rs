@@ -4,18 +4,21 @@ use chrono::prelude::*;
 use chrono::DateTime;
 
 use crate::chunk_manager;
-use crate::chunk_manager::{get_chunks, write_chunks};
+use crate::chunk_manager::{allocate_chunks, chunk_count, get_chunk_info, get_replicas,
+                          grant_lease, register_chunks, schedule_chunk_deletion};
+use crate::log_manager;
+use crate::log_manager::Operation;
+use crate::lock_manager;
+use crate::placement_manager;
 use crate::safe_map::SafeMap;
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::RwLock;
 use rocket::serde::json::Json;
 use serde::Serialize;
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use uuid::Uuid;
-use lib::shared::master_client_utils::{FileInfo, DirectoryInfo, Metadata};
+use lib::shared::master_client_utils::{FileChunks, FileInfo, DirectoryInfo, ErasureCoding, Metadata};
 
 /*
 *   A managers for all the files and directories
@@ -35,7 +38,9 @@ use lib::shared::master_client_utils::{FileInfo, DirectoryInfo, Metadata};
 *       2.  File Node {
 *               File Name           : String
 *               File Parent         : String
-*               Chunks              : List<Chunk Handle(uuid)>
+*               Chunks              : List<List<Chunk Handle(uuid)>>
+*                                       (replicas of each chunk, in file order)
+*               Chunk Size          : u64 (chunk size of the last write)
 *               File Metadata       : Metadata
 *               Read Write Lock     : RW Lock
 *           }
@@ -44,14 +49,20 @@ use lib::shared::master_client_utils::{FileInfo, DirectoryInfo, Metadata};
 *
 *       3.  Directory Map (Path -> Directory Node)
 *
+*           Every operation first takes the path locks of the
+*           lock manager (read locks on the ancestors, read or
+*           write lock on the path) and holds them until it is
+*           logged and applied.
+*
 *
 *       4.  Metadata {
 *               Size            : i64
 *               Creation Time   : DateTime
 *               Modify Time     : DateTime
-*               Permissions     : i32
+*               Permissions     : i32 (mode bits, e.g. 0o755)
 *               Owner           : String
 *               Group           : String
+*               Replication     : usize (replicas of each chunk)
 *           }
 *
 *
@@ -64,7 +75,11 @@ use lib::shared::master_client_utils::{FileInfo, DirectoryInfo, Metadata};
 *       2. Create File
 *       3. Delete File
 *       4. List Directory
-*       5. Rename
+*       5. Rename (files and whole directory subtrees)
+*       6. Replace the replicas lost with a chunkserver
+*       7. Set the replication of a file, or of a directory and
+*          everything below it. Files and directories are created
+*          with the replication of their directory unless given one.
 *
 *
 *
@@ -77,8 +92,25 @@ const FILE_ALREADY_EXIST: &str = "This file already exists";
 const DIR_ALREADY_EXIST: &str = "This directory already exists";
 const CREATED_DIR_SUCCESSFULLY: &str = "Successfully created directory";
 const CREATED_FILE_SUCCESSFULLY: &str = "Successfully created file";
-const DIR_SIZE: i32 = 4000;
-const FILE_SIZE: i32 = 10000;
+const PERMISSION_DENIED: &str = "Permission denied";
+const DIR_SIZE: i64 = 4000;
+const FILE_SIZE: i64 = 0;
+
+/*
+*   Permissions are POSIX style mode bits for owner, group
+*   and others. Groups are per user : a user's group is named
+*   after the user and has no other member, so the group bits
+*   only apply to the user the group is named after.
+*/
+pub const SUPERUSER: &str = "root";
+const READ: i32 = 0o4;
+const WRITE: i32 = 0o2;
+const EXECUTE: i32 = 0o1;
+const ROOT_DIR_MODE: i32 = 0o777;
+const DEFAULT_DIR_MODE: i32 = 0o755;
+const DEFAULT_FILE_MODE: i32 = 0o644;
+const MAX_REPLICATION: usize = 10;
+const MAX_FRAGMENTS: usize = 32;
 // #[derive(Debug, Clone)]
 // struct Metadata {
 //     size: i32,
@@ -108,7 +140,9 @@ struct FileNode {
     file_name: String,
     file_parent: String,
     file_metadata: Metadata,
-    chunks: Vec<Vec<Uuid>>,
+    // chunk handle of each chunk index
+    chunks: Vec<Uuid>,
+    chunk_size: u64,
     // rw_lock: RwLock<i32>,
 }
 
@@ -122,7 +156,8 @@ impl FileNode {
             file_name: file_name.clone(),
             file_parent: file_parent.clone(),
             chunks: Vec::new(),
-            file_metadata: file_metadata,
+            chunk_size: chunk_manager::chunk_size(),
+            file_metadata,
         };
 
         // Acquire DIR_MAP lock first
@@ -144,6 +179,10 @@ impl FileNode {
             .write()
             .map_err(|_| "Failed to acquire write lock on parent directory")?;
 
+        if parent_write.files.contains_key(&file_name) {
+            return Err(FILE_ALREADY_EXIST.to_string());
+        }
+
         parent_write
             .files
             .insert(file_name, Arc::new(RwLock::new(file.clone())));
@@ -156,9 +195,8 @@ impl FileNode {
             file_name: self.file_name.clone(),
             file_parent: self.file_parent.clone(),
             file_metadata: self.file_metadata.clone(),
-            chunks: self.chunks.clone().iter()
-                .map(|x| x.iter()
-                    .map(|uuid| uuid.to_string()).collect()).collect(),
+            chunks: self.chunks.iter().map(|uuid| uuid.to_string()).collect(),
+            chunk_size: self.chunk_size,
         }
     }
 
@@ -167,9 +205,11 @@ impl FileNode {
             file_name: info.file_name.clone(),
             file_parent: info.file_parent.clone(),
             file_metadata: info.file_metadata.clone(),
-            chunks: info.chunks.iter()
-                .map(|x| x.iter()
-                    .map(|uuid| Uuid::parse_str(uuid).unwrap()).collect()).collect(),
+            chunks: info.chunks.iter().map(|uuid| Uuid::parse_str(uuid).unwrap()).collect(),
+            chunk_size: match info.chunk_size {
+                0 => chunk_manager::chunk_size(),
+                chunk_size => chunk_size,
+            },
         }
     }
 }
@@ -183,20 +223,12 @@ struct DirectoryNode {
 }
 
 impl DirectoryNode {
-    pub fn new(dir_name: String, dir_metadata: Metadata, dir_parent: String) {
-        let node = DirectoryNode {
-            dir_name: dir_name.clone(),
-            dir_metadata: dir_metadata,
-            dir_parent: dir_parent.clone(),
+    pub fn new(dir_name: String, dir_metadata: Metadata, dir_parent: String) -> Self {
+        DirectoryNode {
+            dir_name,
+            dir_metadata,
+            dir_parent,
             files: HashMap::new(),
-        };
-        /*
-         *   Add the new node to the directory map
-         */
-        if DIR_MAP.get(&dir_parent).is_some() || dir_parent == "/" {
-            DIR_MAP.insert(dir_name.clone(), node);
-        } else {
-            println!("This directory \"{}\" does not exist !!!", dir_parent);
         }
     }
 
@@ -244,29 +276,18 @@ impl Clone for DirectoryNode {
 
 static DIR_MAP: SafeMap<String, DirectoryNode> = SafeMap::new();
 
-// TODO: pass DIR_MAP from main
-pub async fn save_dir_state() {
-    let mut file = OpenOptions::new()
-        .write(true).create(true)
-        .open("dir.json").await.unwrap();
-
-    for (_, dir) in DIR_MAP.to_map().iter() {
-        let data = serde_json::to_string(&dir.serialize()).unwrap();
-        file.write_all(data.as_bytes()).await.unwrap();
-        file.write_all(b"\n").await.unwrap();
-    }
+/*
+*   Copy of the whole namespace for a checkpoint.
+*/
+pub fn snapshot_dir_state() -> Vec<DirectoryInfo> {
+    DIR_MAP.to_map().values().map(|dir| dir.serialize()).collect()
 }
 
-pub async fn load_dir_state() {
-    let file = OpenOptions::new()
-        .read(true).create(true)
-        .open("dir.json").await.unwrap();
-
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
-
-    while let Some(line_result) = lines.next_line().await.unwrap() {
-        let dir: DirectoryInfo = serde_json::from_str(&line_result).unwrap();
+/*
+*   Replaces the namespace with the one stored in a checkpoint.
+*/
+pub fn restore_dir_state(dirs: Vec<DirectoryInfo>) {
+    for dir in dirs {
         let node = DirectoryNode::deserialize(dir);
         DIR_MAP.insert(node.dir_name.clone(), node);
     }
@@ -274,45 +295,604 @@ pub async fn load_dir_state() {
 
 pub fn namespace_manager_init() {
     DIR_MAP.init();
-    load_dir_state();
-    let root_metadata = Metadata::new(DIR_SIZE, 0x666, "0".to_string(), "root".to_string());
-    DirectoryNode::new("/".to_string(), root_metadata, "/".to_string());
+    let root_metadata = Metadata::new(
+        DIR_SIZE, ROOT_DIR_MODE, SUPERUSER.to_string(), SUPERUSER.to_string());
+    let root = DirectoryNode::new("/".to_string(), root_metadata, "/".to_string());
+    DIR_MAP.insert(root.dir_name.clone(), root);
+}
+
+/*
+*   Splits a path into its parent directory and its last component.
+*   Example : /foo/bar.txt -> (/foo, bar.txt)
+*/
+fn split_path(path: &str) -> (&str, &str) {
+    match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((dir, name)) => (dir, name),
+        None => ("/", path),
+    }
 }
 
 /////////////////////////////////////////////////////
-/// Path Lookup
+// Operation Log
 
-pub fn file_lookup(path: String) -> Result<Vec<Vec<Uuid>>, String>{
-    let (directory, filename) = match path.rsplit_once('/') {
-        Some((dir, name)) if dir.is_empty() => ("/", name),
-        Some((dir, name)) => (dir, name),
-        None => ("/", path.as_str()),
+/*
+*   Redo a logged operation on the in-memory namespace.
+*   Used both when committing a new operation and when
+*   replaying the operation log at startup, so it must only
+*   depend on the contents of the operation.
+*/
+pub fn apply_operation(operation: &Operation) -> Result<(), String> {
+    let result = match operation {
+        Operation::FileCreate { path, metadata } =>
+            apply_file_create(path, metadata.clone()),
+        Operation::FileDelete { path } =>
+            apply_file_delete(path),
+        Operation::FileWrite { path, size, chunk_size, chunks } =>
+            apply_file_write(path, *size, *chunk_size, chunks),
+        Operation::DirectoryCreate { path, metadata } =>
+            apply_directory_create(path, metadata.clone()),
+        Operation::DirectoryDelete { path } =>
+            apply_directory_delete(path),
+        Operation::FileRename { from, to } =>
+            apply_file_rename(from, to),
+        Operation::DirectoryRename { from, to } =>
+            apply_directory_rename(from, to),
+        Operation::ChangeMode { path, mode } =>
+            apply_update_metadata(path, |metadata| metadata.permission = *mode),
+        Operation::ChangeOwner { path, owner, group } =>
+            apply_update_metadata(path, |metadata| {
+                metadata.owner = owner.clone();
+                metadata.group = group.clone();
+            }),
+        Operation::ReplaceReplica { path, index, old, server, version } =>
+            apply_replace_replica(path, *index, old.as_deref(), server, *version),
+        Operation::SetReplication { path, replication } =>
+            apply_set_replication(path, *replication),
+        Operation::RemoveReplica { path, index, server } =>
+            apply_remove_replica(path, *index, server),
+        Operation::ReplaceFragment { path, index, fragment, old, server, version } =>
+            apply_replace_fragment(path, *index, *fragment, old.as_deref(), server, *version),
+        Operation::SetChunkVersion { uuid, from, to } =>
+            chunk_manager::set_chunk_version(uuid, *from, *to),
+    };
+    if let Err(e) = &result {
+        warn!("Failed to apply {:?}: {}", operation, e);
+    }
+    result
+}
+
+fn apply_file_create(path: &str, metadata: Metadata) -> Result<(), String> {
+    let (directory, filename) = split_path(path);
+    FileNode::new(filename.to_string(), directory.to_string(), metadata)?;
+    Ok(())
+}
+
+fn apply_file_delete(path: &str) -> Result<(), String> {
+    let (directory, filename) = split_path(path);
+
+    let dir_lock = DIR_MAP
+        .get(&directory.to_string())
+        .ok_or_else(|| format!("{}: {}", NO_DIR_EXIST, directory))?;
+
+    let mut dir_write = dir_lock
+        .write()
+        .map_err(|_| "Failed to acquire write lock on directory".to_string())?;
+
+    if dir_write.files.remove(filename).is_some() {
+        Ok(())
+    } else {
+        Err(format!("{}: {}", NO_FILE_EXIST, filename))
+    }
+}
+
+/*
+*   Sets the chunks of a file after a write of `size` bytes. Chunks
+*   the file no longer uses are left to the gc manager, which reclaims
+*   them once the write had time to reach the chunks that are kept.
+*/
+fn apply_file_write(path: &str, size: u64, chunk_size: u64, chunks: &[(Uuid, Vec<String>)])
+    -> Result<(), String> {
+    let (directory, filename) = split_path(path);
+
+    let parent = DIR_MAP
+        .get(&directory.to_string())
+        .ok_or_else(|| format!("Directory '{}' does not exist", directory))?;
+
+    let dir_read = parent.read().map_err(|e| e.to_string())?;
+
+    let file: &Arc<RwLock<FileNode>> = dir_read
+        .files
+        .get(filename)
+        .ok_or_else(|| format!("No such file {} exists", filename))?;
+
+    let handles: Vec<Uuid> = chunks.iter().map(|(uuid, _)| *uuid).collect();
+    let mut file_write = file.write().unwrap();
+
+    for (uuid, servers) in chunks {
+        match file_write.file_metadata.erasure {
+            Some(erasure) => chunk_manager::register_fragments(*uuid, servers, erasure),
+            None => {
+                register_chunks(*uuid, servers);
+                chunk_manager::set_replication(uuid, file_write.file_metadata.replication);
+            }
+        }
+    }
+    file_write.chunks = handles;
+    file_write.chunk_size = chunk_size;
+    file_write.file_metadata.set_size(size as i64);
+    Ok(())
+}
+
+/*
+*   Adds a re-replicated copy on `server` to the replicas of chunk
+*   `index` of a file, in place of the lost replica on `old`.
+*/
+fn apply_replace_replica(path: &str, index: usize, old: Option<&str>,
+                         server: &str, version: u64) -> Result<(), String> {
+    let (directory, filename) = split_path(path);
+
+    let parent = DIR_MAP
+        .get(&directory.to_string())
+        .ok_or_else(|| format!("Directory '{}' does not exist", directory))?;
+    let dir_read = parent.read().map_err(|e| e.to_string())?;
+    let file = dir_read
+        .files
+        .get(filename)
+        .ok_or_else(|| format!("No such file {} exists", filename))?;
+
+    let uuid = *file.read().unwrap().chunks.get(index)
+        .ok_or_else(|| format!("File {} has no chunk {}", path, index))?;
+    chunk_manager::add_replica(uuid, server, version)?;
+    if let Some(old) = old {
+        chunk_manager::drop_replica(&uuid, old);
+    }
+    Ok(())
+}
+
+/*
+*   Sets the replication of a file and its chunks. For a directory
+*   it is set on the directory, the directories below it and all
+*   their files.
+*/
+fn apply_set_replication(path: &str, replication: usize) -> Result<(), String> {
+    let set_file = |file: &Arc<RwLock<FileNode>>| {
+        let mut file = file.write().unwrap();
+        file.file_metadata.replication = replication;
+        for uuid in file.chunks.iter() {
+            chunk_manager::set_replication(uuid, replication);
+        }
+    };
+
+    if DIR_MAP.get(&path.to_string()).is_some() {
+        let prefix = match path {
+            "/" => "/".to_string(),
+            _ => format!("{}/", path),
+        };
+        let guard = DIR_MAP.inner.lock()
+            .map_err(|_| "Failed to acquire lock on DIR_MAP".to_string())?;
+        let map = guard.as_ref().ok_or("DIR_MAP not initialized")?;
+        for (name, dir) in map.iter() {
+            if name == path || name.starts_with(&prefix) {
+                let mut dir = dir.write().unwrap();
+                dir.dir_metadata.replication = replication;
+                dir.files.values().for_each(set_file);
+            }
+        }
+        return Ok(());
+    }
+
+    let (directory, filename) = split_path(path);
+    let dir = DIR_MAP.get(&directory.to_string())
+        .ok_or_else(|| format!("{}: {}", NO_DIR_EXIST, directory))?;
+    let dir_read = dir.read().unwrap();
+    let file = dir_read.files.get(filename)
+        .ok_or_else(|| format!("{}: {}", NO_FILE_EXIST, path))?;
+    set_file(file);
+    Ok(())
+}
+
+/*
+*   Records fragment `fragment` of chunk `index` of a file
+*   on `server`, in place of the lost one on `old`.
+*/
+fn apply_replace_fragment(path: &str, index: usize, fragment: usize, old: Option<&str>,
+                          server: &str, version: u64) -> Result<(), String> {
+    let (directory, filename) = split_path(path);
+    let dir = DIR_MAP.get(&directory.to_string())
+        .ok_or_else(|| format!("{}: {}", NO_DIR_EXIST, directory))?;
+    let dir_read = dir.read().unwrap();
+    let file = dir_read.files.get(filename)
+        .ok_or_else(|| format!("{}: {}", NO_FILE_EXIST, path))?;
+    let uuid = *file.read().unwrap().chunks.get(index)
+        .ok_or_else(|| format!("File {} has no chunk {}", path, index))?;
+    chunk_manager::add_fragment(uuid, server, fragment, version)?;
+    if let Some(old) = old {
+        chunk_manager::drop_replica(&uuid, old);
+    }
+    Ok(())
+}
+
+/*
+*   Drops the replica on `server` of chunk `index` of a file.
+*/
+fn apply_remove_replica(path: &str, index: usize, server: &str) -> Result<(), String> {
+    let (directory, filename) = split_path(path);
+    let dir = DIR_MAP.get(&directory.to_string())
+        .ok_or_else(|| format!("{}: {}", NO_DIR_EXIST, directory))?;
+    let dir_read = dir.read().unwrap();
+    let file = dir_read.files.get(filename)
+        .ok_or_else(|| format!("{}: {}", NO_FILE_EXIST, path))?;
+    let uuid = *file.read().unwrap().chunks.get(index)
+        .ok_or_else(|| format!("File {} has no chunk {}", path, index))?;
+    chunk_manager::drop_replica(&uuid, server);
+    Ok(())
+}
+
+fn apply_directory_create(path: &str, metadata: Metadata) -> Result<(), String> {
+    let (parent_dir, _) = split_path(path);
+
+    let mut guard = DIR_MAP.inner.lock()
+        .map_err(|_| "Failed to acquire lock on DIR_MAP".to_string())?;
+    let map = guard.as_mut().ok_or("DIR_MAP not initialized")?;
+
+    if map.contains_key(path) {
+        return Err(DIR_ALREADY_EXIST.to_string());
+    }
+    let parent_name = map
+        .get(parent_dir)
+        .ok_or_else(|| format!("{} : {}", NO_DIR_EXIST, parent_dir))?
+        .read().unwrap().dir_name.clone();
+
+    let node = DirectoryNode::new(path.to_string(), metadata, parent_name);
+    map.insert(path.to_string(), Arc::new(RwLock::new(node)));
+    Ok(())
+}
+
+/*
+*   Removes a directory, all its descendant directories and their
+*   files from the namespace in one step, then schedules every chunk
+*   they referenced for deletion on the chunkservers.
+*/
+fn apply_directory_delete(path: &str) -> Result<(), String> {
+    let prefix = format!("{}/", path);
+    let (parent_dir, dir_name) = split_path(path);
+
+    let removed = {
+        let mut guard = DIR_MAP.inner.lock()
+            .map_err(|_| "Failed to acquire lock on DIR_MAP".to_string())?;
+        let map = guard.as_mut().ok_or("DIR_MAP not initialized")?;
+
+        if path == "/" {
+            return Err("Cannot delete the root directory".to_string());
+        }
+        if !map.contains_key(path) {
+            return Err(format!("{}: {}", NO_DIR_EXIST, path));
+        }
+
+        // Remove directory from its parent
+        if let Some(parent_lock) = map.get(parent_dir) {
+            let mut parent_write = parent_lock
+                .write()
+                .map_err(|_| "Failed to acquire write lock on parent directory".to_string())?;
+            parent_write.files.remove(dir_name);
+        }
+
+        let keys: Vec<String> = map.keys()
+            .filter(|key| key.as_str() == path || key.starts_with(&prefix))
+            .cloned().collect();
+        keys.iter().filter_map(|key| map.remove(key)).collect::<Vec<_>>()
+    };
+
+    let mut chunks = Vec::new();
+    for dir in removed.iter() {
+        let dir_read = dir.read().unwrap();
+        for file in dir_read.files.values() {
+            chunks.extend(file.read().unwrap().chunks.iter().copied());
+        }
+        println!("Directory '{}' deleted with {} files", dir_read.dir_name, dir_read.files.len());
+    }
+    schedule_chunk_deletion(&chunks);
+    Ok(())
+}
+
+/*
+*   Moves a file node from one directory to another (or renames it
+*   within its directory). Only the node moves, its chunks are kept.
+*/
+fn apply_file_rename(from: &str, to: &str) -> Result<(), String> {
+    let (from_dir, from_name) = split_path(from);
+    let (to_dir, to_name) = split_path(to);
+
+    let guard = DIR_MAP.inner.lock()
+        .map_err(|_| "Failed to acquire lock on DIR_MAP".to_string())?;
+    let map = guard.as_ref().ok_or("DIR_MAP not initialized")?;
+
+    if map.contains_key(to) {
+        return Err(format!("{}: {}", DIR_ALREADY_EXIST, to));
+    }
+    let source = map.get(from_dir)
+        .ok_or_else(|| format!("{}: {}", NO_DIR_EXIST, from_dir))?.clone();
+    let target = map.get(to_dir)
+        .ok_or_else(|| format!("{}: {}", NO_DIR_EXIST, to_dir))?.clone();
+
+    let file = if Arc::ptr_eq(&source, &target) {
+        let mut dir_write = source.write().unwrap();
+        if dir_write.files.contains_key(to_name) {
+            return Err(format!("{}: {}", FILE_ALREADY_EXIST, to));
+        }
+        let file = dir_write.files.remove(from_name)
+            .ok_or_else(|| format!("{}: {}", NO_FILE_EXIST, from))?;
+        dir_write.files.insert(to_name.to_string(), file.clone());
+        file
+    } else {
+        // lock both directories in path order, like the path locks
+        let (mut source_write, mut target_write);
+        if from_dir < to_dir {
+            source_write = source.write().unwrap();
+            target_write = target.write().unwrap();
+        } else {
+            target_write = target.write().unwrap();
+            source_write = source.write().unwrap();
+        }
+        if target_write.files.contains_key(to_name) {
+            return Err(format!("{}: {}", FILE_ALREADY_EXIST, to));
+        }
+        let file = source_write.files.remove(from_name)
+            .ok_or_else(|| format!("{}: {}", NO_FILE_EXIST, from))?;
+        target_write.files.insert(to_name.to_string(), file.clone());
+        file
+    };
+
+    let mut file_write = file.write().unwrap();
+    file_write.file_name = to_name.to_string();
+    file_write.file_parent = to_dir.to_string();
+    Ok(())
+}
+
+/*
+*   Moves a directory and its whole subtree. DIR_MAP is keyed by
+*   full path, so every descendant directory is re-keyed under the
+*   new path while DIR_MAP is locked, which makes the move atomic.
+*/
+fn apply_directory_rename(from: &str, to: &str) -> Result<(), String> {
+    let (to_parent, to_name) = split_path(to);
+    let prefix = format!("{}/", from);
+
+    let mut guard = DIR_MAP.inner.lock()
+        .map_err(|_| "Failed to acquire lock on DIR_MAP".to_string())?;
+    let map = guard.as_mut().ok_or("DIR_MAP not initialized")?;
+
+    if from == "/" || to.starts_with(&prefix) {
+        return Err(format!("Cannot move '{}' into '{}'", from, to));
+    }
+    if !map.contains_key(from) {
+        return Err(format!("{}: {}", NO_DIR_EXIST, from));
+    }
+    if map.contains_key(to) {
+        return Err(format!("{}: {}", DIR_ALREADY_EXIST, to));
+    }
+    let parent = map.get(to_parent)
+        .ok_or_else(|| format!("{}: {}", NO_DIR_EXIST, to_parent))?;
+    if parent.read().unwrap().files.contains_key(to_name) {
+        return Err(format!("{}: {}", FILE_ALREADY_EXIST, to));
+    }
+
+    let keys: Vec<String> = map.keys()
+        .filter(|key| key.as_str() == from || key.starts_with(&prefix))
+        .cloned().collect();
+    for key in keys {
+        let node = map.remove(&key).unwrap();
+        let new_key = format!("{}{}", to, &key[from.len()..]);
+        {
+            let mut dir_write = node.write().unwrap();
+            dir_write.dir_parent = if key == from {
+                to_parent.to_string()
+            } else {
+                format!("{}{}", to, &dir_write.dir_parent[from.len()..])
+            };
+            dir_write.dir_name = new_key.clone();
+            for file in dir_write.files.values() {
+                file.write().unwrap().file_parent = new_key.clone();
+            }
+        }
+        map.insert(new_key, node);
+    }
+    Ok(())
+}
+
+/*
+*   Changes the metadata of a file or directory in place.
+*/
+fn apply_update_metadata(path: &str, update: impl FnOnce(&mut Metadata)) -> Result<(), String> {
+    if let Some(dir) = DIR_MAP.get(&path.to_string()) {
+        update(&mut dir.write().unwrap().dir_metadata);
+        return Ok(());
+    }
+
+    let (directory, filename) = split_path(path);
+    let dir = DIR_MAP.get(&directory.to_string())
+        .ok_or_else(|| format!("{}: {}", NO_DIR_EXIST, directory))?;
+    let dir_read = dir.read().unwrap();
+    let file = dir_read.files.get(filename)
+        .ok_or_else(|| format!("{}: {}", NO_FILE_EXIST, path))?;
+    update(&mut file.write().unwrap().file_metadata);
+    Ok(())
+}
+
+/////////////////////////////////////////////////////
+// Permissions
+
+fn has_access(metadata: &Metadata, user: &str, access: i32) -> bool {
+    if user == SUPERUSER {
+        return true;
+    }
+    let bits = if metadata.owner == user {
+        metadata.permission >> 6
+    } else if metadata.group == user {
+        metadata.permission >> 3
+    } else {
+        metadata.permission
     };
-    println!("dir {}, file {}", directory, filename);
+    bits & access == access
+}
+
+/*
+*   Metadata of the directory or file at a path.
+*/
+fn node_metadata(path: &str) -> Option<Metadata> {
+    if let Some(dir) = DIR_MAP.get(&path.to_string()) {
+        return Some(dir.read().unwrap().dir_metadata.clone());
+    }
+    file_info(path).map(|file| file.file_metadata)
+}
+
+/*
+*   Checks that the user may search (execute) every ancestor
+*   directory of the path and has `access` on the path itself.
+*/
+fn check_access(path: &str, user: &str, access: i32) -> Result<(), Error> {
+    for ancestor in lock_manager::ancestors(path) {
+        let metadata = node_metadata(&ancestor)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{}: {}", NO_DIR_EXIST, ancestor)))?;
+        if !has_access(&metadata, user, EXECUTE) {
+            return Err(Error::new(ErrorKind::PermissionDenied,
+                                  format!("{}: {}", PERMISSION_DENIED, ancestor)));
+        }
+    }
+    let metadata = node_metadata(path)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{}: {}", NO_FILE_EXIST, path)))?;
+    if !has_access(&metadata, user, access) {
+        return Err(Error::new(ErrorKind::PermissionDenied,
+                              format!("{}: {}", PERMISSION_DENIED, path)));
+    }
+    Ok(())
+}
+
+/*
+*   Checks that the user may add or remove entries of the
+*   directory holding the path.
+*/
+fn check_parent_access(path: &str, user: &str) -> Result<(), Error> {
+    let (parent, _) = split_path(path);
+    check_access(parent, user, WRITE | EXECUTE)
+}
+
+/*
+*   Checks that the user may remove the entries of the directory
+*   and of every directory below it, as a recursive delete does,
+*   and has `file_access` on every file below it.
+*/
+fn check_subtree_access(path: &str, user: &str, file_access: i32) -> Result<(), Error> {
+    check_access(path, user, WRITE | EXECUTE)?;
+    let prefix = format!("{}/", path.trim_end_matches('/'));
+    let denied = |name: &str| Error::new(ErrorKind::PermissionDenied,
+                                         format!("{}: {}", PERMISSION_DENIED, name));
+    let guard = DIR_MAP.inner.lock().map_err(|_| Error::other("Failed to acquire lock on DIR_MAP"))?;
+    let map = guard.as_ref().ok_or_else(|| Error::other("DIR_MAP not initialized"))?;
+    for (name, dir) in map.iter() {
+        if name != path && !name.starts_with(&prefix) {
+            continue;
+        }
+        let dir = dir.read().unwrap();
+        if !has_access(&dir.dir_metadata, user, WRITE | EXECUTE) {
+            return Err(denied(name));
+        }
+        for (filename, file) in dir.files.iter() {
+            if !has_access(&file.read().unwrap().file_metadata, user, file_access) {
+                return Err(denied(&format!("{}/{}", name.trim_end_matches('/'), filename)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/////////////////////////////////////////////////////
+// Path Lookup
+
+pub fn file_lookup(path: String, user: &str) -> Result<(u64, u64, Vec<Uuid>), String>{
+    let _locks = lock_manager::read_lock(&path);
+    check_access(&path, user, READ).map_err(|e| e.to_string())?;
+    let (directory, filename) = split_path(&path);
     if let Some(dir) = DIR_MAP.get(&directory.to_string()) {
-        // println!("this is dir : {:?}", dir);
         if let Some(file) = dir.read().unwrap().files.get(filename) {
-            return Ok(file.read().unwrap().chunks.clone());
+            let file = file.read().unwrap();
+            Ok((file.file_metadata.size() as u64, file.chunk_size, file.chunks.clone()))
         } else {
-            return Err(format!("{}",NO_FILE_EXIST));
+            Err(NO_FILE_EXIST.to_string())
         }
     } else {
-        return Err(format!("{}",NO_DIR_EXIST));
+        Err(NO_DIR_EXIST.to_string())
+    }
+}
+
+fn file_info(path: &str) -> Option<FileInfo> {
+    let (directory, filename) = split_path(path);
+    let dir = DIR_MAP.get(&directory.to_string())?;
+    let dir_read = dir.read().unwrap();
+    let file = dir_read.files.get(filename)?;
+    let info = file.read().unwrap().serialize();
+    Some(info)
+}
+
+/*
+*   The replication given for a new file or directory, or
+*   else the one of the directory it is created in.
+*/
+fn new_replication(directory: &str, replication: Option<usize>) -> Result<usize, Error> {
+    match replication {
+        Some(replication) => check_replication(replication),
+        None => Ok(DIR_MAP.get(&directory.to_string())
+            .map(|dir| dir.read().unwrap().dir_metadata.replication)
+            .unwrap_or(chunk_manager::REPLICATION_FACTOR)),
+    }
+}
+
+/*
+*   The erasure coding given for a new file or directory,
+*   or else the one of the directory it is created in.
+*/
+fn new_erasure(directory: &str, erasure: Option<ErasureCoding>)
+               -> Result<Option<ErasureCoding>, Error> {
+    match erasure {
+        Some(erasure) => check_erasure(erasure).map(Some),
+        None => Ok(DIR_MAP.get(&directory.to_string())
+            .and_then(|dir| dir.read().unwrap().dir_metadata.erasure)),
+    }
+}
+
+fn check_erasure(erasure: ErasureCoding) -> Result<ErasureCoding, Error> {
+    if erasure.data >= 1 && erasure.parity >= 1 && erasure.fragments() <= MAX_FRAGMENTS {
+        Ok(erasure)
+    } else {
+        Err(Error::new(ErrorKind::InvalidInput,
+                       format!("Erasure coding needs data and parity fragments, at most {}",
+                               MAX_FRAGMENTS)))
+    }
+}
+
+fn check_replication(replication: usize) -> Result<usize, Error> {
+    if (1..=MAX_REPLICATION).contains(&replication) {
+        Ok(replication)
+    } else {
+        Err(Error::new(ErrorKind::InvalidInput,
+                       format!("Replication must be between 1 and {}", MAX_REPLICATION)))
     }
 }
 
 ////////////////////////////////////////////////////
-/// File Operations
+// File Operations
 
 /*
 *   Example : file_create(/foo/bar.txt)
 */
-pub fn file_create(path: String) -> Result<FileInfo, Error>{
-    let (directory, filename) = match path.rsplit_once('/') {
-        Some((dir, name)) if dir.is_empty() => ("/", name),
-        Some((dir, name)) => (dir, name),
-        None => ("/", path.as_str()),
-    };
+pub fn file_create(path: String, user: &str, mode: Option<i32>, replication: Option<usize>,
+                   erasure: Option<ErasureCoding>) -> Result<FileInfo, Error>{
+    let _locks = lock_manager::write_lock(&path);
+    let (directory, filename) = split_path(&path);
+    check_parent_access(&path, user)?;
+    let replication = new_replication(directory, replication)?;
+    let erasure = new_erasure(directory, erasure)?;
 
     // Get directory lock first
     if let Some(dir) = DIR_MAP.get(&directory.to_string()) {
@@ -321,123 +901,250 @@ pub fn file_create(path: String) -> Result<FileInfo, Error>{
             Ok(guard) => guard,
             Err(_) => {
                 println!("Failed to acquire read lock on directory");
-                return Err(Error::new(std::io::ErrorKind::Other,
-                                      "Failed to acquire read lock on directory"));
+                return Err(Error::other("Failed to acquire read lock on directory"));
             }
         };
 
-        if dir_read.files.get(filename).is_some() {
+        if dir_read.files.contains_key(filename) {
             println!("{}", FILE_ALREADY_EXIST);
-            return Err(Error::new(std::io::ErrorKind::Other, FILE_ALREADY_EXIST));
+            return Err(Error::other(FILE_ALREADY_EXIST));
         }
 
         // Drop read lock before creating file
         drop(dir_read);
 
-        let m = Metadata::new(FILE_SIZE, 0x666, "1".to_string(), "user".to_string());
-        
-        match FileNode::new(filename.to_string(), directory.to_string(), m) {
-            Ok(file) => {
-                save_dir_state();
+        let mut m = Metadata::new(FILE_SIZE, mode.unwrap_or(DEFAULT_FILE_MODE),
+                                  user.to_string(), user.to_string());
+        m.replication = replication;
+        m.erasure = erasure;
+        let operation = Operation::FileCreate { path: path.clone(), metadata: m };
+
+        match log_manager::commit(operation, apply_operation)? {
+            Ok(()) => {
                 println!("{}", CREATED_FILE_SUCCESSFULLY);
-                Ok(file.serialize())
+                file_info(&path).ok_or_else(|| Error::other(NO_FILE_EXIST))
             }
             Err(e) => {
                 println!("Failed to create file: {}", filename);
-                Err(Error::new(std::io::ErrorKind::Other, "Failed to create file"))
+                Err(Error::other(e))
             }
         }
-        
+
     } else {
         println!("{}", NO_DIR_EXIST);
-        return Err(Error::new(std::io::ErrorKind::Other, NO_DIR_EXIST));
+        Err(Error::other(NO_DIR_EXIST))
     }
 }
 
-pub fn file_delete(path: String) -> Result<(), String> {
-    let (directory, filename) = match path.rsplit_once('/') {
-        Some((dir, name)) if dir.is_empty() => ("/", name),
-        Some((dir, name)) => (dir, name),
-        None => ("/", path.as_str()),
-    };
+pub fn file_delete(path: String, user: &str) -> Result<(), String> {
+    let _locks = lock_manager::write_lock(&path);
+    let (directory, filename) = split_path(&path);
+    check_parent_access(&path, user).map_err(|e| e.to_string())?;
 
     let dir_lock = DIR_MAP
         .get(&directory.to_string())
         .ok_or_else(|| format!("{}: {}", NO_DIR_EXIST, directory))?;
 
-    let mut dir_write = dir_lock
-        .write()
-        .map_err(|_| "Failed to acquire write lock on directory".to_string())?;
-
-    if dir_write.files.remove(filename).is_some() {
-        save_dir_state();
-        println!("File '{}' deleted successfully", filename);
-        Ok(())
-    } else {
-        Err(format!("{}: {}", NO_FILE_EXIST, filename))
+    if !dir_lock.read().unwrap().files.contains_key(filename) {
+        return Err(format!("{}: {}", NO_FILE_EXIST, filename));
     }
+
+    let operation = Operation::FileDelete { path: path.clone() };
+    log_manager::commit(operation, apply_operation).map_err(|e| e.to_string())??;
+    println!("File '{}' deleted successfully", filename);
+    Ok(())
 }
 
 /*
- *      Write to a file.
- *      1. Allocate some chunks according to the
- *         chunkmanagers best fit chunkservers
+ *      Write `size` bytes to a file.
+ *      1. Allocate ceil(size / chunk size) chunks according to
+ *         the chunkmanagers best fit chunkservers, reusing the
+ *         chunks the file already has at those indexes (except
+ *         for erasure-coded files, which get new fragments)
  *
- *      2. Update chunkservers to tell them the
+ *      2. Log the allocation so that the chunk handles
+ *         survive a master crash.
+ *
+ *      3. Update chunkservers to tell them the
  *         chunk handles.
  *
  *
  */
-pub fn file_write(path: String, size: usize) -> Result<Vec<(Uuid,String)>, String> {
-    let (directory, filename) = match path.rsplit_once('/') {
-        Some((dir, name)) if dir.is_empty() => ("/", name),
-        Some((dir, name)) => (dir, name),
-        None => ("/", path.as_str()),
-    };
+pub fn file_write(path: String, size: u64, user: &str) -> Result<FileChunks, String> {
+    let _locks = lock_manager::write_lock(&path);
+    let (directory, filename) = split_path(&path);
+    check_access(&path, user, WRITE).map_err(|e| e.to_string())?;
 
     let parent = DIR_MAP
         .get(&directory.to_string())
         .ok_or_else(|| format!("Directory '{}' does not exist", directory))?;
 
-    let dir_read = match parent.read() {
-        Ok(guard) => guard,
-        Err(e) => {
-            return Err(e.to_string());
+    let chunk_size = chunk_manager::chunk_size();
+    let dir_read = parent.read().map_err(|e| e.to_string())?;
+    let (current, replication, erasure) = match dir_read.files.get(filename) {
+        Some(file) => {
+            let file = file.read().unwrap();
+            // chunks of another size cannot be reused, nor
+            // fragments, which are only written once
+            let current = match file.chunk_size == chunk_size && file.file_metadata.erasure.is_none() {
+                true => file.chunks.clone(),
+                false => Vec::new(),
+            };
+            (current, file.file_metadata.replication, file.file_metadata.erasure)
         }
+        None => return Err(format!("No such file {} exists", filename)),
     };
+    drop(dir_read);
 
-    let file: &Arc<RwLock<FileNode>> = dir_read
-        .files
-        .get(filename)
-        .ok_or_else(|| format!("No such file {} exists", filename))?;
+    // keep the chunks that are still in range, allocate the missing ones
+    let chunks: Vec<(Uuid, Vec<String>)> = (0..chunk_count(size))
+        .map(|index| match current.get(index).map(|uuid| (*uuid, get_replicas(uuid))) {
+            Some((uuid, servers)) if !servers.is_empty() => {
+                placement_manager::record_writes(&servers);
+                Ok((uuid, servers))
+            }
+            _ => allocate_chunks(erasure.map_or(replication, |erasure| erasure.fragments())),
+        })
+        .collect::<Result<_, String>>()?;
+
+    let handles: Vec<Uuid> = chunks.iter().map(|(uuid, _)| *uuid).collect();
+    let operation = Operation::FileWrite { path: path.clone(), size, chunk_size, chunks };
+    log_manager::commit(operation, apply_operation).map_err(|e| e.to_string())??;
+    if erasure.is_none() {
+        for uuid in handles.iter() {
+            grant_lease(uuid);
+        }
+    }
 
-    // parent.read().unwrap().files.get()
-    let chunks = write_chunks(size);
-    file.write().unwrap().chunks.push(chunks.iter().map(|x| x.0).collect::<Vec<Uuid>>());
-    save_dir_state();
-    Ok(chunks)
+    // the versions the replicas have to be written with, and their primaries
+    Ok(FileChunks {
+        size,
+        chunk_size,
+        chunks: handles.iter().map(get_chunk_info).collect(),
+    })
 }
 
-pub fn file_read(path: String) -> Result<Vec<(Uuid, String)>, String> {
-    let chunks = file_lookup(path)?;
-    // currently reading from the first chunk
-    let tuples = get_chunks(chunks[0].clone());
-    Ok(tuples)
+/*
+*   Returns the size of a file, its chunk size and the
+*   locations of each of its chunks.
+*/
+pub fn file_read(path: String, user: &str) -> Result<FileChunks, String> {
+    let (size, chunk_size, chunks) = file_lookup(path, user)?;
+    Ok(FileChunks {
+        size,
+        chunk_size,
+        chunks: chunks.iter().map(get_chunk_info).collect(),
+    })
+}
+
+/*
+*   Every chunk in the namespace, as
+*   (file path, chunk index, chunk handle).
+*/
+pub fn chunk_sets() -> Vec<(String, usize, Uuid)> {
+    let guard = DIR_MAP.inner.lock().unwrap();
+    let map = match guard.as_ref() {
+        Some(map) => map,
+        None => return Vec::new(),
+    };
+    let mut sets = Vec::new();
+    for (dir_name, dir) in map.iter() {
+        for (file_name, file) in dir.read().unwrap().files.iter() {
+            let path = match dir_name.as_str() {
+                "/" => format!("/{}", file_name),
+                _ => format!("{}/{}", dir_name, file_name),
+            };
+            for (index, uuid) in file.read().unwrap().chunks.iter().enumerate() {
+                sets.push((path.clone(), index, *uuid));
+            }
+        }
+    }
+    sets
+}
+
+/*
+*   Records a replica of chunk `uuid`, at index `index` of a file,
+*   copied to `server`. Fails when the chunk changed since the copy
+*   started (the file was written, moved or deleted), the copy is
+*   then useless.
+*/
+pub fn replace_replica(path: String, index: usize, uuid: Uuid, old: Option<String>,
+                       server: String, version: u64) -> Result<(), String> {
+    let _locks = lock_manager::write_lock(&path);
+    let (directory, filename) = split_path(&path);
+    let current = DIR_MAP.get(&directory.to_string())
+        .and_then(|dir| dir.read().unwrap().files.get(filename)
+            .and_then(|file| file.read().unwrap().chunks.get(index).copied()));
+    if current != Some(uuid) {
+        return Err(format!("Chunk {} of {} no longer exists", index, path));
+    }
+    let servers = chunk_manager::chunk_servers(&uuid);
+    if servers.contains(&server) || old.as_ref().is_some_and(|old| !servers.contains(old)) {
+        return Err(format!("Replicas of chunk {} of {} changed", index, path));
+    }
+    if chunk_manager::chunk_version(&uuid) != Some(version) {
+        return Err(format!("Chunk {} of {} was written during the copy", index, path));
+    }
+
+    let operation = Operation::ReplaceReplica { path, index, old, server, version };
+    log_manager::commit(operation, apply_operation).map_err(|e| e.to_string())?
+}
+
+/*
+*   Records fragment `fragment` of chunk `uuid`, at index `index` of
+*   an erasure-coded file, rebuilt or copied on `server`. Fails when
+*   the chunk changed since (the file was written, moved or deleted).
+*/
+pub fn replace_fragment(path: String, index: usize, uuid: Uuid, fragment: usize,
+                        old: Option<String>, server: String, version: u64) -> Result<(), String> {
+    let _locks = lock_manager::write_lock(&path);
+    let (directory, filename) = split_path(&path);
+    let current = DIR_MAP.get(&directory.to_string())
+        .and_then(|dir| dir.read().unwrap().files.get(filename)
+            .and_then(|file| file.read().unwrap().chunks.get(index).copied()));
+    if current != Some(uuid) {
+        return Err(format!("Chunk {} of {} no longer exists", index, path));
+    }
+    let servers = chunk_manager::chunk_servers(&uuid);
+    if servers.contains(&server) || old.as_ref().is_some_and(|old| !servers.contains(old)) {
+        return Err(format!("Fragments of chunk {} of {} changed", index, path));
+    }
+    if chunk_manager::chunk_version(&uuid) != Some(version) {
+        return Err(format!("Chunk {} of {} was written during the rebuild", index, path));
+    }
+
+    let operation = Operation::ReplaceFragment { path, index, fragment, old, server, version };
+    log_manager::commit(operation, apply_operation).map_err(|e| e.to_string())?
 }
 
-pub fn file_read_all(path: String) -> Result<Vec<(Uuid, String)>, String> {
-    let chunks = file_lookup(path)?;
-    let mut tuples = Vec::new();
-    for chunk in chunks {
-        tuples.append(&mut get_chunks(chunk));
+/*
+*   Removes the replica on `server` of chunk `uuid`, at index `index`
+*   of a file, that has more live replicas than its replication.
+*/
+pub fn remove_replica(path: String, index: usize, uuid: Uuid, server: String) -> Result<(), String> {
+    let _locks = lock_manager::write_lock(&path);
+    let (directory, filename) = split_path(&path);
+    let current = DIR_MAP.get(&directory.to_string())
+        .and_then(|dir| dir.read().unwrap().files.get(filename)
+            .and_then(|file| file.read().unwrap().chunks.get(index).copied()));
+    if current != Some(uuid) {
+        return Err(format!("Chunk {} of {} no longer exists", index, path));
+    }
+    let kept = crate::replication_manager::kept(&get_replicas(&uuid));
+    if !kept.contains(&server) || kept.len() <= chunk_manager::chunk_replication(&uuid) {
+        return Err(format!("Replicas of chunk {} of {} changed", index, path));
     }
-    Ok(tuples)
+
+    let operation = Operation::RemoveReplica { path, index, server };
+    log_manager::commit(operation, apply_operation).map_err(|e| e.to_string())?
 }
 
 ////////////////////////////////////////////////////
-/// Directory Operations
+// Directory Operations
 
-pub fn list_directory(path: String) -> Result<DirectoryInfo, Error> {
+pub fn list_directory(path: String, user: &str) -> Result<DirectoryInfo, Error> {
+    let _locks = lock_manager::read_lock(&path);
+    check_access(&path, user, READ)?;
     if let Some(dir) = DIR_MAP.get(&path) {
         println!("------------------------------------------");
         // let directory_files = dir.read().unwrap().files.clone();
@@ -445,84 +1152,311 @@ pub fn list_directory(path: String) -> Result<DirectoryInfo, Error> {
         Ok(dir.read().unwrap().serialize())
     } else {
         println!("{}", NO_DIR_EXIST);
-        Err(Error::new(std::io::ErrorKind::Other, NO_DIR_EXIST))
+        Err(Error::other(NO_DIR_EXIST))
     }
 }
 
-pub fn directory_create(path: String) -> String {
-    /*
-     *   Call logger and wait to log operation
-     */
-    if let Some(new_dir) = DIR_MAP.get(&path) {
+pub fn directory_create(path: String, user: &str, mode: Option<i32>,
+                        replication: Option<usize>, erasure: Option<ErasureCoding>) -> String {
+    let _locks = lock_manager::write_lock(&path);
+    if let Err(e) = check_parent_access(&path, user) {
+        return e.to_string();
+    }
+    let (parent_dir, _) = split_path(&path);
+    let replication = match new_replication(parent_dir, replication) {
+        Ok(replication) => replication,
+        Err(e) => return e.to_string(),
+    };
+    let erasure = match new_erasure(parent_dir, erasure) {
+        Ok(erasure) => erasure,
+        Err(e) => return e.to_string(),
+    };
+    if DIR_MAP.get(&path).is_some() {
         println!("{}", DIR_ALREADY_EXIST);
         DIR_ALREADY_EXIST.to_string()
-    } else {
-        let (parent_dir, dir) = match path.rsplit_once('/') {
-            Some((dir, name)) if dir.is_empty() => ("/", name),
-            Some((dir, name)) => (dir, name),
-            None => ("/", path.as_str()),
-        };
-        if let Some(parent) = DIR_MAP.get(&parent_dir.to_string()) {
-            /*
-             *      This metadata should be given by the client but until then
-             *      add a dummy metadata
-             */
-            let m = Metadata::new(
-                FILE_SIZE, 0x666, "1".to_string(), "user".to_string());
-            DirectoryNode::new(path, m, parent.read().unwrap().dir_name.to_string());
-            save_dir_state();
-            println!("{}", CREATED_DIR_SUCCESSFULLY);
-            CREATED_DIR_SUCCESSFULLY.to_string()
-        } else {
-            println!("{} : {}", NO_DIR_EXIST, parent_dir);
-            NO_DIR_EXIST.to_string()
+    } else if DIR_MAP.get(&parent_dir.to_string()).is_some() {
+        let mut m = Metadata::new(DIR_SIZE, mode.unwrap_or(DEFAULT_DIR_MODE),
+                                  user.to_string(), user.to_string());
+        m.replication = replication;
+        m.erasure = erasure;
+        let operation = Operation::DirectoryCreate { path, metadata: m };
+        match log_manager::commit(operation, apply_operation) {
+            Ok(Ok(())) => {
+                println!("{}", CREATED_DIR_SUCCESSFULLY);
+                CREATED_DIR_SUCCESSFULLY.to_string()
+            }
+            Ok(Err(e)) => e,
+            Err(e) => e.to_string(),
         }
+    } else {
+        println!("{} : {}", NO_DIR_EXIST, parent_dir);
+        NO_DIR_EXIST.to_string()
     }
 }
 
-pub fn directory_delete(path: String) -> Result<(), String> {
-    /*
-     *   Call logger and wait to log operation
-     */
+/*
+*   Deletes a directory with everything below it.
+*/
+pub fn directory_delete(path: String, user: &str) -> Result<(), String> {
+    let _locks = lock_manager::write_lock(&path);
+    if path == "/" {
+        return Err("Cannot delete the root directory".to_string());
+    }
+    if DIR_MAP.get(&path).is_none() {
+        return Err(format!("{}: {}", NO_DIR_EXIST, path));
+    }
+    check_parent_access(&path, user).map_err(|e| e.to_string())?;
+    check_subtree_access(&path, user, 0).map_err(|e| e.to_string())?;
 
-    let dir_lock = DIR_MAP
-        .get(&path)
-        .ok_or_else(|| format!("{}: {}", NO_DIR_EXIST, path))?;
+    let operation = Operation::DirectoryDelete { path: path.clone() };
+    log_manager::commit(operation, apply_operation).map_err(|e| e.to_string())??;
+    println!("Directory '{}' deleted successfully", path);
 
-    // Recursively delete files and subdirectories
-    {
-        let mut dir_write = dir_lock
-            .write()
-            .map_err(|_| "Failed to acquire write lock on directory".to_string())?;
+    Ok(())
+}
 
-        // Delete all files in the directory
-        for file_name in dir_write.files.keys().cloned().collect::<Vec<_>>() {
-            dir_write.files.remove(&file_name);
-            println!("File '{}' deleted successfully from directory '{}'", file_name, path);
-        }
+/*
+*   Renames or moves a file, e.g. file_rename(/a/x.txt, /b/y.txt).
+*   Fails if the destination already exists.
+*/
+pub fn file_rename(from: String, to: String, user: &str) -> Result<(), Error> {
+    let _locks = lock_manager::lock_paths(&[], &[&from, &to]);
+    let (to_dir, to_name) = split_path(&to);
+
+    if file_info(&from).is_none() {
+        return Err(Error::new(ErrorKind::NotFound, format!("{}: {}", NO_FILE_EXIST, from)));
+    }
+    check_parent_access(&from, user)?;
+    check_parent_access(&to, user)?;
+    let target = DIR_MAP.get(&to_dir.to_string())
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{}: {}", NO_DIR_EXIST, to_dir)))?;
+    if target.read().unwrap().files.contains_key(to_name) || DIR_MAP.get(&to).is_some() {
+        return Err(Error::new(ErrorKind::AlreadyExists, format!("{}: {}", FILE_ALREADY_EXIST, to)));
     }
 
-    // Remove directory from its parent
-    let (parent_dir, dir_name) = match path.rsplit_once('/') {
-        Some((dir, name)) if dir.is_empty() => ("/", name),
-        Some((dir, name)) => (dir, name),
-        None => ("/", path.as_str()),
-    };
+    let operation = Operation::FileRename { from: from.clone(), to: to.clone() };
+    log_manager::commit(operation, apply_operation)?.map_err(Error::other)?;
+    println!("File '{}' moved to '{}'", from, to);
+    Ok(())
+}
 
-    if let Some(parent_lock) = DIR_MAP.get(&parent_dir.to_string()) {
-        let mut parent_write = parent_lock
-            .write()
-            .map_err(|_| "Failed to acquire write lock on parent directory".to_string())?;
+/*
+*   Renames or moves a directory with everything below it,
+*   e.g. directory_rename(/a/b, /c). Fails if the destination
+*   already exists or is inside the source.
+*/
+pub fn directory_rename(from: String, to: String, user: &str) -> Result<(), Error> {
+    let _locks = lock_manager::lock_paths(&[], &[&from, &to]);
+    let (to_parent, to_name) = split_path(&to);
 
-        if parent_write.files.remove(dir_name).is_none() {
-            println!("Warning: Directory '{}' was not found in parent '{}'", dir_name, parent_dir);
-        }
+    if from == "/" || to.starts_with(&format!("{}/", from)) {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("Cannot move '{}' into '{}'", from, to)));
+    }
+    if DIR_MAP.get(&from).is_none() {
+        return Err(Error::new(ErrorKind::NotFound, format!("{}: {}", NO_DIR_EXIST, from)));
+    }
+    check_parent_access(&from, user)?;
+    check_parent_access(&to, user)?;
+    let parent = DIR_MAP.get(&to_parent.to_string())
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{}: {}", NO_DIR_EXIST, to_parent)))?;
+    if DIR_MAP.get(&to).is_some() || parent.read().unwrap().files.contains_key(to_name) {
+        return Err(Error::new(ErrorKind::AlreadyExists, format!("{}: {}", DIR_ALREADY_EXIST, to)));
     }
 
-    // Finally remove the directory itself from the DIR_MAP
-    DIR_MAP.remove(&path);
-    save_dir_state();
-    println!("Directory '{}' deleted successfully", path);
-
+    let operation = Operation::DirectoryRename { from: from.clone(), to: to.clone() };
+    log_manager::commit(operation, apply_operation)?.map_err(Error::other)?;
+    println!("Directory '{}' moved to '{}'", from, to);
     Ok(())
 }
+
+////////////////////////////////////////////////////
+// Metadata Operations
+
+/*
+*   Changes the mode bits of a file or directory.
+*   Only its owner (or the superuser) may do it.
+*/
+pub fn change_mode(path: String, mode: i32, user: &str) -> Result<(), Error> {
+    let _locks = lock_manager::write_lock(&path);
+    check_access(&path, user, 0)?;
+    let metadata = node_metadata(&path)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{}: {}", NO_FILE_EXIST, path)))?;
+    if user != SUPERUSER && metadata.owner != user {
+        return Err(Error::new(ErrorKind::PermissionDenied,
+                              format!("{}: {}", PERMISSION_DENIED, path)));
+    }
+
+    let operation = Operation::ChangeMode { path, mode };
+    log_manager::commit(operation, apply_operation)?.map_err(Error::other)
+}
+
+/*
+*   Changes the owner and group of a file or directory.
+*   Only the superuser may do it.
+*/
+pub fn change_owner(path: String, owner: String, group: String, user: &str) -> Result<(), Error> {
+    let _locks = lock_manager::write_lock(&path);
+    if user != SUPERUSER {
+        return Err(Error::new(ErrorKind::PermissionDenied,
+                              format!("{}: {}", PERMISSION_DENIED, path)));
+    }
+    if node_metadata(&path).is_none() {
+        return Err(Error::new(ErrorKind::NotFound, format!("{}: {}", NO_FILE_EXIST, path)));
+    }
+
+    let operation = Operation::ChangeOwner { path, owner, group };
+    log_manager::commit(operation, apply_operation)?.map_err(Error::other)
+}
+
+/*
+*   Changes the replication of a file, or of a directory and
+*   everything below it. The replication manager then adds or
+*   removes replicas of the chunks.
+*/
+pub fn set_replication(path: String, replication: usize, user: &str) -> Result<(), Error> {
+    let _locks = lock_manager::write_lock(&path);
+    // on a directory, the replication of every file below it changes
+    match DIR_MAP.get(&path) {
+        Some(_) => check_subtree_access(&path, user, WRITE)?,
+        None => check_access(&path, user, WRITE)?,
+    }
+    check_replication(replication)?;
+
+    let operation = Operation::SetReplication { path, replication };
+    log_manager::commit(operation, apply_operation)?.map_err(Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> Metadata {
+        Metadata::new(DIR_SIZE, DEFAULT_DIR_MODE, "alice".to_string(), "alice".to_string())
+    }
+
+    #[test]
+    fn test_directory_rename_moves_subtree() {
+        namespace_manager_init();
+        apply_directory_create("/test_rename", metadata()).unwrap();
+        apply_directory_create("/test_rename/a", metadata()).unwrap();
+        apply_directory_create("/test_rename/a/b", metadata()).unwrap();
+        apply_file_create("/test_rename/a/b/x.txt", metadata()).unwrap();
+
+        apply_directory_rename("/test_rename/a", "/test_rename/c").unwrap();
+
+        assert!(DIR_MAP.get(&"/test_rename/a".to_string()).is_none());
+        assert!(DIR_MAP.get(&"/test_rename/a/b".to_string()).is_none());
+        let moved = DIR_MAP.get(&"/test_rename/c/b".to_string()).unwrap();
+        assert_eq!(moved.read().unwrap().dir_parent, "/test_rename/c");
+        let file = file_info("/test_rename/c/b/x.txt").unwrap();
+        assert_eq!(file.file_parent, "/test_rename/c/b");
+    }
+
+    #[test]
+    fn test_rename_fails_when_destination_exists() {
+        namespace_manager_init();
+        apply_directory_create("/test_rename_exists", metadata()).unwrap();
+        apply_file_create("/test_rename_exists/x.txt", metadata()).unwrap();
+        apply_file_create("/test_rename_exists/y.txt", metadata()).unwrap();
+
+        assert!(apply_file_rename("/test_rename_exists/x.txt", "/test_rename_exists/y.txt").is_err());
+        assert!(file_info("/test_rename_exists/x.txt").is_some());
+    }
+
+    #[test]
+    fn test_directory_delete_is_recursive() {
+        namespace_manager_init();
+        chunk_manager::chunk_manager_init();
+        apply_directory_create("/test_delete", metadata()).unwrap();
+        apply_directory_create("/test_delete/a", metadata()).unwrap();
+        apply_directory_create("/test_delete/a/b", metadata()).unwrap();
+        apply_file_create("/test_delete/a/b/x.txt", metadata()).unwrap();
+        let chunk = (Uuid::new_v4(), vec!["localhost:8100".to_string()]);
+        apply_file_write("/test_delete/a/b/x.txt", 10, 64, std::slice::from_ref(&chunk)).unwrap();
+        assert_eq!(chunk_manager::chunk_servers(&chunk.0), chunk.1);
+
+        // bob may not remove the entries of alice's directories
+        apply_update_metadata("/test_delete", |metadata| metadata.permission = 0o777).unwrap();
+        assert!(check_subtree_access("/test_delete", "bob", 0).is_err());
+        assert!(check_subtree_access("/test_delete", "alice", 0).is_ok());
+
+        apply_directory_delete("/test_delete").unwrap();
+
+        assert!(DIR_MAP.get(&"/test_delete".to_string()).is_none());
+        assert!(DIR_MAP.get(&"/test_delete/a/b".to_string()).is_none());
+        assert!(chunk_manager::chunk_servers(&chunk.0).is_empty());
+    }
+
+    #[test]
+    fn test_check_access() {
+        namespace_manager_init();
+        apply_directory_create("/test_access", metadata()).unwrap();
+        let private = Metadata::new(FILE_SIZE, 0o600, "alice".to_string(), "alice".to_string());
+        apply_file_create("/test_access/x.txt", private).unwrap();
+
+        assert!(check_access("/test_access/x.txt", "alice", READ | WRITE).is_ok());
+        assert!(check_access("/test_access/x.txt", "bob", READ).is_err());
+        assert!(check_access("/test_access/x.txt", SUPERUSER, READ).is_ok());
+
+        // without search permission on the parent the file is unreachable
+        apply_update_metadata("/test_access", |metadata| metadata.permission = 0o700).unwrap();
+        apply_update_metadata("/test_access/x.txt", |metadata| metadata.permission = 0o644).unwrap();
+        assert!(check_access("/test_access/x.txt", "bob", READ).is_err());
+        assert!(check_access("/test_access/x.txt", "alice", READ).is_ok());
+    }
+
+    #[test]
+    fn test_file_write_drops_chunks_out_of_range() {
+        namespace_manager_init();
+        chunk_manager::chunk_manager_init();
+        apply_directory_create("/test_write", metadata()).unwrap();
+        apply_file_create("/test_write/x.txt", metadata()).unwrap();
+        let chunks: Vec<(Uuid, Vec<String>)> = (0..3)
+            .map(|_| (Uuid::new_v4(), vec!["localhost:8100".to_string()]))
+            .collect();
+        apply_file_write("/test_write/x.txt", 150, 64, &chunks).unwrap();
+
+        // shrinking to one chunk keeps the first and leaves the others to gc
+        apply_file_write("/test_write/x.txt", 10, 64, &chunks[..1]).unwrap();
+
+        let file = file_info("/test_write/x.txt").unwrap();
+        assert_eq!(file.chunks, vec![chunks[0].0.to_string()]);
+        assert_eq!(file.file_metadata.size(), 10);
+        assert_eq!(chunk_manager::chunk_version(&chunks[0].0), Some(2));
+        assert_eq!(chunk_manager::chunk_servers(&chunks[1].0), chunks[1].1);
+        assert_eq!(chunk_manager::chunk_servers(&chunks[2].0), chunks[2].1);
+    }
+
+    #[test]
+    fn test_replication_is_inherited_and_set_recursively() {
+        namespace_manager_init();
+        chunk_manager::chunk_manager_init();
+        let mut two = metadata();
+        two.replication = 2;
+        apply_directory_create("/test_rep", two).unwrap();
+        assert_eq!(new_replication("/test_rep", None).unwrap(), 2);
+        assert_eq!(new_replication("/test_rep", Some(5)).unwrap(), 5);
+        assert!(new_replication("/test_rep", Some(0)).is_err());
+
+        apply_directory_create("/test_rep/a", metadata()).unwrap();
+        apply_file_create("/test_rep/a/x.txt", metadata()).unwrap();
+        let uuid = Uuid::new_v4();
+        apply_file_write("/test_rep/a/x.txt", 10, 64, &[(uuid, vec!["localhost:8100".to_string()])]).unwrap();
+        assert_eq!(chunk_manager::chunk_replication(&uuid), 3);
+
+        // bob may write the directories but not alice's file below them
+        for dir in ["/test_rep", "/test_rep/a"] {
+            apply_update_metadata(dir, |metadata| metadata.permission = 0o777).unwrap();
+        }
+        assert!(check_subtree_access("/test_rep", "bob", 0).is_ok());
+        assert!(check_subtree_access("/test_rep", "bob", WRITE).is_err());
+        assert!(check_subtree_access("/test_rep", "alice", WRITE).is_ok());
+
+        apply_set_replication("/test_rep", 1).unwrap();
+        assert_eq!(file_info("/test_rep/a/x.txt").unwrap().file_metadata.replication, 1);
+        assert_eq!(DIR_MAP.get(&"/test_rep/a".to_string()).unwrap().read().unwrap()
+                       .dir_metadata.replication, 1);
+        assert_eq!(chunk_manager::chunk_replication(&uuid), 1);
+    }
+}
@@ -0,0 +1,260 @@
+/*
+*   Chooses the chunkservers new replicas are placed on.
+*
+*   Only alive chunkservers that are not draining are candidates, and of those only the ones
+*   with room for a chunk and at least MIN_FREE_RATIO of their disk
+*   free. The candidates are ranked by a score :
+*       free space ratio
+*       - CHUNK_WEIGHT * chunk count   (relative to the fullest one)
+*       - LOAD_WEIGHT  * recent writes (relative to the busiest one)
+*   and picked from the best down, never two on the same host (also
+*   not on the host of a replica the chunk already has). Ties are
+*   broken at random. The replicas of a chunk end up in at least
+*   MIN_RACKS racks, as far as there are racks with room : once the
+*   remaining replicas are needed for that, only servers in racks the
+*   chunk is not in yet are picked. If fewer candidates qualify than
+*   replicas are needed the placement fails, placing fewer replicas
+*   is an error.
+*
+*   The write load of a server is the number of chunks placed on or
+*   written to it in the last WRITE_LOAD_WINDOW seconds.
+*
+*   Replicas may share a host when DFS_ALLOW_SAME_HOST is set, e.g. to
+*   run several chunkservers on one machine.
+*
+*   Data Structures :
+*       1. Write Load Map       : Map (Server Address -> List<DateTime>)
+*/
+#![allow(unused)]
+
+use crate::chunk_manager;
+use crate::chunk_manager::SERVER_MAP;
+use crate::drain_manager;
+use crate::heartbeat_manager;
+use crate::safe_map::SafeMap;
+use crate::topology_manager;
+use crate::topology_manager::{rack_of, MIN_RACKS};
+use lib::shared::master_chunk_utils::Topology;
+use chrono::{DateTime, Utc};
+use rand::seq::SliceRandom;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static WRITE_LOAD_MAP: SafeMap<String, Vec<DateTime<Utc>>> = SafeMap::new();
+static ALLOW_SAME_HOST: AtomicBool = AtomicBool::new(false);
+
+const MIN_FREE_RATIO: f64 = 0.05;
+const CHUNK_WEIGHT: f64 = 0.5;
+const LOAD_WEIGHT: f64 = 0.5;
+const WRITE_LOAD_WINDOW: i64 = 60;
+const ALLOW_SAME_HOST_ENV: &str = "DFS_ALLOW_SAME_HOST";
+
+/*
+*   A chunkserver replicas can be placed on. The disk
+*   space is unknown for servers that sent no heartbeat.
+*/
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub server: String,
+    pub topology: Topology,
+    pub available_space: Option<u64>,
+    pub total_space: Option<u64>,
+    pub chunk_count: usize,
+    pub recent_writes: usize,
+}
+
+impl Candidate {
+    fn has_room(&self, chunk_size: u64) -> bool {
+        match (self.available_space, self.total_space) {
+            (Some(available), Some(total)) =>
+                available >= chunk_size && available as f64 >= total as f64 * MIN_FREE_RATIO,
+            _ => true,
+        }
+    }
+
+    fn free_ratio(&self) -> f64 {
+        match (self.available_space, self.total_space) {
+            (Some(available), Some(total)) if total > 0 => available as f64 / total as f64,
+            _ => 1.0,
+        }
+    }
+}
+
+pub fn placement_manager_init() {
+    WRITE_LOAD_MAP.init();
+    ALLOW_SAME_HOST.store(std::env::var(ALLOW_SAME_HOST_ENV).is_ok(), Ordering::Relaxed);
+}
+
+/*
+*   Chooses `count` of the candidates, best first. The
+*   other replicas of the chunk are on the `used` servers.
+*/
+pub fn choose(mut candidates: Vec<Candidate>, count: usize, used: &[Topology],
+              chunk_size: u64, same_host: bool) -> Result<Vec<String>, String> {
+    let alive = candidates.len();
+    candidates.retain(|candidate| candidate.has_room(chunk_size));
+    let with_room = candidates.len();
+
+    let max_chunks = candidates.iter().map(|c| c.chunk_count).max().unwrap_or(0).max(1) as f64;
+    let max_writes = candidates.iter().map(|c| c.recent_writes).max().unwrap_or(0).max(1) as f64;
+    let score = |candidate: &Candidate| candidate.free_ratio()
+        - CHUNK_WEIGHT * candidate.chunk_count as f64 / max_chunks
+        - LOAD_WEIGHT * candidate.recent_writes as f64 / max_writes;
+    candidates.shuffle(&mut rand::thread_rng());
+    candidates.sort_by(|a, b| score(b).total_cmp(&score(a)));
+
+    let mut hosts: HashSet<String> = used.iter().map(|t| t.host.clone()).collect();
+    let mut racks: HashSet<(String, String)> = used.iter().map(rack_of).collect();
+    let rack_count = candidates.iter()
+        .map(|candidate| rack_of(&candidate.topology))
+        .chain(racks.iter().cloned())
+        .collect::<HashSet<_>>()
+        .len();
+    let racks_wanted = MIN_RACKS.min(rack_count);
+
+    let mut chosen = Vec::new();
+    while chosen.len() < count {
+        let new_rack = racks_wanted.saturating_sub(racks.len()) >= count - chosen.len();
+        let allowed = |candidate: &Candidate, new_rack: bool|
+            (same_host || !hosts.contains(&candidate.topology.host))
+            && (!new_rack || !racks.contains(&rack_of(&candidate.topology)));
+        // fewer racks beat fewer replicas
+        let position = candidates.iter().position(|c| allowed(c, new_rack))
+            .or_else(|| candidates.iter().position(|c| allowed(c, false)));
+        let candidate = match position {
+            Some(position) => candidates.remove(position),
+            None => break,
+        };
+        hosts.insert(candidate.topology.host.clone());
+        racks.insert(rack_of(&candidate.topology));
+        chosen.push(candidate.server);
+    }
+    if chosen.len() < count {
+        return Err(format!(
+            "Not enough chunkservers to place {} replicas: {} alive, {} with enough free space, \
+             {} on distinct hosts", count, alive, with_room, chosen.len()));
+    }
+    Ok(chosen)
+}
+
+/*
+*   Places `count` new replicas of a chunk whose other replicas are on
+*   `holders`, and records the writes they are about to receive.
+*/
+pub fn place(count: usize, holders: &[String]) -> Result<Vec<String>, String> {
+    let candidates: Vec<Candidate> = heartbeat_manager::alive_servers()
+        .into_iter()
+        .filter(|server| !holders.contains(server) && !drain_manager::is_draining(server))
+        .map(|server| candidate(&server))
+        .collect();
+    let used: Vec<Topology> = holders.iter()
+        .map(|server| topology_manager::topology_of(server))
+        .collect();
+    let chosen = choose(candidates, count, &used, chunk_manager::chunk_size(),
+                        allow_same_host())?;
+    record_writes(&chosen);
+    Ok(chosen)
+}
+
+pub fn allow_same_host() -> bool {
+    ALLOW_SAME_HOST.load(Ordering::Relaxed)
+}
+
+/*
+*   Counts a chunk write on each of the servers.
+*/
+pub fn record_writes(servers: &[String]) {
+    let now = Utc::now();
+    let window = chrono::Duration::seconds(WRITE_LOAD_WINDOW);
+    for server in servers {
+        match WRITE_LOAD_MAP.get(server) {
+            Some(writes) => {
+                let mut writes = writes.write().unwrap();
+                writes.retain(|time| now - *time <= window);
+                writes.push(now);
+            }
+            None => { WRITE_LOAD_MAP.insert(server.clone(), vec![now]); }
+        }
+    }
+}
+
+fn recent_writes(server: &str) -> usize {
+    let window = chrono::Duration::seconds(WRITE_LOAD_WINDOW);
+    let now = Utc::now();
+    WRITE_LOAD_MAP.get(&server.to_string())
+        .map(|writes| writes.read().unwrap().iter().filter(|time| now - **time <= window).count())
+        .unwrap_or(0)
+}
+
+pub fn candidate(server: &str) -> Candidate {
+    let metadata = heartbeat_manager::server_metadata(server);
+    Candidate {
+        server: server.to_string(),
+        topology: topology_manager::topology_of(server),
+        available_space: metadata.as_ref().map(|m| m.disk_info.available_space),
+        total_space: metadata.as_ref().map(|m| m.disk_info.total_space),
+        chunk_count: SERVER_MAP.get(&server.to_string())
+            .map(|uuids| uuids.read().unwrap().len())
+            .unwrap_or(0),
+        recent_writes: recent_writes(server),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(server: &str, rack: &str, available: u64, chunk_count: usize) -> Candidate {
+        Candidate {
+            server: server.to_string(),
+            topology: Topology {
+                rack: rack.to_string(),
+                host: server.split(':').next().unwrap().to_string(),
+                ..Topology::default()
+            },
+            available_space: Some(available),
+            total_space: Some(1000),
+            chunk_count,
+            recent_writes: 0,
+        }
+    }
+
+    #[test]
+    fn test_choose_skips_full_servers_and_shared_hosts() {
+        let candidates = vec![
+            candidate("a:1", "r", 900, 10),
+            candidate("a:2", "r", 800, 0),
+            candidate("b:1", "r", 20, 0),
+            candidate("c:1", "r", 500, 5),
+            candidate("d:1", "r", 500, 0),
+        ];
+
+        let chosen = choose(candidates.clone(), 3, &[], 10, false).unwrap();
+        assert_eq!(chosen, vec!["a:2", "d:1", "c:1"]);
+
+        let used = vec![candidates[4].topology.clone()];
+        let error = choose(candidates.clone(), 3, &used, 10, false).unwrap_err();
+        assert!(error.contains("4 with enough free space"), "{}", error);
+
+        assert_eq!(choose(candidates, 4, &[], 10, true).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_choose_spreads_replicas_over_racks() {
+        let candidates = vec![
+            candidate("a:1", "r1", 900, 0),
+            candidate("b:1", "r1", 800, 0),
+            candidate("c:1", "r1", 700, 0),
+            candidate("d:1", "r2", 100, 0),
+        ];
+
+        let chosen = choose(candidates.clone(), 3, &[], 10, false).unwrap();
+        assert_eq!(chosen, vec!["a:1", "b:1", "d:1"]);
+
+        // the chunk is already in the other rack
+        let used = vec![candidates[3].topology.clone()];
+        assert_eq!(choose(candidates.clone(), 1, &used, 10, false).unwrap(), vec!["a:1"]);
+        let used = vec![candidates[0].topology.clone()];
+        assert_eq!(choose(candidates, 1, &used, 10, false).unwrap(), vec!["d:1"]);
+    }
+}
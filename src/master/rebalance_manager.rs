@@ -0,0 +1,303 @@
+/*
+*   Evens out the chunk data stored on the chunkservers, e.g. after
+*   new (empty) chunkservers joined.
+*
+*   The usage of a server is the size of the replicas it reported in
+*   its heartbeats. Servers holding more than the average by over
+*   `threshold` of it are over-full, those holding less by over that
+*   much are under-full. A run plans moves of replicas from the
+*   fullest servers to under-full ones :
+*       - only alive, not draining servers take part,
+*       - only chunks with all their replicas live, at least their
+*         replication of them and no lease are moved (fragments of
+*         erasure-coded files are not),
+*       - a move never leaves the source with less than the target,
+*         so that replicas do not move back and forth,
+*       - the target is picked like a new replica (see the placement
+*         manager) : another host, and racks the chunk spans are kept,
+*       - at most `budget` bytes are moved per run.
+*   A move copies the replica to the target (peer copy, as a repair
+*   does), logs it in place of the source replica, which then gets
+*   deleted. Moves run one at a time.
+*
+*   The rebalancer runs on demand (/rebalance, a dry run only returns
+*   the plan) and, when DFS_REBALANCE_INTERVAL is set, in the
+*   background every that many seconds. DFS_REBALANCE_THRESHOLD and
+*   DFS_REBALANCE_BUDGET (bytes per run) set the defaults.
+*/
+#![allow(unused)]
+
+use crate::chunk_manager;
+use crate::drain_manager;
+use crate::heartbeat_manager;
+use crate::placement_manager;
+use crate::namespace_manager;
+use crate::replication_manager;
+use crate::topology_manager;
+use lib::shared::master_client_utils::{RebalancePlan, ReplicaMove, ServerUsage};
+use lib::shared::master_chunk_utils::Topology;
+use reqwest::Client;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::time::{sleep, Duration};
+use uuid::Uuid;
+
+static THRESHOLD: AtomicU64 = AtomicU64::new(0);
+static BUDGET: AtomicU64 = AtomicU64::new(DEFAULT_BUDGET);
+static INTERVAL: AtomicU64 = AtomicU64::new(0);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+const DEFAULT_THRESHOLD: f64 = 0.1;
+const DEFAULT_BUDGET: u64 = 1 << 30;
+const THRESHOLD_ENV: &str = "DFS_REBALANCE_THRESHOLD";
+const BUDGET_ENV: &str = "DFS_REBALANCE_BUDGET";
+const INTERVAL_ENV: &str = "DFS_REBALANCE_INTERVAL";
+
+pub fn rebalance_manager_init() {
+    let threshold = match std::env::var(THRESHOLD_ENV) {
+        Ok(value) => match value.parse::<f64>() {
+            Ok(value) if value > 0.0 => value,
+            _ => panic!("{} must be a positive fraction of the average usage", THRESHOLD_ENV),
+        },
+        Err(_) => DEFAULT_THRESHOLD,
+    };
+    THRESHOLD.store(threshold.to_bits(), Ordering::Relaxed);
+    BUDGET.store(env_number(BUDGET_ENV, DEFAULT_BUDGET), Ordering::Relaxed);
+    INTERVAL.store(env_number(INTERVAL_ENV, 0), Ordering::Relaxed);
+}
+
+fn env_number(name: &str, default: u64) -> u64 {
+    match std::env::var(name) {
+        Ok(value) => value.parse::<u64>()
+            .unwrap_or_else(|_| panic!("{} must be a number", name)),
+        Err(_) => default,
+    }
+}
+
+pub fn default_threshold() -> f64 {
+    f64::from_bits(THRESHOLD.load(Ordering::Relaxed))
+}
+
+pub fn default_budget() -> u64 {
+    BUDGET.load(Ordering::Relaxed)
+}
+
+/*
+*   A chunk that can be moved, with the size of its replicas.
+*/
+struct Movable {
+    path: String,
+    index: usize,
+    uuid: Uuid,
+    size: u64,
+    holders: Vec<String>,
+}
+
+fn movable_chunks(sets: Vec<(String, usize, Uuid)>) -> Vec<Movable> {
+    sets.into_iter()
+        .filter_map(|(path, index, uuid)| {
+            let info = chunk_manager::chunk_info(&uuid)?;
+            let holders: Vec<String> = info.replicas.iter().map(|r| r.server.clone()).collect();
+            let movable = info.erasure.is_none()
+                && holders.len() >= chunk_manager::chunk_replication(&uuid)
+                && chunk_manager::get_replicas(&uuid).len() == holders.len()
+                && !holders.iter().any(|server| drain_manager::is_draining(server))
+                && chunk_manager::lease_holder(&uuid).is_none();
+            let size = info.replicas.iter().map(|r| r.size).max().unwrap_or(0);
+            movable.then_some(Movable { path, index, uuid, size, holders })
+        })
+        .collect()
+}
+
+/*
+*   Usage of the servers : bytes and number of the replicas they hold.
+*/
+fn server_usage(servers: &[String], sets: &[(String, usize, Uuid)]) -> BTreeMap<String, (u64, usize)> {
+    let mut usage: BTreeMap<String, (u64, usize)> = servers.iter()
+        .map(|server| (server.clone(), (0, 0)))
+        .collect();
+    for (_, _, uuid) in sets {
+        let replicas = chunk_manager::chunk_info(uuid).map(|info| info.replicas).unwrap_or_default();
+        for replica in replicas {
+            if let Some((used, count)) = usage.get_mut(&replica.server) {
+                *used += replica.size;
+                *count += 1;
+            }
+        }
+    }
+    usage
+}
+
+/*
+*   Plans the moves between the given servers.
+*/
+pub fn plan(servers: &[String], sets: Vec<(String, usize, Uuid)>, threshold: f64,
+            budget: u64, dry_run: bool) -> RebalancePlan {
+    let usage = server_usage(servers, &sets);
+    let mut used: BTreeMap<String, u64> = usage.iter().map(|(s, (used, _))| (s.clone(), *used)).collect();
+    let average = match used.len() {
+        0 => 0,
+        n => used.values().sum::<u64>() / n as u64,
+    };
+    let high = average as f64 * (1.0 + threshold);
+    let low = average as f64 * (1.0 - threshold);
+
+    let mut chunks = movable_chunks(sets);
+    chunks.sort_by_key(|chunk| std::cmp::Reverse(chunk.size));
+    let mut moved: HashSet<Uuid> = HashSet::new();
+    let mut moves = Vec::new();
+    let mut remaining = budget;
+
+    let mut sources: Vec<String> = used.iter()
+        .filter(|(_, used)| **used as f64 > high)
+        .map(|(server, _)| server.clone())
+        .collect();
+    sources.sort_by(|a, b| used[b].cmp(&used[a]));
+    for source in sources {
+        for chunk in chunks.iter() {
+            if used[&source] as f64 <= high {
+                break;
+            }
+            if chunk.size > remaining || moved.contains(&chunk.uuid) || !chunk.holders.contains(&source) {
+                continue;
+            }
+            let left = used[&source].saturating_sub(chunk.size);
+            let candidates = used.iter()
+                .filter(|(server, used)| **used as f64 <= low
+                    && !chunk.holders.contains(server)
+                    && left >= **used + chunk.size)
+                .map(|(server, _)| placement_manager::candidate(server))
+                .collect();
+            let others: Vec<Topology> = chunk.holders.iter()
+                .filter(|holder| **holder != source)
+                .map(|holder| topology_manager::topology_of(holder))
+                .collect();
+            let target = match placement_manager::choose(candidates, 1, &others, chunk.size,
+                                                         placement_manager::allow_same_host()) {
+                Ok(mut chosen) => chosen.remove(0),
+                Err(_) => continue,
+            };
+            *used.get_mut(&source).unwrap() -= chunk.size;
+            *used.get_mut(&target).unwrap() += chunk.size;
+            remaining -= chunk.size;
+            moved.insert(chunk.uuid);
+            moves.push(ReplicaMove {
+                path: chunk.path.clone(),
+                index: chunk.index,
+                uuid: chunk.uuid.to_string(),
+                source: source.clone(),
+                target,
+                size: chunk.size,
+                done: false,
+                error: None,
+            });
+        }
+    }
+
+    RebalancePlan {
+        average,
+        threshold,
+        budget,
+        dry_run,
+        servers: usage.into_iter()
+            .map(|(server, (used, chunk_count))| ServerUsage { server, used, chunk_count })
+            .collect(),
+        moves,
+    }
+}
+
+/*
+*   Plans a run over the alive servers that are not draining,
+*   and moves the replicas unless it is a dry run.
+*/
+pub async fn rebalance(threshold: f64, budget: u64, dry_run: bool) -> Result<RebalancePlan, String> {
+    let servers: Vec<String> = heartbeat_manager::alive_servers()
+        .into_iter()
+        .filter(|server| !drain_manager::is_draining(server))
+        .collect();
+    let mut plan = plan(&servers, namespace_manager::chunk_sets(), threshold, budget, dry_run);
+    if dry_run || plan.moves.is_empty() {
+        return Ok(plan);
+    }
+    if RUNNING.swap(true, Ordering::Relaxed) {
+        return Err("A rebalance is already running".to_string());
+    }
+
+    let client = Client::new();
+    for planned in plan.moves.iter_mut() {
+        let uuid = Uuid::parse_str(&planned.uuid).unwrap();
+        match replication_manager::move_replica(&client, &planned.path, planned.index, uuid,
+                                                &planned.source, &planned.target,
+                                                Some(planned.source.clone())).await {
+            Ok(()) => {
+                info!("Moved chunk {} of {} from {} to {}",
+                      planned.index, planned.path, planned.source, planned.target);
+                planned.done = true;
+            }
+            Err(e) => {
+                warn!("Failed to move chunk {} of {}: {}", planned.index, planned.path, e);
+                planned.error = Some(e);
+            }
+        }
+    }
+    RUNNING.store(false, Ordering::Relaxed);
+    Ok(plan)
+}
+
+/*
+*   Background task rebalancing every DFS_REBALANCE_INTERVAL
+*   seconds, if set.
+*/
+pub async fn rebalance_loop() {
+    let interval = INTERVAL.load(Ordering::Relaxed);
+    if interval == 0 {
+        return;
+    }
+    loop {
+        sleep(Duration::from_secs(interval)).await;
+        match rebalance(default_threshold(), default_budget(), false).await {
+            Ok(plan) if !plan.moves.is_empty() => info!("Rebalanced {} of {} planned moves",
+                plan.moves.iter().filter(|m| m.done).count(), plan.moves.len()),
+            Ok(_) => {}
+            Err(e) => warn!("Rebalance skipped: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::shared::master_chunk_utils::ChunkReport;
+
+    #[test]
+    fn test_plan_moves_replicas_to_empty_server() {
+        chunk_manager::chunk_manager_init();
+        heartbeat_manager::heartbeat_manager_init();
+        drain_manager::drain_manager_init();
+        let servers: Vec<String> = (0..4).map(|i| format!("localhost:824{}", i)).collect();
+        for server in &servers {
+            heartbeat_manager::expect_heartbeat(server);
+        }
+        let mut sets = Vec::new();
+        for i in 0..3 {
+            let uuid = Uuid::new_v4();
+            chunk_manager::register_chunks(uuid, &servers[..3]);
+            for server in &servers[..3] {
+                let report = ChunkReport { id: uuid, version: 1, size: 100, checksum: String::new() };
+                chunk_manager::check_chunk_versions(server, &[report]);
+            }
+            sets.push((format!("/f{}", i), 0, uuid));
+        }
+
+        // 300 bytes on three servers, none on the fourth
+        let planned = plan(&servers, sets.clone(), 0.1, u64::MAX, true);
+        assert_eq!(planned.average, 225);
+        assert_eq!(planned.moves.len(), 2);
+        assert!(planned.moves.iter().all(|m| m.target == servers[3] && m.size == 100));
+        let sources: HashSet<&String> = planned.moves.iter().map(|m| &m.source).collect();
+        assert_eq!(sources.len(), 2);
+
+        // the budget only allows one move
+        assert_eq!(plan(&servers, sets, 0.1, 150, true).moves.len(), 1);
+    }
+}
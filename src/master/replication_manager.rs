@@ -0,0 +1,534 @@
+/*
+*   Repairs chunks that lost replicas, e.g. when a chunkserver died.
+*
+*   Every REPLICATION_INTERVAL seconds the replicas of each chunk in
+*   the namespace are checked. Replicas the chunk map dropped (stale)
+*   or that are on a dead chunkserver are lost. Chunks with
+*   fewer live replicas than the replication of their file get one new
+*   replica per round, those with the fewest live replicas first, at
+*   most MAX_REPAIRS_PER_ROUND per round. Replicas on a draining
+*   chunkserver are live but do not count towards the replication, they
+*   are copied elsewhere and kept until the drain is done.
+*
+*   Chunks with more live replicas than the replication of their file
+*   (it was lowered) lose one replica per round : the one whose removal
+*   keeps the most racks and hosts, not the primary, on the server
+*   holding the most chunks. The removal is logged, then the replica
+*   is deleted.
+*
+*   A repair :
+*       1. Picks a live replica as the source (alive before suspect)
+*          and a chunkserver on a host holding no replica of the chunk
+*          as the target.
+*       2. Asks the source for the version and checksum of the chunk,
+*          and has the target pull it from the source under the same
+*          handle (replicate_from). The data never goes through the master.
+*       3. Once the target reported the copy, logs the new replica in
+*          place of a lost one, which is scheduled for deletion.
+*          If the chunk was written in the meantime the copy is deleted.
+*
+*   Chunks of erasure-coded files have one fragment per server instead
+*   of replicas. A fragment without a live holder (draining ones aside)
+*   is rebuilt, chunks with the fewest fragments kept first :
+*       - a fragment still on a draining server is copied from it like
+*         a replica,
+*       - else the master fetches `data` of the other fragments, decodes
+*         the missing one (see shared::erasure) and stores it on a
+*         server holding no fragment of the chunk, in place of the lost one.
+*   A chunk that lost more than `parity` fragments cannot be rebuilt.
+*/
+#![allow(unused)]
+
+use crate::chunk_manager;
+use crate::drain_manager;
+use crate::heartbeat_manager;
+use crate::heartbeat_manager::ServerState;
+use crate::lock_manager;
+use crate::namespace_manager;
+use crate::placement_manager;
+use crate::topology_manager;
+use crate::topology_manager::rack_of;
+use lib::shared::cluster_auth;
+use lib::shared::erasure;
+use lib::shared::master_chunk_utils::ChunkReport;
+use lib::shared::master_client_utils::{ErasureCoding, FileChunks};
+use reqwest::Client;
+use std::collections::HashSet;
+use tokio::time::{sleep, Duration};
+use uuid::Uuid;
+
+const REPLICATION_INTERVAL: u64 = 10;
+const MAX_REPAIRS_PER_ROUND: usize = 16;
+
+/*
+*   A chunk index of a file missing replicas.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Repair {
+    pub path: String,
+    pub index: usize,
+    pub uuid: Uuid,
+    // servers of the live replicas
+    pub live: Vec<String>,
+    // server of a lost replica the copy replaces
+    pub lost: Option<String>,
+}
+
+/*
+*   A replica beyond the replication of its chunk.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Removal {
+    pub path: String,
+    pub index: usize,
+    pub uuid: Uuid,
+    pub server: String,
+}
+
+/*
+*   A fragment of an erasure-coded chunk without a live holder.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rebuild {
+    pub path: String,
+    pub index: usize,
+    pub uuid: Uuid,
+    pub erasure: ErasureCoding,
+    pub fragment: usize,
+    // live servers of each fragment of the chunk, draining ones included
+    pub live: Vec<Vec<String>>,
+    // server of the lost fragment the rebuilt one replaces
+    pub lost: Option<String>,
+}
+
+/*
+*   Repairs needed for the given chunks, fewest live replicas
+*   first. Chunks without any live replica cannot be repaired.
+*/
+pub fn plan_repairs(sets: Vec<(String, usize, Uuid)>) -> Vec<Repair> {
+    let mut repairs: Vec<Repair> = sets.into_iter()
+        .filter(|(_, _, uuid)| chunk_manager::chunk_erasure(uuid).is_none())
+        .filter_map(|(path, index, uuid)| {
+            let live = chunk_manager::get_replicas(&uuid);
+            if kept(&live).len() >= chunk_manager::chunk_replication(&uuid) {
+                return None;
+            }
+            if live.is_empty() {
+                warn!("Chunk {} of {} has no live replica left", index, path);
+                return None;
+            }
+            let lost = chunk_manager::chunk_servers(&uuid).into_iter()
+                .find(|server| !live.contains(server));
+            Some(Repair { path, index, uuid, live, lost })
+        })
+        .collect();
+    repairs.sort_by_key(|repair| kept(&repair.live).len());
+    repairs
+}
+
+/*
+*   Removals needed for the given chunks, one per chunk.
+*/
+pub fn plan_removals(sets: Vec<(String, usize, Uuid)>) -> Vec<Removal> {
+    sets.into_iter()
+        .filter(|(_, _, uuid)| chunk_manager::chunk_erasure(uuid).is_none())
+        .filter_map(|(path, index, uuid)| {
+            let kept = kept(&chunk_manager::get_replicas(&uuid));
+            if kept.len() <= chunk_manager::chunk_replication(&uuid) {
+                return None;
+            }
+            let server = pick_excess(&uuid, &kept);
+            Some(Removal { path, index, uuid, server })
+        })
+        .collect()
+}
+
+/*
+*   Live servers of each fragment of an erasure-coded chunk.
+*/
+pub fn live_fragments(uuid: &Uuid) -> Vec<Vec<String>> {
+    chunk_manager::fragment_servers(uuid).into_iter()
+        .map(|holders| holders.into_iter()
+            .filter(|server| !heartbeat_manager::is_dead(server))
+            .collect())
+        .collect()
+}
+
+fn kept_fragments(live: &[Vec<String>]) -> usize {
+    live.iter().filter(|holders| !kept(holders).is_empty()).count()
+}
+
+/*
+*   Rebuilds needed for the given chunks, those with the fewest
+*   fragments kept first. Chunks with fewer than `data` live
+*   fragments cannot be rebuilt.
+*/
+pub fn plan_rebuilds(sets: Vec<(String, usize, Uuid)>) -> Vec<Rebuild> {
+    let mut rebuilds = Vec::new();
+    for (path, index, uuid) in sets {
+        let erasure = match chunk_manager::chunk_erasure(&uuid) {
+            Some(erasure) => erasure,
+            None => continue,
+        };
+        let live = live_fragments(&uuid);
+        if kept_fragments(&live) == erasure.fragments() {
+            continue;
+        }
+        if live.iter().filter(|holders| !holders.is_empty()).count() < erasure.data {
+            warn!("Chunk {} of {} lost more than {} fragments", index, path, erasure.parity);
+            continue;
+        }
+        let recorded = chunk_manager::fragment_servers(&uuid);
+        for (fragment, holders) in live.iter().enumerate() {
+            if !kept(holders).is_empty() {
+                continue;
+            }
+            let lost = recorded[fragment].iter().find(|server| !holders.contains(server)).cloned();
+            rebuilds.push(Rebuild {
+                path: path.clone(), index, uuid, erasure, fragment, live: live.clone(), lost,
+            });
+        }
+    }
+    rebuilds.sort_by_key(|rebuild| kept_fragments(&rebuild.live));
+    rebuilds
+}
+
+/*
+*   Replica to remove : the one leaving the most racks, then hosts,
+*   not the primary, then the one on the server with the most chunks.
+*/
+fn pick_excess(uuid: &Uuid, kept: &[String]) -> String {
+    let primary = chunk_manager::lease_holder(uuid);
+    let spread_without = |server: &String| {
+        let others: Vec<_> = kept.iter()
+            .filter(|other| *other != server)
+            .map(|other| topology_manager::topology_of(other))
+            .collect();
+        let racks: HashSet<_> = others.iter().map(rack_of).collect();
+        let hosts: HashSet<_> = others.iter().map(|topology| topology.host.clone()).collect();
+        (racks.len(), hosts.len())
+    };
+    let chunk_count = |server: &String| chunk_manager::SERVER_MAP.get(server)
+        .map(|uuids| uuids.read().unwrap().len())
+        .unwrap_or(0);
+    kept.iter()
+        .max_by_key(|server| (spread_without(server), primary.as_ref() != Some(*server),
+                              chunk_count(server)))
+        .unwrap()
+        .clone()
+}
+
+/*
+*   Replicas that are not on a draining chunkserver.
+*/
+pub fn kept(live: &[String]) -> Vec<String> {
+    live.iter()
+        .filter(|server| !drain_manager::is_draining(server))
+        .cloned()
+        .collect()
+}
+
+/*
+*   Chunkserver for the new replica, on another host
+*   than the live ones (see the placement manager).
+*/
+fn pick_target(repair: &Repair) -> Result<String, String> {
+    Ok(placement_manager::place(1, &kept(&repair.live))?.remove(0))
+}
+
+fn pick_source(repair: &Repair) -> String {
+    repair.live.iter()
+        .find(|server| heartbeat_manager::server_state(server) == ServerState::Alive)
+        .unwrap_or(&repair.live[0])
+        .clone()
+}
+
+async fn chunk_report(request: reqwest::RequestBuilder, server: &str)
+    -> Result<ChunkReport, String> {
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("{} answered {}", server, response.status()));
+    }
+    response.json().await.map_err(|e| e.to_string())
+}
+
+/*
+*   Has the target pull the replica of the source, returns
+*   the copy as reported by the target.
+*/
+pub async fn copy_chunk(client: &Client, uuid: Uuid, source: &str, target: &str,
+                        version: u64) -> Result<ChunkReport, String> {
+    let url = format!("http://{}/chunk_info?id={}", source, uuid);
+    let request = client.get(&url).bearer_auth(cluster_auth::server_token());
+    let expected = chunk_report(request, source).await?;
+    if expected.version != version {
+        return Err(format!("{} holds version {} instead of {}",
+                           source, expected.version, version));
+    }
+
+    let url = format!("http://{}/replicate_from?id={}&source={}&source_id={}&version={}&checksum={}",
+                      target, uuid, source, uuid, version, expected.checksum);
+    let request = client.post(&url).bearer_auth(cluster_auth::server_token());
+    let copy = chunk_report(request, target).await?;
+    if copy.version != version || copy.checksum != expected.checksum {
+        return Err(format!("{} reported a different copy", target));
+    }
+    Ok(copy)
+}
+
+/*
+*   Copies the replica of the source to the target and logs it in
+*   place of the `old` replica. The copy is deleted if that fails.
+*/
+pub async fn move_replica(client: &Client, path: &str, index: usize, uuid: Uuid, source: &str,
+                          target: &str, old: Option<String>) -> Result<(), String> {
+    let version = chunk_manager::chunk_version(&uuid)
+        .ok_or_else(|| format!("Chunk {} is gone", uuid))?;
+
+    if let Err(e) = copy_chunk(client, uuid, source, target, version).await {
+        // the target may have stored the chunk before failing
+        chunk_manager::queue_chunk_deletion(target, vec![uuid]);
+        return Err(e);
+    }
+    let (path, replacement) = (path.to_string(), target.to_string());
+    let replaced = lock_manager::blocking(move || namespace_manager::replace_replica(
+        path, index, uuid, old, replacement, version)).await;
+    if let Err(e) = replaced {
+        chunk_manager::queue_chunk_deletion(target, vec![uuid]);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/*
+*   Fetches `data` fragments of a chunk and decodes
+*   the missing fragment from them.
+*/
+async fn decode_fragment(client: &Client, rebuild: &Rebuild, version: u64)
+                         -> Result<Vec<u8>, String> {
+    let erasure = rebuild.erasure;
+    let mut fragments: Vec<Option<Vec<u8>>> = vec![None; erasure.fragments()];
+    let mut fetched = 0;
+    for (fragment, holders) in rebuild.live.iter().enumerate() {
+        if fetched == erasure.data {
+            break;
+        }
+        for server in holders {
+            // a fragment at another version would decode garbage
+            let url = format!("http://{}/get_chunk?id={}&version={}", server, rebuild.uuid, version);
+            let request = client.get(&url).bearer_auth(cluster_auth::server_token());
+            let response = match request.send().await {
+                Ok(response) if response.status().is_success() => response,
+                _ => continue,
+            };
+            if let Ok(bytes) = response.bytes().await {
+                fragments[fragment] = Some(bytes.to_vec());
+                fetched += 1;
+                break;
+            }
+        }
+    }
+    erasure::reconstruct(&erasure, &mut fragments)?;
+    Ok(fragments[rebuild.fragment].take().unwrap())
+}
+
+/*
+*   Stores a decoded fragment on the target, the chunk is new to it.
+*/
+async fn store_fragment(client: &Client, uuid: Uuid, target: &str, version: u64,
+                        data: Vec<u8>) -> Result<(), String> {
+    let url = format!("http://{}/add_chunk?id={}&version={}", target, uuid, version);
+    let request = client.post(&url).bearer_auth(cluster_auth::server_token());
+    let response = request.body(data).send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("{} answered {}", target, response.status()));
+    }
+    Ok(())
+}
+
+/*
+*   Copies the fragment from a draining holder, or decodes it from
+*   the other fragments, to a server holding no fragment of the
+*   chunk, and logs it in place of the lost one. Returns the server.
+*/
+async fn rebuild(client: &Client, rebuild: Rebuild) -> Result<String, String> {
+    let version = chunk_manager::chunk_version(&rebuild.uuid)
+        .ok_or_else(|| format!("Chunk {} is gone", rebuild.uuid))?;
+    let target = placement_manager::place(1, &kept(&chunk_manager::get_replicas(&rebuild.uuid)))?
+        .remove(0);
+
+    let stored = match rebuild.live[rebuild.fragment].first() {
+        Some(source) => copy_chunk(client, rebuild.uuid, source, &target, version).await.map(|_| ()),
+        None => {
+            let data = decode_fragment(client, &rebuild, version).await?;
+            store_fragment(client, rebuild.uuid, &target, version, data).await
+        }
+    };
+    let logged = match stored {
+        Ok(()) => {
+            let Rebuild { path, index, uuid, fragment, lost, .. } = rebuild.clone();
+            let replacement = target.clone();
+            lock_manager::blocking(move || namespace_manager::replace_fragment(
+                path, index, uuid, fragment, lost, replacement, version)).await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = logged {
+        // the target may have stored the fragment before failing
+        chunk_manager::queue_chunk_deletion(&target, vec![rebuild.uuid]);
+        return Err(e);
+    }
+    Ok(target)
+}
+
+async fn repair(client: &Client, repair: Repair) -> Result<(), String> {
+    let target = pick_target(&repair)?;
+    let source = pick_source(&repair);
+    move_replica(client, &repair.path, repair.index, repair.uuid, &source, &target,
+                 repair.lost.clone()).await?;
+    info!("Copied chunk {} of {} from {} to {}", repair.index, repair.path, source, target);
+    Ok(())
+}
+
+/*
+*   Background task re-replicating the chunks that lost
+*   replicas every REPLICATION_INTERVAL seconds.
+*/
+pub async fn replication_loop() {
+    let interval = Duration::from_secs(REPLICATION_INTERVAL);
+    let client = Client::new();
+    loop {
+        sleep(interval).await;
+
+        let repairs = plan_repairs(namespace_manager::chunk_sets());
+        if !repairs.is_empty() {
+            info!("{} chunks are under-replicated", repairs.len());
+        }
+        for under_replicated in repairs.into_iter().take(MAX_REPAIRS_PER_ROUND) {
+            let (path, index) = (under_replicated.path.clone(), under_replicated.index);
+            if let Err(e) = repair(&client, under_replicated).await {
+                warn!("Failed to re-replicate chunk {} of {}: {}", index, path, e);
+            }
+        }
+
+        let rebuilds = plan_rebuilds(namespace_manager::chunk_sets());
+        if !rebuilds.is_empty() {
+            info!("{} fragments are missing", rebuilds.len());
+        }
+        for missing in rebuilds.into_iter().take(MAX_REPAIRS_PER_ROUND) {
+            let (path, index, fragment) = (missing.path.clone(), missing.index, missing.fragment);
+            match rebuild(&client, missing).await {
+                Ok(target) => info!("Rebuilt fragment {} of chunk {} of {} on {}",
+                                    fragment, index, path, target),
+                Err(e) => warn!("Failed to rebuild fragment {} of chunk {} of {}: {}",
+                                fragment, index, path, e),
+            }
+        }
+
+        let removals = plan_removals(namespace_manager::chunk_sets());
+        for removal in removals.into_iter().take(MAX_REPAIRS_PER_ROUND) {
+            let Removal { path, index, uuid, server } = removal;
+            let (logged_path, logged_server) = (path.clone(), server.clone());
+            let removed = lock_manager::blocking(move || namespace_manager::remove_replica(
+                logged_path, index, uuid, logged_server)).await;
+            match removed {
+                Ok(()) => info!("Removed replica of chunk {} of {} on {}", index, path, server),
+                Err(e) => warn!("Failed to remove replica of chunk {} of {}: {}", index, path, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_repairs_fewest_live_replicas_first() {
+        chunk_manager::chunk_manager_init();
+        heartbeat_manager::heartbeat_manager_init();
+        let servers: Vec<String> = (0..3).map(|i| format!("localhost:817{}", i)).collect();
+        for server in &servers[..2] {
+            heartbeat_manager::expect_heartbeat(server);
+        }
+        // two replicas on live servers, one on a server never heard of
+        let (degraded, single, lost) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        chunk_manager::register_chunks(degraded, &servers);
+        chunk_manager::register_chunks(single, &servers[..1]);
+        chunk_manager::register_chunks(lost, &servers[2..]);
+
+        let repairs = plan_repairs(vec![
+            ("/a".to_string(), 0, degraded),
+            ("/b".to_string(), 0, single),
+            ("/c".to_string(), 0, lost),
+        ]);
+
+        assert_eq!(repairs.len(), 2);
+        assert_eq!(repairs[0].path, "/b");
+        assert_eq!(repairs[0].lost, None);
+        assert_eq!(repairs[1].path, "/a");
+        assert_eq!(repairs[1].lost, Some(servers[2].clone()));
+        assert_eq!(repairs[1].live, servers[..2].to_vec());
+    }
+
+    #[test]
+    fn test_plan_removals_above_replication() {
+        chunk_manager::chunk_manager_init();
+        heartbeat_manager::heartbeat_manager_init();
+        let servers: Vec<String> = (0..4).map(|i| format!("localhost:825{}", i)).collect();
+        for server in &servers {
+            heartbeat_manager::expect_heartbeat(server);
+        }
+        let uuid = Uuid::new_v4();
+        chunk_manager::register_chunks(uuid, &servers);
+        // the third server holds the most chunks
+        chunk_manager::register_chunks(Uuid::new_v4(), &servers[2..3]);
+        let sets = vec![("/a".to_string(), 0, uuid)];
+
+        chunk_manager::set_replication(&uuid, 4);
+        assert!(plan_removals(sets.clone()).is_empty());
+
+        chunk_manager::set_replication(&uuid, 3);
+        let removals = plan_removals(sets.clone());
+        assert_eq!(removals.len(), 1);
+        assert_eq!(removals[0].server, servers[2]);
+
+        chunk_manager::set_replication(&uuid, 5);
+        assert_eq!(plan_repairs(sets).len(), 1);
+    }
+
+    #[test]
+    fn test_plan_rebuilds_lost_fragments() {
+        chunk_manager::chunk_manager_init();
+        heartbeat_manager::heartbeat_manager_init();
+        drain_manager::drain_manager_init();
+        let servers: Vec<String> = (0..4).map(|i| format!("localhost:826{}", i)).collect();
+        // the last server was never heard of
+        for server in &servers[..3] {
+            heartbeat_manager::expect_heartbeat(server);
+        }
+        let erasure = ErasureCoding { data: 2, parity: 2 };
+        let uuid = Uuid::new_v4();
+        chunk_manager::register_fragments(uuid, &servers, erasure);
+        let sets = vec![("/a".to_string(), 0, uuid)];
+        assert!(plan_repairs(sets.clone()).is_empty());
+
+        let rebuilds = plan_rebuilds(sets.clone());
+        assert_eq!(rebuilds.len(), 1);
+        assert_eq!(rebuilds[0].fragment, 3);
+        assert_eq!(rebuilds[0].lost, Some(servers[3].clone()));
+        assert_eq!(rebuilds[0].live[0], vec![servers[0].clone()]);
+
+        // a fragment on a draining server is copied, not replaced
+        drain_manager::start_drain(&servers[1]).unwrap();
+        let rebuilds = plan_rebuilds(sets.clone());
+        assert_eq!(rebuilds.iter().map(|r| r.fragment).collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(rebuilds[0].lost, None);
+        assert_eq!(drain_manager::remaining_chunks(&servers[1], &sets), vec![uuid]);
+
+        // fewer than `data` fragments left
+        chunk_manager::drop_replica(&uuid, &servers[0]);
+        chunk_manager::drop_replica(&uuid, &servers[1]);
+        assert!(plan_rebuilds(sets).is_empty());
+    }
+}
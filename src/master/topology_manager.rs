@@ -0,0 +1,161 @@
+/*
+*   Keeps track of the failure domains of the chunkservers.
+*
+*   Each chunkserver reports the datacenter, rack and host it is on in
+*   its heartbeats (DFS_DATACENTER / DFS_RACK / DFS_HOST on the
+*   chunkserver, the host defaults to its host name). Replicas on one
+*   host or one rack can be lost together, placement spreads the
+*   replicas of a chunk over distinct hosts and at least MIN_RACKS racks.
+*
+*   A server the master has no labels for is on a host of its own
+*   in the default rack.
+*
+*   Data Structures :
+*       1. Topology Map         : Map (Server Address -> Topology)
+*/
+#![allow(unused)]
+
+use crate::chunk_manager;
+use crate::namespace_manager;
+use crate::safe_map::SafeMap;
+use lib::shared::master_chunk_utils::Topology;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use uuid::Uuid;
+
+static TOPOLOGY_MAP: SafeMap<String, Topology> = SafeMap::new();
+
+pub const MIN_RACKS: usize = 2;
+
+/*
+*   Datacenter -> Rack -> Host -> Servers
+*/
+pub type TopologyTree = BTreeMap<String, BTreeMap<String, BTreeMap<String, Vec<String>>>>;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ReplicaLocation {
+    pub server: String,
+    pub topology: Topology,
+}
+
+/*
+*   Failure domains the live replicas of a chunk are spread over.
+*/
+#[derive(Serialize, Debug, Clone)]
+pub struct ChunkSpread {
+    pub path: String,
+    pub index: usize,
+    pub uuid: Uuid,
+    pub replicas: Vec<ReplicaLocation>,
+    pub datacenters: usize,
+    pub racks: usize,
+    pub hosts: usize,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TopologyView {
+    pub servers: TopologyTree,
+    pub chunks: Vec<ChunkSpread>,
+}
+
+pub fn topology_manager_init() {
+    TOPOLOGY_MAP.init();
+}
+
+/*
+*   Records the labels a server sent in its heartbeat.
+*/
+pub fn update_topology(server: &str, topology: &Topology) {
+    let previous = TOPOLOGY_MAP.insert(server.to_string(), topology.clone());
+    if let Some(previous) = previous {
+        let previous = previous.read().unwrap();
+        if *previous != *topology {
+            info!("Chunkserver {} moved from {:?} to {:?}", server, *previous, topology);
+        }
+    }
+}
+
+pub fn topology_of(server: &str) -> Topology {
+    match TOPOLOGY_MAP.get(&server.to_string()) {
+        Some(topology) => topology.read().unwrap().clone(),
+        None => Topology { host: server.to_string(), ..Topology::default() },
+    }
+}
+
+/*
+*   Racks are named within their datacenter.
+*/
+pub fn rack_of(topology: &Topology) -> (String, String) {
+    (topology.datacenter.clone(), topology.rack.clone())
+}
+
+pub fn topology_tree() -> TopologyTree {
+    let mut tree = TopologyTree::new();
+    for (server, topology) in TOPOLOGY_MAP.to_map() {
+        let servers = tree.entry(topology.datacenter)
+            .or_default()
+            .entry(topology.rack)
+            .or_default()
+            .entry(topology.host)
+            .or_default();
+        servers.push(server);
+        servers.sort();
+    }
+    tree
+}
+
+pub fn chunk_spread(path: String, index: usize, uuid: Uuid) -> ChunkSpread {
+    let replicas: Vec<ReplicaLocation> = chunk_manager::get_replicas(&uuid)
+        .into_iter()
+        .map(|server| ReplicaLocation { topology: topology_of(&server), server })
+        .collect();
+    let datacenters: HashSet<&String> = replicas.iter().map(|r| &r.topology.datacenter).collect();
+    let racks: HashSet<(String, String)> = replicas.iter().map(|r| rack_of(&r.topology)).collect();
+    let hosts: HashSet<&String> = replicas.iter().map(|r| &r.topology.host).collect();
+    ChunkSpread {
+        path,
+        index,
+        uuid,
+        datacenters: datacenters.len(),
+        racks: racks.len(),
+        hosts: hosts.len(),
+        replicas,
+    }
+}
+
+pub fn topology_view() -> TopologyView {
+    let mut chunks: Vec<ChunkSpread> = namespace_manager::chunk_sets()
+        .into_iter()
+        .map(|(path, index, uuid)| chunk_spread(path, index, uuid))
+        .collect();
+    chunks.sort_by(|a, b| (&a.path, a.index).cmp(&(&b.path, b.index)));
+    TopologyView { servers: topology_tree(), chunks }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_spread_counts_failure_domains() {
+        topology_manager_init();
+        chunk_manager::chunk_manager_init();
+        crate::heartbeat_manager::heartbeat_manager_init();
+        let racks = ["r1", "r1", "r2"];
+        let servers: Vec<String> = (0..racks.len()).map(|i| format!("localhost:819{}", i)).collect();
+        for (server, rack) in servers.iter().zip(racks) {
+            crate::heartbeat_manager::expect_heartbeat(server);
+            update_topology(server, &Topology {
+                rack: rack.to_string(),
+                host: server.clone(),
+                ..Topology::default()
+            });
+        }
+        let uuid = Uuid::new_v4();
+        chunk_manager::register_chunks(uuid, &servers);
+
+        let spread = chunk_spread("/a".to_string(), 0, uuid);
+        assert_eq!((spread.datacenters, spread.racks, spread.hosts), (1, 2, 3));
+        assert!(topology_tree()["default"]["r1"].contains_key("localhost:8190"));
+    }
+}
@@ -0,0 +1,285 @@
+/*
+*   The user store of the master.
+*
+*   File Format : users.json, one JSON encoded user per line
+*       User Record {
+*           Username        : String
+*           Password        : String (Argon2id hash)
+*           Admin           : bool
+*           Disabled        : bool
+*       }
+*
+*   Every change rewrites the whole file through a temp file and a
+*   rename, so a crash leaves either the old or the new store.
+*
+*   The superuser is not stored in the file. It exists only when its
+*   password is given to the master at startup, is always an admin
+*   and cannot be changed through the API.
+*/
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use rocket::tokio::fs::OpenOptions;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::RwLock;
+use lib::shared::master_client_utils::{User, UserSummary};
+use crate::auth_manager;
+use crate::namespace_manager::SUPERUSER;
+
+const USER_NOT_FOUND: &str = "User not found";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct UserRecord {
+    username: String,
+    password: String,
+    #[serde(default)]
+    admin: bool,
+    #[serde(default)]
+    disabled: bool,
+}
+
+pub struct UserDatabase {
+    path: String,
+    users: RwLock<HashMap<String, UserRecord>>,
+}
+
+/*
+*   Hashes a password without blocking the async runtime.
+*/
+async fn hash_password(password: String) -> Result<String, Error> {
+    tokio::task::spawn_blocking(move || auth_manager::hash_password(&password))
+        .await.map_err(Error::other)?
+}
+
+/*
+*   Rejects changes to the superuser and to unknown users.
+*/
+fn user_mut<'a>(users: &'a mut HashMap<String, UserRecord>, username: &str)
+    -> Result<&'a mut UserRecord, Error> {
+    if username == SUPERUSER {
+        return Err(Error::new(ErrorKind::PermissionDenied, "The superuser cannot be changed"));
+    }
+    users.get_mut(username).ok_or_else(|| Error::new(ErrorKind::NotFound, USER_NOT_FOUND))
+}
+
+impl UserDatabase {
+    /*
+    *   Loads the user store. Entries written before passwords were
+    *   hashed are hashed now and the store is rewritten once.
+    */
+    pub async fn new(path: &str, root_password: Option<String>) -> Result<Self, Error> {
+        let mut users = HashMap::new();
+
+        let file = OpenOptions::new()
+            .read(true).write(true).create(true).truncate(false)
+            .open(path).await?;
+
+        let reader = BufReader::new(file);
+        let mut lines = reader.lines();
+
+        let mut migrated = 0;
+        while let Some(line_result) = lines.next_line().await? {
+            if line_result.trim().is_empty() {
+                continue;
+            }
+            let mut user: UserRecord = serde_json::from_str(&line_result)?;
+            // entries written before passwords were hashed
+            if !auth_manager::is_password_hash(&user.password) {
+                user.password = auth_manager::hash_password(&user.password)?;
+                migrated += 1;
+            }
+            users.insert(user.username.clone(), user);
+        }
+
+        let user_db = Self { path: path.to_string(), users: RwLock::new(HashMap::new()) };
+        if migrated > 0 {
+            user_db.rewrite(&users).await?;
+            warn!("Hashed {} plaintext passwords in {}", migrated, path);
+        }
+
+        if let Some(password) = root_password {
+            users.insert(SUPERUSER.to_string(), UserRecord {
+                username: SUPERUSER.to_string(),
+                password: auth_manager::hash_password(&password)?,
+                admin: true,
+                disabled: false,
+            });
+        }
+        *user_db.users.write().await = users;
+        Ok(user_db)
+    }
+
+    /*
+    *   Replaces the user file with the given users. Callers hold the
+    *   write lock of the store so that rewrites never interleave.
+    */
+    async fn rewrite(&self, users: &HashMap<String, UserRecord>) -> Result<(), Error> {
+        let tmp_path = format!("{}.tmp", self.path);
+        let mut file = OpenOptions::new()
+            .write(true).create(true).truncate(true)
+            .open(&tmp_path).await?;
+
+        let mut records: Vec<&UserRecord> = users.values()
+            .filter(|user| user.username != SUPERUSER)
+            .collect();
+        records.sort_by(|a, b| a.username.cmp(&b.username));
+        for user in records {
+            let data = serde_json::to_string(user)?;
+            file.write_all(data.as_bytes()).await?;
+            file.write_all(b"\n").await?;
+        }
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp_path, &self.path).await
+    }
+
+    pub async fn register(&self, user: User) -> Result<(), Error> {
+        if user.username == SUPERUSER {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Reserved username"));
+        }
+        if self.users.read().await.contains_key(&user.username) {
+            return Err(Error::new(ErrorKind::AlreadyExists, "User already exists"));
+        }
+        let password = hash_password(user.password).await?;
+
+        let mut users = self.users.write().await;
+        // check again, someone may have registered while hashing
+        if users.contains_key(&user.username) {
+            return Err(Error::new(ErrorKind::AlreadyExists, "User already exists"));
+        }
+        let mut updated = users.clone();
+        updated.insert(user.username.clone(), UserRecord {
+            username: user.username,
+            password,
+            admin: false,
+            disabled: false,
+        });
+        self.rewrite(&updated).await?;
+        *users = updated;
+        Ok(())
+    }
+
+    /*
+    *   Checks the credentials of an enabled user.
+    */
+    pub async fn authenticate(&self, user: &User) -> Result<(), Error> {
+        let record = match self.users.read().await.get(&user.username) {
+            Some(record) => record.clone(),
+            None => return Err(Error::new(ErrorKind::NotFound, USER_NOT_FOUND)),
+        };
+        if record.disabled {
+            return Err(Error::new(ErrorKind::PermissionDenied, "User is disabled"));
+        }
+
+        let password = user.password.clone();
+        let valid = tokio::task::spawn_blocking(
+            move || auth_manager::verify_password(&password, &record.password))
+            .await.map_err(Error::other)?;
+        if !valid {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid password"));
+        }
+        Ok(())
+    }
+
+    pub async fn is_admin(&self, username: &str) -> bool {
+        self.users.read().await.get(username)
+            .is_some_and(|user| user.admin && !user.disabled)
+    }
+
+    pub async fn exists(&self, username: &str) -> bool {
+        self.users.read().await.contains_key(username)
+    }
+
+    pub async fn list(&self) -> Vec<UserSummary> {
+        let mut users: Vec<UserSummary> = self.users.read().await.values()
+            .map(|user| UserSummary {
+                username: user.username.clone(),
+                admin: user.admin,
+                disabled: user.disabled,
+            })
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+
+    /*
+    *   Applies a change to one user and persists the store.
+    */
+    async fn update(&self, username: &str, change: impl FnOnce(&mut UserRecord))
+        -> Result<(), Error> {
+        let mut users = self.users.write().await;
+        let mut updated = users.clone();
+        change(user_mut(&mut updated, username)?);
+        self.rewrite(&updated).await?;
+        *users = updated;
+        Ok(())
+    }
+
+    pub async fn set_password(&self, username: &str, password: String) -> Result<(), Error> {
+        let password = hash_password(password).await?;
+        self.update(username, |user| user.password = password).await
+    }
+
+    pub async fn set_disabled(&self, username: &str, disabled: bool) -> Result<(), Error> {
+        self.update(username, |user| user.disabled = disabled).await
+    }
+
+    pub async fn set_admin(&self, username: &str, admin: bool) -> Result<(), Error> {
+        self.update(username, |user| user.admin = admin).await
+    }
+
+    pub async fn delete(&self, username: &str) -> Result<(), Error> {
+        let mut users = self.users.write().await;
+        let mut updated = users.clone();
+        user_mut(&mut updated, username)?;
+        updated.remove(username);
+        self.rewrite(&updated).await?;
+        *users = updated;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_USER_DIR: &str = "/tmp/dfs_user_manager";
+
+    fn pre_test(name: &str) -> String {
+        std::fs::create_dir_all(TEST_USER_DIR).unwrap();
+        let path = format!("{}/{}", TEST_USER_DIR, name);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn user(username: &str, password: &str) -> User {
+        User { username: username.to_string(), password: password.to_string() }
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_users_are_migrated() {
+        let path = pre_test("migrate.json");
+        std::fs::write(&path, "{\"username\":\"alice\",\"password\":\"secret\"}\n").unwrap();
+
+        let user_db = UserDatabase::new(&path, None).await.unwrap();
+        assert!(user_db.authenticate(&user("alice", "secret")).await.is_ok());
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("\"secret\""));
+    }
+
+    #[tokio::test]
+    async fn test_changes_are_persisted() {
+        let path = pre_test("changes.json");
+        let user_db = UserDatabase::new(&path, Some("toor".to_string())).await.unwrap();
+        user_db.register(user("alice", "a")).await.unwrap();
+        user_db.register(user("bob", "b")).await.unwrap();
+        user_db.set_password("alice", "new".to_string()).await.unwrap();
+        user_db.set_disabled("bob", true).await.unwrap();
+        assert!(user_db.set_admin(SUPERUSER, false).await.is_err());
+
+        let user_db = UserDatabase::new(&path, None).await.unwrap();
+        assert!(user_db.authenticate(&user("alice", "new")).await.is_ok());
+        assert!(user_db.authenticate(&user("bob", "b")).await.is_err());
+        user_db.delete("bob").await.unwrap();
+        assert_eq!(user_db.list().await.len(), 1);
+    }
+}
@@ -0,0 +1,234 @@
+/*
+*   Authenticates the requests between the master, the chunkservers
+*   and the clients reading and writing chunks.
+*
+*   The master and every chunkserver share a secret, set with the
+*   DFS_CLUSTER_SECRET environment variable, and sign the tokens they
+*   send with it. A request carries its token in the
+*   "Authorization: Bearer <token>" header.
+*
+*   Token Format : base64(claims).base64(HMAC-SHA256(claims))
+*       Claims {
+*           Grant           : Server | Read { Chunk } | Write { Write Grant }
+*           Expires         : DateTime
+*       }
+*       Write Grant {
+*           Chunk           : Uuid
+*           Version         : u64 (version the write stores the chunk at)
+*           Primary         : Option<String> (lease holder, None without a lease)
+*           Secondaries     : List<String> (servers the primary forwards to)
+*       }
+*
+*   A server token is sent by the master and the chunkservers to each
+*   other : heartbeats, re-replication, forwarded mutations, reads of
+*   chunks. Clients do not know the secret, the master hands them a
+*   token for each chunk of a read or a write, which only allows that
+*   on that chunk. Everything a chunkserver needs to apply a write
+*   (version, primary, secondaries) is taken from the write grant,
+*   never from the client. Tokens expire after TOKEN_DURATION.
+*
+*   Routes requiring a server token take a `ClusterServer` argument,
+*   routes reading or writing the chunk of their `id` parameter take a
+*   `ChunkReader` or `ChunkWriter` argument, which also accept a token
+*   for that chunk.
+*/
+
+use std::sync::OnceLock;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const CLUSTER_SECRET_ENV: &str = "DFS_CLUSTER_SECRET";
+const AUTHORIZATION: &str = "Authorization";
+const BEARER_PREFIX: &str = "Bearer ";
+const TOKEN_DURATION: i64 = 300;
+
+static CLUSTER_SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// What the master allowed a client to write, see the header.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WriteGrant {
+    pub chunk: Uuid,
+    pub version: u64,
+    pub primary: Option<String>,
+    pub secondaries: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+enum Grant {
+    Server,
+    Read { chunk: Uuid },
+    Write(WriteGrant),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Claims {
+    grant: Grant,
+    expires: DateTime<Utc>,
+}
+
+/// A request signed with the cluster secret by the master or a chunkserver.
+#[derive(Debug)]
+pub struct ClusterServer;
+
+/// A request allowed to read the chunk of its `id` parameter.
+#[derive(Debug)]
+pub struct ChunkReader;
+
+/// A request allowed to write the chunk of its `id` parameter,
+/// with the grant of the master for a client (None for a server).
+#[derive(Debug)]
+pub struct ChunkWriter {
+    pub grant: Option<WriteGrant>,
+}
+
+/*
+*   Reads the cluster secret from DFS_CLUSTER_SECRET,
+*   which has to be set and not empty.
+*/
+pub fn cluster_auth_init() -> Result<(), String> {
+    let secret = std::env::var(CLUSTER_SECRET_ENV)
+        .ok()
+        .filter(|secret| !secret.is_empty())
+        .ok_or_else(|| format!("{} is not set", CLUSTER_SECRET_ENV))?;
+    init_secret(&secret);
+    Ok(())
+}
+
+fn init_secret(secret: &str) {
+    CLUSTER_SECRET.get_or_init(|| secret.as_bytes().to_vec());
+}
+
+fn mac() -> HmacSha256 {
+    let key = CLUSTER_SECRET.get().expect("Cluster auth not initialized");
+    HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size")
+}
+
+fn sign(grant: Grant) -> String {
+    let claims = Claims { grant, expires: Utc::now() + Duration::seconds(TOKEN_DURATION) };
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+    let mut mac = mac();
+    mac.update(payload.as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+    format!("{}.{}", payload, signature)
+}
+
+/*
+*   Token for the requests of the master and the chunkservers.
+*/
+pub fn server_token() -> String {
+    sign(Grant::Server)
+}
+
+/*
+*   Token a client reads chunk `uuid` with.
+*/
+pub fn read_token(uuid: Uuid) -> String {
+    sign(Grant::Read { chunk: uuid })
+}
+
+/*
+*   Token a client writes a chunk with, as granted by the master.
+*/
+pub fn write_token(grant: WriteGrant) -> String {
+    sign(Grant::Write(grant))
+}
+
+/*
+*   Returns the grant of a token if its signature
+*   is valid and it has not expired.
+*/
+fn verify_token(token: &str) -> Option<Grant> {
+    let (payload, signature) = token.split_once('.')?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    let mut mac = mac();
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature).ok()?;
+
+    let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    (claims.expires > Utc::now()).then_some(claims.grant)
+}
+
+fn request_token(request: &Request<'_>) -> Option<Grant> {
+    let token = request.headers().get_one(AUTHORIZATION)?.strip_prefix(BEARER_PREFIX)?;
+    verify_token(token)
+}
+
+/// The chunk of the `id` parameter of a request.
+fn request_chunk(request: &Request<'_>) -> Option<Uuid> {
+    request.query_value::<&str>("id")
+        .and_then(|id| id.ok())
+        .and_then(|id| Uuid::parse_str(id).ok())
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClusterServer {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        match request_token(request) {
+            Some(Grant::Server) => Outcome::Success(ClusterServer),
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ChunkReader {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        match request_token(request) {
+            Some(Grant::Server) => Outcome::Success(ChunkReader),
+            Some(Grant::Read { chunk }) if Some(chunk) == request_chunk(request) =>
+                Outcome::Success(ChunkReader),
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ChunkWriter {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        match request_token(request) {
+            Some(Grant::Server) => Outcome::Success(ChunkWriter { grant: None }),
+            Some(Grant::Write(grant)) if Some(grant.chunk) == request_chunk(request) =>
+                Outcome::Success(ChunkWriter { grant: Some(grant) }),
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_token_only_allows_its_chunk() {
+        init_secret("secret");
+        let uuid = Uuid::new_v4();
+        let grant = WriteGrant { chunk: uuid, version: 2, primary: Some("localhost:8100".to_string()),
+                                 secondaries: vec!["localhost:8101".to_string()] };
+        assert_eq!(verify_token(&server_token()), Some(Grant::Server));
+        assert_eq!(verify_token(&read_token(uuid)), Some(Grant::Read { chunk: uuid }));
+        assert_eq!(verify_token(&write_token(grant.clone())), Some(Grant::Write(grant)));
+
+        // a read token turned into a server token is refused
+        let token = read_token(uuid);
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = Claims { grant: Grant::Server, expires: Utc::now() + Duration::seconds(60) };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+        assert_eq!(verify_token(&format!("{}.{}", payload, signature)), None);
+        assert_eq!(verify_token("not a token"), None);
+    }
+}
@@ -0,0 +1,97 @@
+/*
+*   Reed-Solomon coding of the chunks of erasure-coded files.
+*
+*   A chunk of `len` bytes is cut into `data` shards of
+*   ceil(len / data) bytes (the last one padded with zeros), and
+*   `parity` parity shards of that size are computed from them. Any
+*   `data` of the `data + parity` shards (fragments) rebuild the
+*   others, so a chunk survives the loss of up to `parity` fragments.
+*   The padding is dropped by cutting the rebuilt chunk to its length.
+*/
+use crate::shared::master_client_utils::ErasureCoding;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+fn coder(erasure: &ErasureCoding) -> Result<ReedSolomon, String> {
+    ReedSolomon::new(erasure.data, erasure.parity).map_err(|e| format!("{:?}", e))
+}
+
+/*
+*   Size of each fragment of a chunk of `len` bytes.
+*/
+pub fn fragment_size(erasure: &ErasureCoding, len: usize) -> usize {
+    len.div_ceil(erasure.data).max(1)
+}
+
+/*
+*   Cuts a chunk into its data fragments followed by its parity fragments.
+*/
+pub fn encode(erasure: &ErasureCoding, chunk: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let size = fragment_size(erasure, chunk.len());
+    let mut fragments: Vec<Vec<u8>> = (0..erasure.fragments())
+        .map(|i| {
+            let start = (i * size).min(chunk.len());
+            let end = ((i + 1) * size).min(chunk.len());
+            let mut fragment = match i < erasure.data {
+                true => chunk[start..end].to_vec(),
+                false => Vec::new(),
+            };
+            fragment.resize(size, 0);
+            fragment
+        })
+        .collect();
+    coder(erasure)?.encode(&mut fragments).map_err(|e| format!("{:?}", e))?;
+    Ok(fragments)
+}
+
+/*
+*   Rebuilds the missing (None) fragments of a chunk in place,
+*   at least `data` of them have to be present.
+*/
+pub fn reconstruct(erasure: &ErasureCoding, fragments: &mut [Option<Vec<u8>>]) -> Result<(), String> {
+    let present = fragments.iter().filter(|fragment| fragment.is_some()).count();
+    if present < erasure.data {
+        return Err(format!("Only {} of the {} fragments needed are available",
+                           present, erasure.data));
+    }
+    coder(erasure)?.reconstruct(fragments).map_err(|e| format!("{:?}", e))
+}
+
+/*
+*   Rebuilds a chunk of `len` bytes from its fragments.
+*/
+pub fn decode(erasure: &ErasureCoding, mut fragments: Vec<Option<Vec<u8>>>, len: usize)
+              -> Result<Vec<u8>, String> {
+    if fragments[..erasure.data].iter().any(|fragment| fragment.is_none()) {
+        reconstruct(erasure, &mut fragments)?;
+    }
+    let mut chunk: Vec<u8> = fragments.into_iter()
+        .take(erasure.data)
+        .flat_map(|fragment| fragment.unwrap())
+        .collect();
+    chunk.truncate(len);
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_with_parity_fragments_missing() {
+        let erasure = ErasureCoding { data: 6, parity: 3 };
+        let chunk: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let fragments = encode(&erasure, &chunk).unwrap();
+        assert_eq!(fragments.len(), 9);
+        assert!(fragments.iter().all(|fragment| fragment.len() == 167));
+
+        // three fragments lost, two of them data
+        let mut available: Vec<Option<Vec<u8>>> = fragments.into_iter().map(Some).collect();
+        for i in [0, 4, 7] {
+            available[i] = None;
+        }
+        assert_eq!(decode(&erasure, available.clone(), chunk.len()).unwrap(), chunk);
+
+        available[1] = None;
+        assert!(decode(&erasure, available, chunk.len()).is_err());
+    }
+}
@@ -1,8 +1,11 @@
 use rocket::serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
 
 pub const HEARTBEAT_INTERVAL: u64 = 2;
+pub const CHUNK_SIZE_MAX: usize = 1024 * 1024 * 256; // 256 MB
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Disk {
     pub name: String,
     pub kind: String,
@@ -12,7 +15,7 @@ pub struct Disk {
     pub available_space: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Metadata {
     pub os_name: String,
     pub os_version: String,
@@ -21,4 +24,97 @@ pub struct Metadata {
     pub chunkserver_id: u16,
     pub last_heartbeat: u64,
     pub disk_info: Disk,
-}
\ No newline at end of file
+    // every chunk stored on the server if `full_report` is set,
+    // else the chunks stored since the last heartbeat
+    #[serde(default)]
+    pub chunks: Vec<ChunkReport>,
+    // chunks deleted since the last heartbeat
+    #[serde(default)]
+    pub removed_chunks: Vec<Uuid>,
+    #[serde(default)]
+    pub full_report: bool,
+    // set while the server accepts no new chunks
+    #[serde(default)]
+    pub draining: bool,
+    // commands of the previous replies that were executed
+    #[serde(default)]
+    pub acks: Vec<CommandAck>,
+    // failure domains the server is in
+    #[serde(default)]
+    pub topology: Topology,
+}
+
+/*
+*   Location of a chunkserver : replicas on the same host, rack or
+*   datacenter fail together. Rack names are only unique within
+*   their datacenter.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Topology {
+    pub datacenter: String,
+    pub rack: String,
+    pub host: String,
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Topology {
+            datacenter: "default".to_string(),
+            rack: "default".to_string(),
+            host: "default".to_string(),
+        }
+    }
+}
+
+/*
+*   Commands the master sends a chunkserver in its heartbeat replies.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Command {
+    DeleteChunks { chunks: Vec<Uuid> },
+    // have `target` copy the local chunk `id` and store it as `target_id`
+    ReplicateChunk { id: Uuid, target: String, target_id: Uuid },
+    // send a full chunk report in the next heartbeat
+    ReportChunks,
+    // register again, with fresh server information and a full chunk report
+    Reregister,
+    // stop (or start again) accepting new chunks
+    Drain { enabled: bool },
+}
+
+/*
+*   Reply of the master to a heartbeat, commands are numbered
+*   so that the chunkserver can acknowledge them.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HeartbeatResponse {
+    #[serde(default)]
+    pub commands: Vec<(u64, Command)>,
+}
+
+/*
+*   Sent back in the next heartbeat once a command was executed.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommandAck {
+    pub id: u64,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/*
+*   A chunk as stored on a chunkserver. Sent by a chunkserver
+*   describing a chunk it holds or just copied from a peer.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChunkReport {
+    pub id: Uuid,
+    pub version: u64,
+    pub size: u64,
+    // hex encoded SHA-256 of the chunk data
+    pub checksum: String,
+}
+
+pub fn chunk_checksum(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -1,46 +1,132 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
 
+/*
+*   A chunk and the chunkservers holding a replica of it,
+*   every replica is stored under the chunk handle `uuid`.
+*/
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChunkInfo {
     pub uuid: String,
-    pub server_ip: String,
+    // version the replicas have to be written with
+    #[serde(default)]
+    pub version: u64,
+    pub locations: Vec<String>,
+    // server of the replica holding the mutation lease of the chunk
+    #[serde(default)]
+    pub primary: Option<String>,
+    // set for a chunk of an erasure-coded file, which has one
+    // fragment on each server of `fragments` instead of replicas
+    #[serde(default)]
+    pub erasure: Option<ErasureCoding>,
+    // server of each fragment in order, None for a lost one
+    #[serde(default)]
+    pub fragments: Vec<Option<String>>,
+    // allows the client to read the chunk on its chunkservers for a
+    // read, or to write it at `version` through `primary` for a write
+    #[serde(default)]
+    pub token: Option<String>,
 }
 
-impl ChunkInfo {
-    pub fn serialize(file: Vec<(Uuid, String)>) -> Vec<ChunkInfo> {
-        let mut chunks = Vec::new();
-        for (uuid, ip) in file{
-            chunks.push(ChunkInfo{uuid: uuid.to_string(), server_ip: ip });
-        }
-        chunks
+/*
+*   The chunks of a file in order. Chunk i holds the bytes
+*   [i * chunk_size, (i + 1) * chunk_size) of the file.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileChunks {
+    pub size: u64,
+    pub chunk_size: u64,
+    pub chunks: Vec<ChunkInfo>,
+}
+
+// Replicas kept of each chunk unless a file or directory sets another number
+pub const DEFAULT_REPLICATION: usize = 3;
+
+fn default_replication() -> usize {
+    DEFAULT_REPLICATION
+}
+
+/*
+*   Reed-Solomon layout of an erasure-coded file : each chunk is
+*   stored as `data` data fragments and `parity` parity fragments,
+*   on distinct chunkservers, instead of being replicated.
+*   Written as "data,parity", e.g. "6,3".
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErasureCoding {
+    pub data: usize,
+    pub parity: usize,
+}
+
+impl ErasureCoding {
+    pub fn fragments(&self) -> usize {
+        self.data + self.parity
+    }
+}
+
+impl FromStr for ErasureCoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid erasure coding {}, expected data,parity", s);
+        let (data, parity) = s.split_once(',').ok_or_else(invalid)?;
+        Ok(ErasureCoding {
+            data: data.trim().parse().map_err(|_| invalid())?,
+            parity: parity.trim().parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl fmt::Display for ErasureCoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RS({},{})", self.data, self.parity)
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Metadata {
-    size: i32,
+    size: i64,
     creation_time: DateTime<Utc>,
     modification_time: DateTime<Utc>,
-    permission: i32,
-    owner: String,
-    group: String,
+    pub permission: i32,
+    pub owner: String,
+    pub group: String,
+    // replicas of each chunk of a file, for a directory
+    // the default of the files and directories created in it
+    #[serde(default = "default_replication")]
+    pub replication: usize,
+    // layout of the chunks of an erasure-coded file, for a directory
+    // the default of the files and directories created in it
+    #[serde(default)]
+    pub erasure: Option<ErasureCoding>,
 }
 
 impl Metadata {
-    pub fn new(size: i32, permission: i32, owner: String, group: String) -> Self {
+    pub fn new(size: i64, permission: i32, owner: String, group: String) -> Self {
         let utc_now: DateTime<Utc> = Utc::now();
         Self {
-            size: size,
+            size,
             creation_time: utc_now,
             modification_time: utc_now,
-            permission: permission,
-            owner: owner,
-            group: group,
+            permission,
+            owner,
+            group,
+            replication: DEFAULT_REPLICATION,
+            erasure: None,
         }
     }
+
+    pub fn size(&self) -> i64 {
+        self.size
+    }
+
+    pub fn set_size(&mut self, size: i64) {
+        self.size = size;
+        self.modification_time = Utc::now();
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -48,7 +134,11 @@ pub struct FileInfo {
     pub file_name: String,
     pub file_parent: String,
     pub file_metadata: Metadata,
-    pub chunks: Vec<Vec<String>>
+    // chunk handle of each chunk index
+    pub chunks: Vec<String>,
+    // size of the chunks the file was written with
+    #[serde(default)]
+    pub chunk_size: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -63,4 +153,63 @@ pub struct DirectoryInfo {
 pub struct User {
     pub username: String,
     pub password: String,
-}
\ No newline at end of file
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserSummary {
+    pub username: String,
+    pub admin: bool,
+    pub disabled: bool,
+}
+
+/*
+*   Session token issued by the master on login. It is sent back
+*   on every namespace request as "Authorization: Bearer <token>".
+*/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionToken {
+    pub token: String,
+    pub expires: DateTime<Utc>,
+}
+
+/*
+*   Bytes of chunk data a chunkserver holds, as seen by the rebalancer.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerUsage {
+    pub server: String,
+    pub used: u64,
+    pub chunk_count: usize,
+}
+
+/*
+*   A replica moved by the rebalancer : copied from the source to
+*   the target, then deleted on the source.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplicaMove {
+    pub path: String,
+    pub index: usize,
+    pub uuid: String,
+    pub source: String,
+    pub target: String,
+    pub size: u64,
+    // false when only planned (dry run) or failed
+    pub done: bool,
+    pub error: Option<String>,
+}
+
+/*
+*   Moves of a rebalancer run. Servers holding more than the
+*   average by a `threshold` fraction of it give replicas to the
+*   servers holding less by that much, moving at most `budget` bytes.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RebalancePlan {
+    pub average: u64,
+    pub threshold: f64,
+    pub budget: u64,
+    pub dry_run: bool,
+    pub servers: Vec<ServerUsage>,
+    pub moves: Vec<ReplicaMove>,
+}
@@ -1,3 +1,5 @@
+pub mod cluster_auth;
+pub mod erasure;
 pub mod log_manager;
 pub mod master_chunk_utils;
 pub mod master_client_utils;